@@ -0,0 +1,86 @@
+// Times closest hit queries against the built-in scenes and a triangle mesh
+// loaded from OBJ, run with `cargo bench`. Every scene gets a camera ray
+// through each pixel plus a bounce in a random direction from wherever that
+// ray landed, which covers both coherent and incoherent traversal.
+extern crate raytrace;
+
+use std::path::Path;
+use std::time::Instant;
+use raytrace::camera::Camera;
+use raytrace::hitable::HitablePtr;
+use raytrace::obj::parse_obj;
+use raytrace::ray::Ray;
+use raytrace::rt_rand::Rng;
+use raytrace::sampler::{RandomSampler, Sampler, sample_unit_sphere};
+use raytrace::scenes;
+use raytrace::vec3::Vec3;
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 120;
+const RUNS: usize = 5;
+
+fn rays_for(scene: &HitablePtr, camera: &Camera) -> Vec<Ray> {
+    let mut rays = Vec::new();
+    for j in 0..HEIGHT {
+        for i in 0..WIDTH {
+            let mut sampler = RandomSampler::new(0, (j * WIDTH + i) as u64, 0);
+            let u = (i as f64 + 0.5) / WIDTH as f64;
+            let v = (j as f64 + 0.5) / HEIGHT as f64;
+            let ray = camera.get_ray(u, v, &mut sampler);
+            if let Some(hit) = scene.hit(&ray, 0.001, f64::MAX) {
+                let direction = sample_unit_sphere(sampler.get_2d());
+                rays.push(Ray::new(hit.p, direction, ray.time));
+            }
+            rays.push(ray);
+        }
+    }
+    rays
+}
+
+// A torus of `rings` by `sides` quads, which the importer splits into
+// triangles.
+fn torus_obj(rings: usize, sides: usize) -> String {
+    let tau = 2.0 * std::f64::consts::PI;
+    let mut text = String::new();
+    for i in 0..rings {
+        for j in 0..sides {
+            let (u, v) = (i as f64 / rings as f64 * tau, j as f64 / sides as f64 * tau);
+            let r = 1.0 + 0.4 * v.cos();
+            text += &format!("v {} {} {}\n", r * u.cos(), 0.4 * v.sin(), r * u.sin());
+        }
+    }
+    for i in 0..rings {
+        for j in 0..sides {
+            let corner = |i: usize, j: usize| (i % rings) * sides + j % sides + 1;
+            text += &format!("f {} {} {} {}\n", corner(i, j), corner(i + 1, j), corner(i + 1, j + 1), corner(i, j + 1));
+        }
+    }
+    text
+}
+
+fn time(name: &str, scene: &HitablePtr, camera: &Camera) {
+    let rays = rays_for(scene, camera);
+    let mut best = f64::MAX;
+    let mut hits = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        hits = rays.iter().filter(|ray| scene.hit(ray, 0.001, f64::MAX).is_some()).count();
+        let elapsed = start.elapsed();
+        best = best.min(elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9);
+    }
+    // Keeps the queries from being optimized away.
+    assert!(hits <= rays.len());
+    println!("{:<16} {:>8} {:>12.1} {:>10.2}", name, rays.len(), best * 1000.0, rays.len() as f64 / best * 1e-6);
+}
+
+fn main() {
+    println!("{:<16} {:>8} {:>12} {:>10}", "scene", "rays", "best (ms)", "Mrays/s");
+    for name in scenes::scene_names() {
+        let (scene, camera, _) = scenes::scene_by_name(name, WIDTH, HEIGHT, &mut Rng::new(0)).unwrap();
+        time(name, &scene, &camera);
+    }
+    let torus = parse_obj(&torus_obj(400, 100), Path::new("torus.obj"), None).unwrap();
+    let camera = Camera::new(&Vec3::new(0.0, 2.0, 3.0), &Vec3::zero(), &Vec3::new(0.0, 1.0, 0.0), 50.0,
+                             WIDTH as f64 / HEIGHT as f64, 0.0, 1.0, 0.0, 1.0);
+    time("torus_obj", &torus, &camera);
+}
@@ -16,31 +16,101 @@ impl Aabb {
     }
   }
 
+  // Contains nothing, so surrounding it with another box gives that box.
+  pub fn empty() -> Aabb {
+    let inf = f64::INFINITY;
+    Aabb::new(Vec3::new(inf, inf, inf), Vec3::new(-inf, -inf, -inf))
+  }
+
+  // Contains everything, for hitables without bounds.
+  pub fn infinite() -> Aabb {
+    let inf = f64::INFINITY;
+    Aabb::new(Vec3::new(-inf, -inf, -inf), Vec3::new(inf, inf, inf))
+  }
+
+  // Slab test, narrowing [t_min, t_max] to where the ray is inside each
+  // pair of planes in turn. A zero direction component gives infinite slab
+  // distances, so the axis passes when the origin lies between its planes and
+  // fails otherwise. An origin right on a plane gives 0 * inf = NaN, the
+  // comparisons then keep the interval, counting rays along a face as hits.
   pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    let mut t_min = t_min;
+    let mut t_max = t_max;
     for a in 0..3 {
-      if ray.direction[a] == 0.0 {
-        println!("skipping {}", a);
-        continue;
-      }
-      let inv_d = 1.0 / ray.direction[a];
+      let inv_d = ray.inv_direction[a];
       let mut t0 = (self.min[a] - ray.origin[a]) * inv_d;
       let mut t1 = (self.max[a] - ray.origin[a]) * inv_d;
       if inv_d < 0.0 {
         mem::swap(&mut t0, &mut t1);
       }
-      let mn = if t0 > t_min { t0 } else { t_min };
-      let mx = if t1 < t_max { t1 } else { t_max };
-      if mx <= mn {
+      if t0 > t_min {
+        t_min = t0;
+      }
+      if t1 < t_max {
+        t_max = t1;
+      }
+      if t_max < t_min {
         return false;
       }
     }
     true
   }
 
+  pub fn surface_area(&self) -> f64 {
+    let d = self.max - self.min;
+    2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+  }
+
+  pub fn centroid(&self) -> Vec3 {
+    (self.min + self.max) * 0.5
+  }
+
   pub fn surrounding_box(a: &Aabb, b: &Aabb) -> Aabb {
     Aabb {
       min: Vec3::new(a.min.x.min(b.min.x), a.min.y.min(b.min.y), a.min.z.min(b.min.z)),
       max: Vec3::new(a.max.x.max(b.max.x), a.max.y.max(b.max.y), a.max.z.max(b.max.z)),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use ray::Ray;
+  use aabb::*;
+
+  fn hits(origin: Vec3, direction: Vec3) -> bool {
+    let unit = Aabb::new(Vec3::zero(), Vec3::one());
+    unit.hit(&Ray::new(origin, direction, 0.0), 0.0, f64::MAX)
+  }
+
+  #[test]
+  fn test_axis_aligned() {
+    assert!(hits(Vec3::new(0.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0)));
+    assert!(hits(Vec3::new(0.5, 0.5, 2.0), Vec3::new(-0.0, 0.0, -1.0)));
+    assert!(hits(Vec3::new(0.5, 0.5, 0.5), Vec3::new(0.0, 1.0, 0.0)));
+    // Outside the slabs of the axes the ray doesn't move along.
+    assert!(!hits(Vec3::new(2.0, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0)));
+    assert!(!hits(Vec3::new(0.5, -0.5, -1.0), Vec3::new(-0.0, -0.0, 1.0)));
+    assert!(!hits(Vec3::new(0.5, 0.5, 2.0), Vec3::new(0.0, 0.0, 1.0)));
+    let unit = Aabb::new(Vec3::zero(), Vec3::one());
+    let ray = Ray::new(Vec3::new(0.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+    assert!(!unit.hit(&ray, 0.0, 0.5));
+    assert!(!unit.hit(&ray, 2.5, 3.0));
+    assert!(unit.hit(&ray, 1.5, 3.0));
+  }
+
+  #[test]
+  fn test_grazing() {
+    // Along a face and along an edge.
+    assert!(hits(Vec3::new(1.0, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0)));
+    assert!(hits(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0)));
+    // Touching an edge diagonally, and passing just by it.
+    assert!(hits(Vec3::new(-1.0, 1.0, 0.5), Vec3::new(1.0, -1.0, 0.0)));
+    assert!(!hits(Vec3::new(-1.0, 0.999, 0.5), Vec3::new(1.0, -1.0, 0.0)));
+    // Within the x and the y slabs, but never both at once.
+    assert!(!hits(Vec3::new(-1.0, 3.5, 0.5), Vec3::new(1.0, -1.0, 0.0)));
+    assert!(hits(Vec3::new(-1.0, 2.5, 0.5), Vec3::new(1.0, -1.0, 0.0)));
+  }
+}
@@ -0,0 +1,258 @@
+// A bounding volume hierarchy over anything with a bounding box, flattened
+// into one array so traversal walks memory instead of chasing pointers. The
+// tree only knows its primitives by index: `BvhTree::build` says in which
+// order to store them and leaves cover ranges of that order, whoever owns the
+// primitives tests them when traversal reaches a leaf. The hitable `Bvh` and
+// triangle meshes are both built on it.
+
+use std::ops::Range;
+
+use aabb::Aabb;
+use ray::Ray;
+use vec3::Vec3;
+
+// Leaf size for `Bvh::new` and meshes. Testing a handful of primitives in a
+// row is cheap next to the bookkeeping of more nodes.
+pub const DEFAULT_BVH_LEAF_SIZE: usize = 4;
+// Surface area heuristic costs are relative to intersecting one primitive.
+const BVH_TRAVERSAL_COST: f64 = 0.5;
+const BVH_BINS: usize = 12;
+
+// Nodes are stored depth first, an interior node's first child directly
+// follows it and `second` holds the index of the other. Leaves cover `count`
+// primitives starting at `start`. `axis` is the one the node was split along,
+// traversal visits the child on the near side of it first.
+struct BvhNode {
+  bbox: Aabb,
+  start: usize,
+  count: usize,
+  second: usize,
+  axis: usize,
+}
+
+struct BvhPrimitive {
+  index: usize,
+  bbox: Aabb,
+  centroid: Vec3,
+}
+
+pub struct BvhTree {
+  nodes: Vec<BvhNode>,
+}
+
+impl BvhTree {
+  // Splits are placed by the surface area heuristic over binned centroids.
+  // Leaves hold at most `max_leaf_size` primitives, fewer where the heuristic
+  // says splitting further pays off. Returns the tree along with the order to
+  // store the primitives in, as indices into `boxes`.
+  pub fn build(boxes: &[Aabb], max_leaf_size: usize) -> (BvhTree, Vec<usize>) {
+    assert!(max_leaf_size > 0, "BVH leaves must hold at least one primitive!");
+    let mut primitives: Vec<BvhPrimitive> = boxes.iter().enumerate().map(|(index, bbox)| {
+      BvhPrimitive { index, bbox: bbox.clone(), centroid: bbox.centroid() }
+    }).collect();
+    let mut nodes = Vec::new();
+    if !primitives.is_empty() {
+      BvhTree::build_node(&mut nodes, &mut primitives, 0, max_leaf_size);
+    }
+    let order = primitives.iter().map(|p| p.index).collect();
+    (BvhTree { nodes }, order)
+  }
+
+  // Builds the subtree over `primitives`, which start at `offset` in the
+  // final order, and returns its node index.
+  fn build_node(nodes: &mut Vec<BvhNode>, primitives: &mut [BvhPrimitive], offset: usize, max_leaf_size: usize) -> usize {
+    let mut bbox = primitives[0].bbox.clone();
+    let mut centroid_bounds = Aabb::new(primitives[0].centroid, primitives[0].centroid);
+    for primitive in primitives.iter() {
+      bbox = Aabb::surrounding_box(&bbox, &primitive.bbox);
+      centroid_bounds = Aabb::surrounding_box(&centroid_bounds, &Aabb::new(primitive.centroid, primitive.centroid));
+    }
+    let index = nodes.len();
+    let count = primitives.len();
+    nodes.push(BvhNode {
+      bbox,
+      start: offset,
+      count,
+      second: 0,
+      axis: 0,
+    });
+    if count == 1 {
+      return index;
+    }
+    let (axis, mid) = match BvhTree::best_split(primitives, &nodes[index].bbox, &centroid_bounds) {
+      Some((axis, bin, cost)) if cost < count as f64 || count > max_leaf_size => {
+        let (lo, extent) = (centroid_bounds.min[axis], centroid_bounds.max[axis] - centroid_bounds.min[axis]);
+        (axis, partition(primitives, |p| centroid_bin(p.centroid[axis], lo, extent) <= bin))
+      },
+      None if count > max_leaf_size => {
+        // Nothing to go by, the centroids coincide or the boxes are flat.
+        (0, count / 2)
+      },
+      _ => return index,
+    };
+    let (first, second) = primitives.split_at_mut(mid);
+    BvhTree::build_node(nodes, first, offset, max_leaf_size);
+    let second = BvhTree::build_node(nodes, second, offset + mid, max_leaf_size);
+    let node = &mut nodes[index];
+    node.count = 0;
+    node.second = second;
+    node.axis = axis;
+    index
+  }
+
+  // The cheapest split on any axis as (axis, last bin on the left, cost),
+  // with the cost of a leaf being the number of primitives in it.
+  fn best_split(primitives: &[BvhPrimitive], bbox: &Aabb, centroid_bounds: &Aabb) -> Option<(usize, usize, f64)> {
+    let area = bbox.surface_area();
+    if area.is_nan() || area <= 0.0 {
+      return None;
+    }
+    let mut best: Option<(usize, usize, f64)> = None;
+    for axis in 0..3 {
+      let lo = centroid_bounds.min[axis];
+      let extent = centroid_bounds.max[axis] - lo;
+      if extent.is_nan() || extent <= 0.0 {
+        continue;
+      }
+      let mut counts = [0usize; BVH_BINS];
+      let mut boxes: Vec<Option<Aabb>> = vec![None; BVH_BINS];
+      for primitive in primitives {
+        let bin = centroid_bin(primitive.centroid[axis], lo, extent);
+        counts[bin] += 1;
+        boxes[bin] = Some(match boxes[bin] {
+          Some(ref b) => Aabb::surrounding_box(b, &primitive.bbox),
+          None => primitive.bbox.clone(),
+        });
+      }
+      // Sweep from the right for the area and count behind every boundary,
+      // then from the left to price each one.
+      let mut right_cost = [0.0; BVH_BINS];
+      let mut right_box: Option<Aabb> = None;
+      let mut right_count = 0;
+      for bin in (1..BVH_BINS).rev() {
+        right_box = merge_boxes(right_box, &boxes[bin]);
+        right_count += counts[bin];
+        right_cost[bin] = right_box.as_ref().map_or(0.0, |b| b.surface_area()) * right_count as f64;
+      }
+      let mut left_box: Option<Aabb> = None;
+      let mut left_count = 0;
+      for bin in 0..BVH_BINS - 1 {
+        left_box = merge_boxes(left_box, &boxes[bin]);
+        left_count += counts[bin];
+        if left_count == 0 || left_count == primitives.len() {
+          continue;
+        }
+        let left_cost = left_box.as_ref().map_or(0.0, |b| b.surface_area()) * left_count as f64;
+        let cost = BVH_TRAVERSAL_COST + (left_cost + right_cost[bin + 1]) / area;
+        if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+          best = Some((axis, bin, cost));
+        }
+      }
+    }
+    best
+  }
+
+  pub fn bounding_box(&self) -> Aabb {
+    match self.nodes.first() {
+      Some(root) => root.bbox.clone(),
+      None => Aabb::empty()
+    }
+  }
+
+  // Hands `leaf` the range of primitives in every leaf `ray` reaches within
+  // the interval, the near child of each node first. `leaf` returns where
+  // the interval ends now, closer once something was hit, or None when
+  // there's no need to look any further.
+  pub fn traverse<F: FnMut(Range<usize>) -> Option<f64>>(&self, ray: &Ray, t_min: f64, t_max: f64, mut leaf: F) {
+    if self.nodes.is_empty() {
+      return;
+    }
+    let mut t_max = t_max;
+    let mut stack: Vec<usize> = Vec::with_capacity(64);
+    stack.push(0);
+    while let Some(index) = stack.pop() {
+      let node = &self.nodes[index];
+      if !node.bbox.hit(ray, t_min, t_max) {
+        continue;
+      }
+      if node.count > 0 {
+        match leaf(node.start..node.start + node.count) {
+          Some(t) => t_max = t,
+          None => return,
+        }
+      } else if ray.direction[node.axis] < 0.0 {
+        // The second child lies further along the axis, so it comes first.
+        stack.push(index + 1);
+        stack.push(node.second);
+      } else {
+        stack.push(node.second);
+        stack.push(index + 1);
+      }
+    }
+  }
+}
+
+fn centroid_bin(centroid: f64, lo: f64, extent: f64) -> usize {
+  (((centroid - lo) / extent * BVH_BINS as f64) as usize).min(BVH_BINS - 1)
+}
+
+fn merge_boxes(a: Option<Aabb>, b: &Option<Aabb>) -> Option<Aabb> {
+  match (a, b) {
+    (Some(a), Some(b)) => Some(Aabb::surrounding_box(&a, b)),
+    (Some(a), None) => Some(a),
+    (None, b) => b.clone(),
+  }
+}
+
+// Moves the items matching `pred` to the front, returning how many there are.
+fn partition<T, F: Fn(&T) -> bool>(items: &mut [T], pred: F) -> usize {
+  let mut mid = 0;
+  for i in 0..items.len() {
+    if pred(&items[i]) {
+      items.swap(i, mid);
+      mid += 1;
+    }
+  }
+  mid
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use ray::Ray;
+  use aabb::Aabb;
+  use bvh::*;
+
+  #[test]
+  fn test_traversal_order() {
+    // A row of unit boxes along x, visited in the order a ray meets them.
+    let boxes: Vec<Aabb> = (0..40).map(|i| {
+      let min = Vec3::new((i * 7 % 40) as f64 * 2.0, 0.0, 0.0);
+      Aabb::new(min, min + Vec3::one())
+    }).collect();
+    let (tree, order) = BvhTree::build(&boxes, 1);
+    let mut sorted = order.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..40).collect::<Vec<usize>>());
+    assert_eq!(tree.bounding_box().max, Vec3::new(79.0, 1.0, 1.0));
+    for &(origin, direction) in [(-1.0, 1.0), (100.0, -1.0)].iter() {
+      let ray = Ray::new(Vec3::new(origin, 0.5, 0.5), Vec3::new(direction, 0.0, 0.0), 0.0);
+      let mut visited = Vec::new();
+      tree.traverse(&ray, 0.0, f64::MAX, |range| {
+        visited.extend(range.map(|i| boxes[order[i]].min.x * direction));
+        Some(f64::MAX)
+      });
+      assert_eq!(visited.len(), 40);
+      assert!(visited.windows(2).all(|w| w[0] < w[1]), "{:?}", visited);
+    }
+    // Stopping early.
+    let ray = Ray::new(Vec3::new(-1.0, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0), 0.0);
+    let mut leaves = 0;
+    tree.traverse(&ray, 0.0, f64::MAX, |_| {
+      leaves += 1;
+      None
+    });
+    assert_eq!(leaves, 1);
+  }
+}
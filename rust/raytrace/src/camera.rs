@@ -1,7 +1,7 @@
 use vec3::Vec3;
 use ray::Ray;
 use std::f64::consts::PI;
-use rt_rand::*;
+use sampler::{Sampler, sample_unit_disk};
 
 pub struct Camera {
   origin: Vec3,
@@ -40,10 +40,10 @@ impl Camera {
     }
   }
 
-  pub fn get_ray(&self, u: f64, v: f64) -> Ray {
-    let rd = self.lens_radius * random_in_unit_disk();
+  pub fn get_ray(&self, u: f64, v: f64, sampler: &mut dyn Sampler) -> Ray {
+    let rd = self.lens_radius * sample_unit_disk(sampler.get_2d());
     let offset = self.u * rd.x + self.v * rd.y;
-    let time = self.time0 + (self.time1-self.time0)*rand_f64();
+    let time = self.time0 + (self.time1-self.time0)*sampler.get_1d();
     Ray::new(self.origin + offset, self.lower_left_corner + u * self.horizontal + v * self.vertical - self.origin - offset, time)
   }
 }
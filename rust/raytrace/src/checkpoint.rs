@@ -0,0 +1,273 @@
+extern crate byteorder;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use framebuffer::Framebuffer;
+use sampler::SamplerType;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// Everything needed to carry on with a render: the framebuffer plus the
+// settings that decide which samples a pixel takes. Samples are seeded from
+// the seed, the pixel and the sample index, so the seed and the per pixel
+// counts are all the random state there is. Merging renders made with
+// different seeds keeps every seed, such a checkpoint can be merged further
+// but no longer resumed. The scene seed lays out the scene, which all of
+// them have to agree on.
+pub struct Checkpoint {
+  pub scene: String,
+  pub scene_seed: u64,
+  pub seeds: Vec<u64>,
+  pub sampler: SamplerType,
+  pub samples: u32,
+  pub min_bounces: u32,
+  pub max_bounces: u32,
+  pub spectral: bool,
+  pub framebuffer: Framebuffer,
+}
+
+const MAGIC: &[u8; 4] = b"RTCK";
+const VERSION: u32 = 1;
+// Keeps a corrupt header from asking for absurd allocations.
+const MAX_PIXELS: u64 = 1 << 28;
+const MAX_STRING: u32 = 1 << 16;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+  Io(io::Error),
+  Invalid(String),
+  Mismatch(String),
+}
+
+impl fmt::Display for CheckpointError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      CheckpointError::Io(ref err) => write!(f, "{}", err),
+      CheckpointError::Invalid(ref message) => write!(f, "not a valid checkpoint: {}", message),
+      CheckpointError::Mismatch(ref message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+  fn from(err: io::Error) -> CheckpointError {
+    CheckpointError::Io(err)
+  }
+}
+
+impl Checkpoint {
+  pub fn load(path: &Path) -> Result<Checkpoint, CheckpointError> {
+    Checkpoint::read(&mut BufReader::new(File::open(path)?))
+  }
+
+  pub fn save(&self, path: &Path) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    self.write(&mut f)?;
+    f.flush()
+  }
+
+  pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_u32::<LittleEndian>(VERSION)?;
+    w.write_u32::<LittleEndian>(self.framebuffer.width())?;
+    w.write_u32::<LittleEndian>(self.framebuffer.height())?;
+    write_string(w, &self.scene)?;
+    write_string(w, self.sampler.name())?;
+    w.write_u32::<LittleEndian>(self.samples)?;
+    w.write_u32::<LittleEndian>(self.min_bounces)?;
+    w.write_u32::<LittleEndian>(self.max_bounces)?;
+    w.write_u32::<LittleEndian>(self.spectral as u32)?;
+    w.write_u64::<LittleEndian>(self.scene_seed)?;
+    w.write_u32::<LittleEndian>(self.seeds.len() as u32)?;
+    for seed in &self.seeds {
+      w.write_u64::<LittleEndian>(*seed)?;
+    }
+    self.framebuffer.write_to(w)
+  }
+
+  pub fn read<R: Read>(r: &mut R) -> Result<Checkpoint, CheckpointError> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+      return Err(CheckpointError::Invalid("bad magic number".to_string()));
+    }
+    let version = r.read_u32::<LittleEndian>()?;
+    if version != VERSION {
+      return Err(CheckpointError::Invalid(format!("unsupported version {}", version)));
+    }
+    let width = r.read_u32::<LittleEndian>()?;
+    let height = r.read_u32::<LittleEndian>()?;
+    if width == 0 || height == 0 || width as u64 * height as u64 > MAX_PIXELS {
+      return Err(CheckpointError::Invalid(format!("bad dimensions {}x{}", width, height)));
+    }
+    let scene = read_string(r)?;
+    let sampler_name = read_string(r)?;
+    let sampler = SamplerType::from_name(&sampler_name)
+      .ok_or_else(|| CheckpointError::Invalid(format!("unknown sampler {}", sampler_name)))?;
+    let samples = r.read_u32::<LittleEndian>()?;
+    let min_bounces = r.read_u32::<LittleEndian>()?;
+    let max_bounces = r.read_u32::<LittleEndian>()?;
+    let spectral = r.read_u32::<LittleEndian>()? != 0;
+    let scene_seed = r.read_u64::<LittleEndian>()?;
+    let num_seeds = r.read_u32::<LittleEndian>()?;
+    if num_seeds == 0 || num_seeds > MAX_STRING {
+      return Err(CheckpointError::Invalid(format!("bad seed count {}", num_seeds)));
+    }
+    let mut seeds = Vec::with_capacity(num_seeds as usize);
+    for _ in 0..num_seeds {
+      seeds.push(r.read_u64::<LittleEndian>()?);
+    }
+    let framebuffer = Framebuffer::read_from(r, width, height)?;
+    Ok(Checkpoint { scene, scene_seed, seeds, sampler, samples, min_bounces, max_bounces, spectral, framebuffer })
+  }
+
+  // Checks `other` renders the same image the same way, though maybe with
+  // other sampling seeds. The stratified sampler lays its strata out for the
+  // sample count, so it needs that to match as well.
+  pub fn check_compatible(&self, other: &Checkpoint) -> Result<(), CheckpointError> {
+    let (a, b) = (&self.framebuffer, &other.framebuffer);
+    if (a.width(), a.height()) != (b.width(), b.height()) {
+      return Err(CheckpointError::Mismatch(format!("image size {}x{} differs from {}x{}", b.width(), b.height(), a.width(), a.height())));
+    }
+    if self.scene != other.scene {
+      return Err(CheckpointError::Mismatch(format!("scene {} differs from {}", other.scene, self.scene)));
+    }
+    if self.scene_seed != other.scene_seed {
+      return Err(CheckpointError::Mismatch(format!("scene seed {} differs from {}", other.scene_seed, self.scene_seed)));
+    }
+    if self.sampler != other.sampler {
+      return Err(CheckpointError::Mismatch(format!("sampler {} differs from {}", other.sampler.name(), self.sampler.name())));
+    }
+    if (self.min_bounces, self.max_bounces) != (other.min_bounces, other.max_bounces) {
+      return Err(CheckpointError::Mismatch(format!("bounce limits {}..{} differ from {}..{}",
+                                                   other.min_bounces, other.max_bounces, self.min_bounces, self.max_bounces)));
+    }
+    if self.spectral != other.spectral {
+      let mode = |spectral| if spectral { "spectral" } else { "RGB" };
+      return Err(CheckpointError::Mismatch(format!("{} render differs from {} one", mode(other.spectral), mode(self.spectral))));
+    }
+    if self.sampler == SamplerType::Stratified && self.samples != other.samples {
+      return Err(CheckpointError::Mismatch(format!("stratified sample count {} differs from {}", other.samples, self.samples)));
+    }
+    Ok(())
+  }
+
+  // Adds the samples of an independent render. Renders sharing a seed took
+  // the very same samples, so those are refused rather than counted twice.
+  pub fn merge(&mut self, other: &Checkpoint) -> Result<(), CheckpointError> {
+    self.check_compatible(other)?;
+    if let Some(seed) = other.seeds.iter().find(|seed| self.seeds.contains(seed)) {
+      return Err(CheckpointError::Mismatch(format!("seed {} was rendered more than once", seed)));
+    }
+    self.seeds.extend_from_slice(&other.seeds);
+    self.framebuffer.merge(&other.framebuffer);
+    Ok(())
+  }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+  w.write_u32::<LittleEndian>(s.len() as u32)?;
+  w.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String, CheckpointError> {
+  let len = r.read_u32::<LittleEndian>()?;
+  if len > MAX_STRING {
+    return Err(CheckpointError::Invalid(format!("string of {} bytes", len)));
+  }
+  let mut bytes = vec![0; len as usize];
+  r.read_exact(&mut bytes)?;
+  String::from_utf8(bytes).map_err(|_| CheckpointError::Invalid("string is not UTF-8".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use framebuffer::Framebuffer;
+  use sampler::SamplerType;
+  use checkpoint::*;
+
+  fn checkpoint(seed: u64) -> Checkpoint {
+    let mut framebuffer = Framebuffer::new(2, 2);
+    framebuffer.add(1, 0, &Vec3::new(1.0, 2.0, 3.0), &Vec3::new(1.0, 4.0, 9.0), 1);
+    Checkpoint {
+      scene: "cornell".to_string(),
+      scene_seed: 0,
+      seeds: vec![seed],
+      sampler: SamplerType::Sobol,
+      samples: 16,
+      min_bounces: 3,
+      max_bounces: 50,
+      spectral: false,
+      framebuffer,
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let mut bytes = Vec::new();
+    let mut written = checkpoint(7);
+    written.scene_seed = 5;
+    written.write(&mut bytes).unwrap();
+    let read = Checkpoint::read(&mut &bytes[..]).unwrap();
+    assert_eq!(read.scene, "cornell");
+    assert_eq!(read.scene_seed, 5);
+    assert_eq!(read.seeds, vec![7]);
+    assert_eq!(read.sampler, SamplerType::Sobol);
+    assert_eq!((read.samples, read.min_bounces, read.max_bounces), (16, 3, 50));
+    assert!(!read.spectral);
+    assert_eq!(read.framebuffer.image(), checkpoint(7).framebuffer.image());
+    assert!(Checkpoint::read(&mut &bytes[..bytes.len() - 1]).is_err());
+    bytes[0] = b'X';
+    assert!(Checkpoint::read(&mut &bytes[..]).is_err());
+  }
+
+  #[test]
+  fn test_merge() {
+    let mut merged = checkpoint(1);
+    merged.merge(&checkpoint(2)).unwrap();
+    assert_eq!(merged.seeds, vec![1, 2]);
+    assert_eq!(merged.framebuffer.count(1, 0), 2);
+    assert!(merged.merge(&checkpoint(2)).is_err());
+    let mut other = checkpoint(3);
+    other.scene = "final".to_string();
+    assert!(merged.merge(&other).is_err());
+    let mut other = checkpoint(3);
+    other.spectral = true;
+    assert!(merged.merge(&other).is_err());
+    let mut other = checkpoint(3);
+    other.samples = 32;
+    assert!(merged.merge(&other).is_ok());
+    merged.sampler = SamplerType::Stratified;
+    let mut other = checkpoint(4);
+    other.sampler = SamplerType::Stratified;
+    other.samples = 32;
+    assert!(merged.merge(&other).is_err());
+  }
+
+  #[test]
+  fn test_scene_seed() {
+    // Partial renders of a random scene with their own sampling seeds merge
+    // as long as they laid the scene out alike.
+    let final_scene = |seed| {
+      let mut checkpoint = checkpoint(seed);
+      checkpoint.scene = "final".to_string();
+      checkpoint.scene_seed = 3;
+      checkpoint
+    };
+    let mut merged = final_scene(1);
+    merged.merge(&final_scene(2)).unwrap();
+    assert_eq!(merged.seeds, vec![1, 2]);
+    assert_eq!(merged.scene_seed, 3);
+    let mut other = final_scene(4);
+    other.scene_seed = 4;
+    assert!(merged.check_compatible(&other).is_err());
+    assert!(merged.merge(&other).is_err());
+  }
+}
@@ -3,7 +3,8 @@ use std::sync::Arc;
 use hitable::{Hitable, HitablePtr, HitRecord};
 use material::{Material, MaterialPtr, ScatterInfo};
 use texture::TexturePtr;
-use rt_rand::*;
+use rt_rand::hash_f64;
+use sampler::{Sampler, sample_unit_sphere};
 use ray::Ray;
 use aabb::Aabb;
 use vec3::Vec3;
@@ -25,13 +26,24 @@ impl Isotropic {
 }
 
 impl Material for Isotropic {
-  fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<ScatterInfo> {
-    let scattered = Ray::new(hit.p, random_in_unit_sphere(), ray.time);
+  fn scatter(&self, ray: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterInfo> {
+    let scattered = Ray::new(hit.p, sample_unit_sphere(sampler.get_2d()), ray.time);
     Some(ScatterInfo {
-      attenuation: self.albedo.value(hit.u, hit.v, &hit.p),
-      scattered
+      attenuation: ray.spectrum(&self.albedo.value(hit.u, hit.v, &hit.p)),
+      scattered,
+      specular: false,
+      pdf: 1.0 / (4.0 * std::f64::consts::PI)
     })
   }
+
+  // Scattering is uniform over the sphere of directions.
+  fn eval(&self, ray: &Ray, hit: &HitRecord, _direction: &Vec3) -> Vec3 {
+    ray.spectrum(&self.albedo.value(hit.u, hit.v, &hit.p)) / (4.0 * std::f64::consts::PI)
+  }
+
+  fn pdf(&self, _ray: &Ray, _hit: &HitRecord, _direction: &Vec3) -> f64 {
+    1.0 / (4.0 * std::f64::consts::PI)
+  }
 }
 
 pub struct ConstantMedium {
@@ -53,31 +65,52 @@ impl ConstantMedium {
   pub fn hitable_ptr(boundary: &HitablePtr, density: f64, phase_texture: TexturePtr) -> Arc<ConstantMedium> {
     Arc::new(ConstantMedium::new(boundary, density, phase_texture))
   }
+
+  // Where in the interval the ray scatters, if it does. The same ray always
+  // scatters at the same place, so `hit` and `occluded` agree.
+  fn scatter_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    if let Some(t1) = self.boundary.hit_distance(ray, -f64::MAX, f64::MAX) {
+      if let Some(t2) = self.boundary.hit_distance(ray, t1 + 0.0001, f64::MAX) {
+        let t1 = t1.max(t_min);
+        let t2 = t2.min(t_max);
+        if t1 < 0.0 {
+          return None;
+        }
+        let dist_inside_boundary = (t2 - t1) * ray.direction.length();
+        // Hitables get no sampler, so the free path comes from hashing the ray.
+        let o = ray.origin;
+        let d = ray.direction;
+        let u = 1.0 - hash_f64(&[o.x, o.y, o.z, d.x, d.y, d.z, ray.time]);
+        let hit_distance = -(1.0 / self.density) * u.log(std::f64::consts::E);
+        if hit_distance < dist_inside_boundary {
+          return Some(t1 + hit_distance / ray.direction.length());
+        }
+      }
+    }
+    None
+  }
 }
 
 impl Hitable for ConstantMedium {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-      if let Some(mut hit1) = self.boundary.hit(ray, -std::f64::MAX, std::f64::MAX) {
-        if let Some(mut hit2) = self.boundary.hit(ray, hit1.t + 0.0001, std::f64::MAX) {
-          hit1.t = hit1.t.max(t_min);
-          hit2.t = hit2.t.min(t_max);
-          if hit1.t < 0.0 {
-            return None;
-          }
-          let dist_inside_boundary = (hit2.t - hit1.t) * ray.direction.length();
-          let hit_distance = -(1.0 / self.density) * rand_f64().log(std::f64::consts::E);
-          if hit_distance < dist_inside_boundary {
-            let t = hit1.t + hit_distance / ray.direction.length();
-            let pt = ray.point_at_parameter(t);
-            return Some(HitRecord::new(t, pt, Vec3::new(0.0, 0.0, 0.0), 0.0, 0.0, self.material.clone()));
-
-          }
-        }
-      }
-      None
+      let t = self.scatter_distance(ray, t_min, t_max)?;
+      let pt = ray.point_at_parameter(t);
+      Some(HitRecord::new(t, pt, Vec3::new(0.0, 0.0, 0.0), 0.0, 0.0, self.material.clone()))
+    }
+
+    fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+      self.scatter_distance(ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+      self.scatter_distance(ray, t_min, t_max).is_some()
     }
 
     fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
       self.boundary.bounding_box(time0, time1)
     }
+
+    fn is_bounded(&self) -> bool {
+      self.boundary.is_bounded()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,244 @@
+extern crate byteorder;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use self::byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+// A minimal OpenEXR writer: single part, scanline, uncompressed. Channels are
+// named the EXR way, a bare "R", "G", "B" for the beauty pass and
+// "layer.channel" for everything else (e.g. "normal.X", "depth.Z"), so
+// compositing tools group them into layers.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrPixelType {
+  Half,
+  Float,
+}
+
+impl ExrPixelType {
+  fn id(&self) -> i32 {
+    match *self {
+      ExrPixelType::Half => 1,
+      ExrPixelType::Float => 2,
+    }
+  }
+
+  fn size(&self) -> usize {
+    match *self {
+      ExrPixelType::Half => 2,
+      ExrPixelType::Float => 4,
+    }
+  }
+}
+
+pub struct ExrImage {
+  width: u32,
+  height: u32,
+  // Channel name and values, rows top to bottom.
+  channels: Vec<(String, Vec<f32>)>,
+}
+
+impl ExrImage {
+  pub fn new(width: u32, height: u32) -> ExrImage {
+    ExrImage {
+      width,
+      height,
+      channels: Vec::new(),
+    }
+  }
+
+  // Adds a single channel of width * height values.
+  pub fn add_channel(&mut self, name: &str, values: &[f64]) {
+    assert_eq!(values.len(), (self.width * self.height) as usize, "Channel {} has the wrong size!", name);
+    assert!(self.channels.iter().all(|c| c.0 != name), "Channel {} already exists!", name);
+    self.channels.push((name.to_string(), values.iter().map(|v| *v as f32).collect()));
+  }
+
+  // Adds interleaved RGB pixels as the R, G and B channels of `layer`, use an
+  // empty layer name for the main image.
+  pub fn add_rgb(&mut self, layer: &str, pixels: &[f64]) {
+    let prefix = if layer.is_empty() {
+      String::new()
+    } else {
+      format!("{}.", layer)
+    };
+    for (c, name) in ["R", "G", "B"].iter().enumerate() {
+      let values: Vec<f64> = pixels.chunks(3).map(|p| p[c]).collect();
+      self.add_channel(&format!("{}{}", prefix, name), &values);
+    }
+  }
+
+  pub fn save(&self, path: &Path, pixel_type: ExrPixelType) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    self.write(&mut f, pixel_type)?;
+    f.flush()
+  }
+
+  pub fn write<W: Write>(&self, w: &mut W, pixel_type: ExrPixelType) -> io::Result<()> {
+    // The file format wants channels in alphabetical order.
+    let mut channels: Vec<&(String, Vec<f32>)> = self.channels.iter().collect();
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut header = Vec::new();
+    header.write_u32::<LittleEndian>(20000630)?;
+    header.write_u32::<LittleEndian>(2)?;
+
+    let mut chlist = Vec::new();
+    for channel in channels.iter() {
+      chlist.extend_from_slice(channel.0.as_bytes());
+      chlist.push(0);
+      chlist.write_i32::<LittleEndian>(pixel_type.id())?;
+      // pLinear and three reserved bytes, then the x and y sampling.
+      chlist.extend_from_slice(&[0, 0, 0, 0]);
+      chlist.write_i32::<LittleEndian>(1)?;
+      chlist.write_i32::<LittleEndian>(1)?;
+    }
+    chlist.push(0);
+    write_attribute(&mut header, "channels", "chlist", &chlist)?;
+    write_attribute(&mut header, "compression", "compression", &[0])?;
+    let mut window = Vec::new();
+    for v in [0, 0, self.width as i32 - 1, self.height as i32 - 1].iter() {
+      window.write_i32::<LittleEndian>(*v)?;
+    }
+    write_attribute(&mut header, "dataWindow", "box2i", &window)?;
+    write_attribute(&mut header, "displayWindow", "box2i", &window)?;
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    write_attribute(&mut header, "pixelAspectRatio", "float", &f32_bytes(1.0))?;
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_attribute(&mut header, "screenWindowWidth", "float", &f32_bytes(1.0))?;
+    header.push(0);
+
+    // One chunk per scanline, each prefixed by its y and its data size.
+    let width = self.width as usize;
+    let line_size = width * channels.len() * pixel_type.size();
+    let chunk_size = 8 + line_size;
+    let first_chunk = header.len() + self.height as usize * 8;
+    for y in 0..self.height as usize {
+      header.write_u64::<LittleEndian>((first_chunk + y * chunk_size) as u64)?;
+    }
+    w.write_all(&header)?;
+
+    let mut line = Vec::with_capacity(chunk_size);
+    for y in 0..self.height as usize {
+      line.clear();
+      line.write_i32::<LittleEndian>(y as i32)?;
+      line.write_i32::<LittleEndian>(line_size as i32)?;
+      for channel in channels.iter() {
+        for v in channel.1[y * width..(y + 1) * width].iter() {
+          match pixel_type {
+            ExrPixelType::Half => line.write_u16::<LittleEndian>(f32_to_half(*v))?,
+            ExrPixelType::Float => line.write_f32::<LittleEndian>(*v)?,
+          }
+        }
+      }
+      w.write_all(&line)?;
+    }
+    Ok(())
+  }
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) -> io::Result<()> {
+  header.extend_from_slice(name.as_bytes());
+  header.push(0);
+  header.extend_from_slice(type_name.as_bytes());
+  header.push(0);
+  header.write_i32::<LittleEndian>(value.len() as i32)?;
+  header.extend_from_slice(value);
+  Ok(())
+}
+
+fn f32_bytes(v: f32) -> [u8; 4] {
+  let mut bytes = [0; 4];
+  LittleEndian::write_f32(&mut bytes, v);
+  bytes
+}
+
+// IEEE half precision, rounding to nearest even.
+pub fn f32_to_half(v: f32) -> u16 {
+  let bits = v.to_bits();
+  let sign = ((bits >> 16) & 0x8000) as u16;
+  let exponent = ((bits >> 23) & 0xff) as i32;
+  let mantissa = bits & 0x7f_ffff;
+  if exponent == 0xff {
+    // Infinity stays infinite, NaN stays NaN.
+    return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+  }
+  let half_exponent = exponent - 127 + 15;
+  if half_exponent >= 0x1f {
+    return sign | 0x7c00;
+  }
+  if half_exponent <= 0 {
+    // Subnormal or zero, shift the implicit leading one into the mantissa.
+    if half_exponent < -10 {
+      return sign;
+    }
+    let m = mantissa | 0x80_0000;
+    let shift = (14 - half_exponent) as u32;
+    let round = 1 << (shift - 1);
+    let half = m >> shift;
+    let rest = m & ((1 << shift) - 1);
+    let rounded = if rest > round || (rest == round && half & 1 == 1) { half + 1 } else { half };
+    return sign | rounded as u16;
+  }
+  let half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+  let rest = mantissa & 0x1fff;
+  // A carry out of the mantissa correctly bumps the exponent, even to infinity.
+  let rounded = if rest > 0x1000 || (rest == 0x1000 && half & 1 == 1) { half + 1 } else { half };
+  sign | rounded as u16
+}
+
+#[cfg(test)]
+mod tests {
+
+  use exr::*;
+
+  #[test]
+  fn test_half() {
+    assert_eq!(f32_to_half(0.0), 0x0000);
+    assert_eq!(f32_to_half(-0.0), 0x8000);
+    assert_eq!(f32_to_half(1.0), 0x3c00);
+    assert_eq!(f32_to_half(-2.0), 0xc000);
+    assert_eq!(f32_to_half(0.5), 0x3800);
+    assert_eq!(f32_to_half(65504.0), 0x7bff);
+    assert_eq!(f32_to_half(1.0e6), 0x7c00);
+    assert_eq!(f32_to_half(f32::INFINITY), 0x7c00);
+    assert_eq!(f32_to_half(f32::NAN) & 0x7e00, 0x7e00);
+    // Smallest subnormal and something too small to represent.
+    assert_eq!(f32_to_half(2f32.powi(-24)), 0x0001);
+    assert_eq!(f32_to_half(1.0e-9), 0x0000);
+    // 1 + 2^-11 is halfway between two halfs and rounds to even.
+    assert_eq!(f32_to_half(1.0 + 2f32.powi(-11)), 0x3c00);
+    assert_eq!(f32_to_half(1.0 + 2f32.powi(-10) + 2f32.powi(-11)), 0x3c02);
+  }
+
+  #[test]
+  fn test_layout() {
+    let mut image = ExrImage::new(2, 3);
+    image.add_rgb("", &[0.0; 18]);
+    image.add_channel("depth.Z", &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    let mut data = Vec::new();
+    image.write(&mut data, ExrPixelType::Float).unwrap();
+    assert_eq!(LittleEndian::read_u32(&data[0..4]), 20000630);
+
+    // Channels come out sorted, with depth.Z after the capitals.
+    let names: Vec<usize> = ["B", "G", "R", "depth.Z"].iter()
+      .map(|name| {
+        let mut needle = name.as_bytes().to_vec();
+        needle.extend_from_slice(&[0, 2, 0, 0, 0]);
+        data.windows(needle.len()).position(|w| w == &needle[..]).unwrap()
+      }).collect();
+    assert!(names.windows(2).all(|w| w[0] < w[1]));
+
+    // Last scanline: y, size, then 2 floats for each of the four channels.
+    let header_end = data.len() - 3 * (8 + 2 * 4 * 4) - 3 * 8;
+    let last_offset = LittleEndian::read_u64(&data[header_end + 16..header_end + 24]) as usize;
+    assert_eq!(LittleEndian::read_i32(&data[last_offset..]), 2);
+    assert_eq!(LittleEndian::read_i32(&data[last_offset + 4..]), 32);
+    let depth = last_offset + 8 + 3 * 8;
+    assert_eq!(LittleEndian::read_f32(&data[depth..]), 5.0);
+    assert_eq!(LittleEndian::read_f32(&data[depth + 4..]), 6.0);
+    assert_eq!(data.len(), last_offset + 40);
+  }
+}
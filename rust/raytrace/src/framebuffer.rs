@@ -0,0 +1,222 @@
+extern crate byteorder;
+
+use std::io;
+use std::io::{Read, Write};
+use vec3::Vec3;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// Running sums of the samples taken so far for every pixel, so renders can be
+// looked at, or stopped, after any number of passes. Pixels are stored rows
+// top to bottom, like the images handed to output.rs. The sums of squares give
+// the variance of every pixel for adaptive sampling.
+pub struct Framebuffer {
+  width: u32,
+  height: u32,
+  sums: Vec<f64>,
+  sums_sq: Vec<f64>,
+  counts: Vec<u32>,
+}
+
+// Relative errors are taken against at least this much brightness, otherwise
+// the darkest pixels would never count as converged.
+const ERROR_FLOOR: f64 = 0.01;
+
+impl Framebuffer {
+  pub fn new(width: u32, height: u32) -> Framebuffer {
+    let n = (width * height) as usize;
+    Framebuffer {
+      width,
+      height,
+      sums: vec![0.0; n * 3],
+      sums_sq: vec![0.0; n * 3],
+      counts: vec![0; n],
+    }
+  }
+
+  pub fn width(&self) -> u32 {
+    self.width
+  }
+
+  pub fn height(&self) -> u32 {
+    self.height
+  }
+
+  // Adds `count` samples adding up to `sum`, with squares adding up to
+  // `sum_sq`, to the pixel.
+  pub fn add(&mut self, x: u32, y: u32, sum: &Vec3, sum_sq: &Vec3, count: u32) {
+    let index = (y * self.width + x) as usize;
+    for c in 0..3 {
+      self.sums[index * 3 + c] += sum[c];
+      self.sums_sq[index * 3 + c] += sum_sq[c];
+    }
+    self.counts[index] += count;
+  }
+
+  pub fn count(&self, x: u32, y: u32) -> u32 {
+    self.counts[(y * self.width + x) as usize]
+  }
+
+  pub fn max_count(&self) -> u32 {
+    self.counts.iter().cloned().max().unwrap_or(0)
+  }
+
+  // The standard error of the pixel mean relative to its brightness, taking
+  // the worst channel. Infinite until there are two samples to compare.
+  pub fn relative_error(&self, x: u32, y: u32) -> f64 {
+    let index = (y * self.width + x) as usize;
+    let n = self.counts[index] as f64;
+    if n < 2.0 {
+      return f64::INFINITY;
+    }
+    let mut brightness: f64 = ERROR_FLOOR;
+    let mut error: f64 = 0.0;
+    for c in 0..3 {
+      let mean = self.sums[index * 3 + c] / n;
+      let variance = ((self.sums_sq[index * 3 + c] - n * mean * mean) / (n - 1.0)).max(0.0);
+      brightness = brightness.max(mean);
+      error = error.max((variance / n).sqrt());
+    }
+    error / brightness
+  }
+
+  // True when the pixel and its eight neighbours are all within `target`
+  // relative error. Looking at the neighbours keeps a pixel going whose few
+  // samples all happened to miss something its neighbours did find.
+  pub fn converged(&self, x: u32, y: u32, target: f64) -> bool {
+    for ny in y.saturating_sub(1)..(y + 2).min(self.height) {
+      for nx in x.saturating_sub(1)..(x + 2).min(self.width) {
+        let error = self.relative_error(nx, ny);
+        if error.is_nan() || error > target {
+          return false;
+        }
+      }
+    }
+    true
+  }
+
+  pub fn total_samples(&self) -> u64 {
+    self.counts.iter().map(|c| *c as u64).sum()
+  }
+
+  // The average of every pixel, black where nothing was sampled yet.
+  pub fn image(&self) -> Vec<f64> {
+    let mut ret = Vec::with_capacity(self.sums.len());
+    for (sum, count) in self.sums.chunks(3).zip(self.counts.iter()) {
+      for c in sum {
+        ret.push(if *count > 0 { c / *count as f64 } else { 0.0 });
+      }
+    }
+    ret
+  }
+
+  // Adds the samples of another render of the same size, which must not
+  // repeat any of ours.
+  pub fn merge(&mut self, other: &Framebuffer) {
+    assert!(self.width == other.width && self.height == other.height, "Framebuffer sizes differ!");
+    for (a, b) in self.sums.iter_mut().zip(other.sums.iter()) {
+      *a += *b;
+    }
+    for (a, b) in self.sums_sq.iter_mut().zip(other.sums_sq.iter()) {
+      *a += *b;
+    }
+    for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+      *a += *b;
+    }
+  }
+
+  // The raw sums and counts, little endian, without the dimensions.
+  pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    for index in 0..self.counts.len() {
+      for c in 0..3 {
+        w.write_f64::<LittleEndian>(self.sums[index * 3 + c])?;
+      }
+      for c in 0..3 {
+        w.write_f64::<LittleEndian>(self.sums_sq[index * 3 + c])?;
+      }
+      w.write_u32::<LittleEndian>(self.counts[index])?;
+    }
+    Ok(())
+  }
+
+  pub fn read_from<R: Read>(r: &mut R, width: u32, height: u32) -> io::Result<Framebuffer> {
+    let mut framebuffer = Framebuffer::new(width, height);
+    for index in 0..framebuffer.counts.len() {
+      for c in 0..3 {
+        framebuffer.sums[index * 3 + c] = r.read_f64::<LittleEndian>()?;
+      }
+      for c in 0..3 {
+        framebuffer.sums_sq[index * 3 + c] = r.read_f64::<LittleEndian>()?;
+      }
+      framebuffer.counts[index] = r.read_u32::<LittleEndian>()?;
+    }
+    Ok(framebuffer)
+  }
+
+  // Every pixel's sample count over `scale` as a grey image, to show where
+  // adaptive sampling spent its effort.
+  pub fn count_image(&self, scale: u32) -> Vec<f64> {
+    let mut ret = Vec::with_capacity(self.sums.len());
+    for count in &self.counts {
+      let v = *count as f64 / scale as f64;
+      ret.extend_from_slice(&[v, v, v]);
+    }
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use framebuffer::*;
+
+  #[test]
+  fn test_accumulate() {
+    let mut framebuffer = Framebuffer::new(2, 1);
+    framebuffer.add(1, 0, &Vec3::new(1.0, 2.0, 3.0), &Vec3::new(1.0, 4.0, 5.0), 2);
+    framebuffer.add(1, 0, &Vec3::new(1.0, 0.0, 1.0), &Vec3::new(1.0, 0.0, 1.0), 2);
+    assert_eq!(framebuffer.count(0, 0), 0);
+    assert_eq!(framebuffer.count(1, 0), 4);
+    assert_eq!(framebuffer.max_count(), 4);
+    assert_eq!(framebuffer.total_samples(), 4);
+    assert_eq!(framebuffer.image(), vec![0.0, 0.0, 0.0, 0.5, 0.5, 1.0]);
+    assert_eq!(framebuffer.count_image(8), vec![0.0, 0.0, 0.0, 0.5, 0.5, 0.5]);
+  }
+
+  #[test]
+  fn test_merge() {
+    let mut a = Framebuffer::new(1, 2);
+    a.add(0, 1, &Vec3::new(1.0, 2.0, 3.0), &Vec3::new(1.0, 4.0, 9.0), 1);
+    let mut bytes = Vec::new();
+    a.write_to(&mut bytes).unwrap();
+    let b = Framebuffer::read_from(&mut &bytes[..], 1, 2).unwrap();
+    assert_eq!(b.image(), a.image());
+    a.merge(&b);
+    assert_eq!(a.count(0, 1), 2);
+    assert_eq!(a.image(), b.image());
+    assert_eq!(a.relative_error(0, 1), 0.0);
+    assert!(Framebuffer::read_from(&mut &bytes[1..], 1, 2).is_err());
+  }
+
+  #[test]
+  fn test_relative_error() {
+    let mut framebuffer = Framebuffer::new(3, 3);
+    for y in 0..3 {
+      for x in 0..3 {
+        if (x, y) == (2, 2) {
+          // Samples of 0, 2, 0 and 2: mean 1 and variance 4/3.
+          framebuffer.add(x, y, &Vec3::new(4.0, 4.0, 4.0), &Vec3::new(8.0, 8.0, 8.0), 4);
+        } else {
+          // Four samples of 1.
+          framebuffer.add(x, y, &Vec3::new(4.0, 4.0, 4.0), &Vec3::new(4.0, 4.0, 4.0), 4);
+        }
+      }
+    }
+    assert_eq!(framebuffer.relative_error(0, 0), 0.0);
+    assert!((framebuffer.relative_error(2, 2) - (1.0f64 / 3.0).sqrt()).abs() < 1e-12);
+    assert!(framebuffer.converged(0, 0, 0.01));
+    assert!(!framebuffer.converged(1, 1, 0.01));
+    assert!(framebuffer.converged(1, 1, 0.6));
+    assert!(Framebuffer::new(1, 1).relative_error(0, 0).is_infinite());
+  }
+}
@@ -6,7 +6,10 @@ use vec3::Vec3;
 use ray::Ray;
 use material::MaterialPtr;
 use aabb::Aabb;
-use rt_rand::*;
+use bvh::{BvhTree, DEFAULT_BVH_LEAF_SIZE};
+use mat4::Mat4;
+use onb::Onb;
+use sampler::{Sampler, sample_unit_sphere};
 
 #[derive(Clone)]
 pub struct HitRecord {
@@ -40,10 +43,59 @@ impl fmt::Debug for HitRecord {
 pub trait Hitable {
   fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
   fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb;
+
+  // Distance to the closest hit in the interval, without working out the
+  // normal, texture coordinates or material. Aggregates look for their
+  // closest child with it and only have that one build a `HitRecord`.
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.hit(ray, t_min, t_max).map(|hit| hit.t)
+  }
+
+  // Whether anything is hit in the interval, for shadow rays. Unlike `hit`
+  // this may stop at the first hit found and never works out a normal,
+  // texture coordinates or material.
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.hit(ray, t_min, t_max).is_some()
+  }
+
+  // Hitables that go on forever, like planes, answer false and return
+  // `Aabb::infinite()`. A BVH can't split those up, `accelerate` keeps them
+  // in a list next to the BVH of everything else.
+  fn is_bounded(&self) -> bool {
+    true
+  }
+
+  // Lights are emissive hitables the renderer can sample directly, they
+  // implement `random` and `pdf_value`.
+  fn is_light(&self) -> bool {
+    false
+  }
+
+  // Aggregates hand over the lights among their children.
+  fn gather_lights(&self, _lights: &mut Vec<HitablePtr>) {
+  }
+
+  // Solid angle density of `random` picking `direction` from `origin`.
+  fn pdf_value(&self, _origin: &Vec3, _direction: &Vec3, _time: f64) -> f64 {
+    0.0
+  }
+
+  // A direction from `origin` towards a random point on the surface.
+  fn random(&self, _origin: &Vec3, _time: f64, _sampler: &mut dyn Sampler) -> Vec3 {
+    Vec3::new(1.0, 0.0, 0.0)
+  }
 }
 
 pub type HitablePtr = Arc<Hitable + Sync + Send>;
 
+pub fn collect_lights(hitable: &HitablePtr, lights: &mut Vec<HitablePtr>) {
+  if hitable.is_light() {
+    lights.push(Arc::clone(hitable));
+  } else {
+    hitable.gather_lights(lights);
+  }
+}
+
 pub struct HitableList {
   pub list: Vec<HitablePtr>,
 }
@@ -58,168 +110,150 @@ impl HitableList {
   pub fn add_hitable(&mut self, hitable: HitablePtr) {
     self.list.push(hitable);
   }
-}
 
-impl Hitable for HitableList {
-  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-    let mut hit: Option<HitRecord> = None;
+  fn closest(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, &HitablePtr)> {
     let mut closest = t_max;
+    let mut found = None;
     for hitable in self.list.iter() {
-      let result = hitable.hit(ray, t_min, closest);
-      if let Some(hit_record) = result {
-        hit = Some(hit_record.clone());
-        closest = hit_record.t;
+      if let Some(t) = hitable.hit_distance(ray, t_min, closest) {
+        closest = t;
+        found = Some(hitable);
       }
     }
-    hit
+    found.map(|hitable| (closest, hitable))
   }
+}
 
-  fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
-    panic!("Implement me?");
+impl Hitable for HitableList {
+  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    let (_, hitable) = self.closest(ray, t_min, t_max)?;
+    hitable.hit(ray, t_min, t_max)
   }
-}
 
-struct BvhNode {
-  left: Option<HitablePtr>,
-  right: Option<HitablePtr>,
-  bbox: Aabb,
-}
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.closest(ray, t_min, t_max).map(|(t, _)| t)
+  }
 
-impl BvhNode {
-  fn hitable_ptr() -> Arc<BvhNode> {
-    Arc::new(BvhNode {
-      left: None,
-      right: None,
-      bbox: Aabb::new(Vec3::zero(), Vec3::zero()),
-    })
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.list.iter().any(|hitable| hitable.occluded(ray, t_min, t_max))
   }
-}
 
-impl Hitable for BvhNode {
-  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-    if !self.bbox.hit(&ray, t_min, t_max) {
-      return None
+  fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
+    self.list.iter().fold(Aabb::empty(), |aabb, hitable| Aabb::surrounding_box(&aabb, &hitable.bounding_box(time0, time1)))
+  }
+
+  fn is_bounded(&self) -> bool {
+    self.list.iter().all(|hitable| hitable.is_bounded())
+  }
+
+  fn gather_lights(&self, lights: &mut Vec<HitablePtr>) {
+    for hitable in self.list.iter() {
+      collect_lights(hitable, lights);
     }
-    if let Some(ref left_hitable) = self.left {
-      if let Some(ref right_hitable) = self.right {
-        let hits = (
-          left_hitable.hit(&ray, t_min, t_max),
-          right_hitable.hit(&ray, t_min, t_max)
-        );
-        match hits {
-          (None, None) => return None,
-          (Some(left), None) => return Some(left),
-          (None, Some(right)) => return Some(right),
-          (Some(left), Some(right)) => if left.t < right.t {
-            return Some(left);
-          } else {
-            return Some(right);
-          }
-        }
-      } else {
-        return left_hitable.hit(&ray, t_min, t_max);
-      }
-    } else {
-      panic!("Empty node!!");
+  }
+
+  // Sampling a list picks one of its members uniformly.
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    if self.list.is_empty() {
+      return 0.0;
     }
+    let sum: f64 = self.list.iter().map(|h| h.pdf_value(origin, direction, time)).sum();
+    sum / self.list.len() as f64
   }
 
-  fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
-    self.bbox.clone()
+  fn random(&self, origin: &Vec3, time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let index = ((sampler.get_1d() * self.list.len() as f64) as usize).min(self.list.len() - 1);
+    self.list[index].random(origin, time, sampler)
   }
 }
+
+// A bounding volume hierarchy over hitables. Leaves refer to ranges of
+// `hitables`, which are kept in the order the tree was built in.
 pub struct Bvh {
-  root: Option<HitablePtr>,
+  hitables: Vec<HitablePtr>,
+  tree: BvhTree,
 }
 
 impl Bvh {
-  pub fn new(mut hitables: Vec<HitablePtr>, time0: f64, time1: f64) -> Bvh {
-    let mut indices: Vec<usize> = Vec::with_capacity(hitables.len());
-    for i in 0..hitables.len() {
-      indices.push(i);
-    }
+  pub fn new(hitables: Vec<HitablePtr>, time0: f64, time1: f64) -> Bvh {
+    Bvh::with_leaf_size(hitables, time0, time1, DEFAULT_BVH_LEAF_SIZE)
+  }
+
+  // Hitables are boxed trait objects, with leaves of `max_leaf_size` at
+  // most, fewer where the surface area heuristic says splitting pays off.
+  pub fn with_leaf_size(hitables: Vec<HitablePtr>, time0: f64, time1: f64, max_leaf_size: usize) -> Bvh {
+    let boxes: Vec<Aabb> = hitables.iter().map(|hitable| hitable.bounding_box(time0, time1)).collect();
+    let (tree, order) = BvhTree::build(&boxes, max_leaf_size);
     Bvh {
-      root: Bvh::build(&mut hitables, &mut indices, time0, time1),
-    }
-  }
-
-  fn build(hitables: &mut Vec<HitablePtr>, hitable_indices: &mut Vec<usize>, time0: f64, time1: f64) -> Option<HitablePtr> {
-    // Alloc our bvh node
-    let mut new_node_rc = BvhNode::hitable_ptr();
-    {
-      let new_node = Arc::get_mut(&mut new_node_rc).unwrap();
-      // We've hit the leaves
-      let indices_len = hitable_indices.len();
-      match indices_len {
-        0 => panic!("Invalid!"),
-        1 => {
-          new_node.bbox = hitables[hitable_indices[0]].bounding_box(time0, time1);
-          new_node.left = Some(Arc::clone(&hitables[hitable_indices[0]]));
-        },
-        2 => {
-          new_node.bbox = Aabb::surrounding_box(
-            &hitables[hitable_indices[0]].bounding_box(time0, time1),
-            &hitables[hitable_indices[1]].bounding_box(time0, time1)
-          );
-          new_node.left = Some(Arc::clone(&hitables[hitable_indices[0]]));
-          new_node.right = Some(Arc::clone(&hitables[hitable_indices[1]]));
-        },
-        _ => {
-          // Sort and divide the list
-          let axis: usize = rand_usize() % 3;
-          hitable_indices.sort_unstable_by(|a, b| {
-            let a_aabb = hitables[*a].bounding_box(time0, time1);
-            let b_aabb = hitables[*b].bounding_box(time0, time1);
-            if let Some(res) = a_aabb.min[axis].partial_cmp(&b_aabb.min[axis]) {
-              res
-            } else {
-              panic!("No NANS");
-            }
-          });
-          let mut right_indices = hitable_indices.split_off(indices_len / 2);
-          new_node.left = Bvh::build(hitables, hitable_indices, time0, time1);
-          new_node.right = Bvh::build(hitables, &mut right_indices, time0, time1);
-          let bbox;
-          if let Some(ref l) = new_node.left {
-            if let Some(ref r) = new_node.right {
-              bbox = Aabb::surrounding_box(
-                &l.bounding_box(time0, time1),
-                &r.bounding_box(time0, time1));
-            } else {
-              panic!("I should be defined");
-            }
-          } else {
-            panic!("I should be defined!");
-          }
-          new_node.bbox = bbox;
+      hitables: order.iter().map(|&i| Arc::clone(&hitables[i])).collect(),
+      tree,
+    }
+  }
+
+  fn closest(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, &HitablePtr)> {
+    let mut closest = t_max;
+    let mut found = None;
+    self.tree.traverse(ray, t_min, t_max, |range| {
+      for hitable in self.hitables[range].iter() {
+        if let Some(t) = hitable.hit_distance(ray, t_min, closest) {
+          closest = t;
+          found = Some(hitable);
         }
       }
-    }
-    Some(new_node_rc)
+      Some(closest)
+    });
+    found.map(|hitable| (closest, hitable))
   }
 }
 
 impl Hitable for Bvh {
+  // Only the closest hitable pays for its normal, uv and material.
   fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-    if let Some(ref root) = self.root {
-      root.hit(ray, t_min, t_max)
-    } else {
-      None
-    }
+    let (_, hitable) = self.closest(ray, t_min, t_max)?;
+    hitable.hit(ray, t_min, t_max)
   }
 
-  fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
-    if let Some(ref root) = self.root {
-      root.bounding_box(time0, time1)
-    } else {
-      Aabb {
-        min: Vec3::zero(),
-        max: Vec3::zero()
-      }
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.closest(ray, t_min, t_max).map(|(t, _)| t)
+  }
+
+  // Any hit will do, but the near side is still the likelier to block.
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    let mut occluded = false;
+    self.tree.traverse(ray, t_min, t_max, |range| {
+      occluded = self.hitables[range].iter().any(|hitable| hitable.occluded(ray, t_min, t_max));
+      if occluded { None } else { Some(t_max) }
+    });
+    occluded
+  }
+
+  fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
+    self.tree.bounding_box()
+  }
+
+  fn gather_lights(&self, lights: &mut Vec<HitablePtr>) {
+    for hitable in self.hitables.iter() {
+      collect_lights(hitable, lights);
     }
   }
 }
 
+// The top level of a scene: a BVH over the bounded hitables, listed next to
+// any unbounded ones, which are tested against every ray.
+pub fn accelerate(hitables: Vec<HitablePtr>, time0: f64, time1: f64) -> HitablePtr {
+  let (bounded, unbounded): (Vec<HitablePtr>, Vec<HitablePtr>) = hitables.into_iter().partition(|hitable| hitable.is_bounded());
+  let bvh = Arc::new(Bvh::new(bounded, time0, time1));
+  if unbounded.is_empty() {
+    return bvh;
+  }
+  let mut list = HitableList::new();
+  list.add_hitable(bvh);
+  for hitable in unbounded {
+    list.add_hitable(hitable);
+  }
+  Arc::new(list)
+}
+
 pub struct FlipNormals {
   hitable: HitablePtr,
 }
@@ -246,9 +280,41 @@ impl Hitable for FlipNormals {
     }
   }
 
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.hitable.hit_distance(ray, t_min, t_max)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.hitable.occluded(ray, t_min, t_max)
+  }
+
   fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
     self.hitable.bounding_box(time0, time1)
   }
+
+  fn is_bounded(&self) -> bool {
+    self.hitable.is_bounded()
+  }
+
+  fn is_light(&self) -> bool {
+    self.hitable.is_light()
+  }
+
+  fn gather_lights(&self, lights: &mut Vec<HitablePtr>) {
+    let mut inner = Vec::new();
+    self.hitable.gather_lights(&mut inner);
+    for light in inner {
+      lights.push(FlipNormals::hitable_ptr(light));
+    }
+  }
+
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    self.hitable.pdf_value(origin, direction, time)
+  }
+
+  fn random(&self, origin: &Vec3, time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    self.hitable.random(origin, time, sampler)
+  }
 }
 
 pub struct Translate {
@@ -280,16 +346,50 @@ impl Hitable for Translate {
     }
   }
 
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.hitable.hit_distance(&Ray::new(ray.origin - self.offset, ray.direction, ray.time), t_min, t_max)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.hitable.occluded(&Ray::new(ray.origin - self.offset, ray.direction, ray.time), t_min, t_max)
+  }
+
   fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
     let mut ret = self.hitable.bounding_box(time0, time1);
     ret.min = ret.min + self.offset;
     ret.max = ret.max + self.offset;
     ret
   }
+
+  fn is_bounded(&self) -> bool {
+    self.hitable.is_bounded()
+  }
+
+  fn is_light(&self) -> bool {
+    self.hitable.is_light()
+  }
+
+  // Lights inside are moved along with everything else.
+  fn gather_lights(&self, lights: &mut Vec<HitablePtr>) {
+    let mut inner = Vec::new();
+    self.hitable.gather_lights(&mut inner);
+    for light in inner {
+      lights.push(Translate::hitable_ptr(light, self.offset));
+    }
+  }
+
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    self.hitable.pdf_value(&(*origin - self.offset), direction, time)
+  }
+
+  fn random(&self, origin: &Vec3, time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    self.hitable.random(&(*origin - self.offset), time, sampler)
+  }
 }
 
 pub struct RotateY {
   hitable: HitablePtr,
+  angle_degrees: f64,
   sin_theta: f64,
   cos_theta: f64,
   aabb: Aabb
@@ -322,6 +422,7 @@ impl RotateY {
     }
     RotateY {
       hitable,
+      angle_degrees,
       sin_theta,
       cos_theta,
       aabb: Aabb::new(minb, maxb)
@@ -355,9 +456,156 @@ impl Hitable for RotateY {
     }
   }
 
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    let rotated_ray = Ray::new(self.rotate_vec3(ray.origin), self.rotate_vec3(ray.direction), ray.time);
+    self.hitable.hit_distance(&rotated_ray, t_min, t_max)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    let rotated_ray = Ray::new(self.rotate_vec3(ray.origin), self.rotate_vec3(ray.direction), ray.time);
+    self.hitable.occluded(&rotated_ray, t_min, t_max)
+  }
+
   fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
     self.aabb.clone()
   }
+
+  fn is_bounded(&self) -> bool {
+    self.hitable.is_bounded()
+  }
+
+  fn is_light(&self) -> bool {
+    self.hitable.is_light()
+  }
+
+  fn gather_lights(&self, lights: &mut Vec<HitablePtr>) {
+    let mut inner = Vec::new();
+    self.hitable.gather_lights(&mut inner);
+    for light in inner {
+      lights.push(RotateY::hitable_ptr(light, self.angle_degrees));
+    }
+  }
+
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    self.hitable.pdf_value(&self.rotate_vec3(*origin), &self.rotate_vec3(*direction), time)
+  }
+
+  fn random(&self, origin: &Vec3, time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    self.inverse_rotate_vec3(self.hitable.random(&self.rotate_vec3(*origin), time, sampler))
+  }
+}
+
+// A general affine transform of a hitable. Rays are taken into object space
+// without normalizing the direction, so `t` means the same on both sides.
+// Hitables are shared through their Arc, so one BVH'd object can be placed
+// many times by wrapping it in several transforms.
+pub struct Transform {
+  hitable: HitablePtr,
+  to_world: Mat4,
+  to_object: Mat4,
+  // Normals go by the inverse transpose.
+  normal_to_world: Mat4,
+}
+
+impl Transform {
+  // `to_world` must be invertible.
+  pub fn new(hitable: HitablePtr, to_world: Mat4) -> Transform {
+    let to_object = to_world.inverse().expect("Singular transform matrix!");
+    Transform {
+      hitable,
+      to_world,
+      to_object,
+      normal_to_world: to_object.transpose(),
+    }
+  }
+
+  pub fn hitable_ptr(hitable: HitablePtr, to_world: Mat4) -> Arc<Transform> {
+    Arc::new(Transform::new(hitable, to_world))
+  }
+
+  fn object_ray(&self, ray: &Ray) -> Ray {
+    Ray::new(self.to_object.transform_point(&ray.origin), self.to_object.transform_vector(&ray.direction), ray.time)
+  }
+}
+
+impl Hitable for Transform {
+  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    if let Some(mut ret) = self.hitable.hit(&self.object_ray(ray), t_min, t_max) {
+      ret.p = self.to_world.transform_point(&ret.p);
+      ret.normal = self.normal_to_world.transform_vector(&ret.normal).normalized();
+      Some(ret)
+    } else {
+      None
+    }
+  }
+
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.hitable.hit_distance(&self.object_ray(ray), t_min, t_max)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.hitable.occluded(&self.object_ray(ray), t_min, t_max)
+  }
+
+  // The box around the transformed corners of the object's box.
+  fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
+    let aabb = self.hitable.bounding_box(time0, time1);
+    let m = f64::MAX;
+    let mut minb = Vec3::new(m, m, m);
+    let mut maxb = minb * -1.0;
+    for i in 0..8 {
+      let corner = Vec3::new(
+        if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+        if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+        if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+      );
+      let p = self.to_world.transform_point(&corner);
+      for c in 0..3 {
+        minb[c] = minb[c].min(p[c]);
+        maxb[c] = maxb[c].max(p[c]);
+      }
+    }
+    Aabb::new(minb, maxb)
+  }
+
+  fn is_bounded(&self) -> bool {
+    self.hitable.is_bounded()
+  }
+
+  fn is_light(&self) -> bool {
+    self.hitable.is_light()
+  }
+
+  // Every instance of a group places its lights anew, so each one of them
+  // gets sampled where that instance put it.
+  fn gather_lights(&self, lights: &mut Vec<HitablePtr>) {
+    let mut inner = Vec::new();
+    self.hitable.gather_lights(&mut inner);
+    for light in inner {
+      lights.push(Arc::new(Transform {
+        hitable: light,
+        to_world: self.to_world,
+        to_object: self.to_object,
+        normal_to_world: self.normal_to_world,
+      }));
+    }
+  }
+
+  // The object's density in object space times the Jacobian of mapping unit
+  // directions through `to_object`, |det M| / |M d|^3.
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    let object_direction = self.to_object.transform_vector(&direction.normalized());
+    let length = object_direction.length();
+    let pdf = self.hitable.pdf_value(&self.to_object.transform_point(origin), &object_direction, time);
+    pdf * self.to_object.determinant3().abs() / (length * length * length)
+  }
+
+  // Points map linearly, so the object space offset to the sampled point
+  // maps straight to the world space one.
+  fn random(&self, origin: &Vec3, time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let direction = self.hitable.random(&self.to_object.transform_point(origin), time, sampler);
+    self.to_world.transform_vector(&direction)
+  }
 }
 
 pub struct Sphere {
@@ -391,10 +639,9 @@ pub struct Sphere {
   pub fn hitable_ptr_moving(c0: Vec3, c1: Vec3, time0: f64, time1: f64, radius: f64, material: MaterialPtr) -> Arc<Sphere> {
     Arc::new(Sphere::new_moving(c0, c1, time0, time1, radius, material))
   }
-}
 
-impl Hitable for Sphere {
-  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+  // The nearest hit in the interval and where the center is at the time.
+  fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, Vec3)> {
     let center = (self.center)(ray.time);
     let oc = ray.origin - center;
     let a = Vec3::dot(&ray.direction, &ray.direction);
@@ -410,19 +657,31 @@ impl Hitable for Sphere {
         (-b + sq) / a
       };
       if t > t_min && t < t_max {
-        let pt = ray.point_at_parameter(t);
-        let normal = (pt - center) / self.radius;
-        let phi = normal.z.atan2(normal.x);
-        let theta = normal.y.asin();
-        let u = 1.0-(phi+std::f64::consts::PI) / (2.0 * std::f64::consts::PI);
-        let v = (theta + std::f64::consts::PI * 0.5) / std::f64::consts::PI;
-        Some(HitRecord::new(t, pt, normal, u, v, self.material.clone()))
-      } else {
-        None
+        return Some((t, center));
       }
-    } else {
-      None
     }
+    None
+  }
+}
+
+impl Hitable for Sphere {
+  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    let (t, center) = self.intersect(ray, t_min, t_max)?;
+    let pt = ray.point_at_parameter(t);
+    let normal = (pt - center) / self.radius;
+    let phi = normal.z.atan2(normal.x);
+    let theta = normal.y.asin();
+    let u = 1.0-(phi+std::f64::consts::PI) / (2.0 * std::f64::consts::PI);
+    let v = (theta + std::f64::consts::PI * 0.5) / std::f64::consts::PI;
+    Some(HitRecord::new(t, pt, normal, u, v, self.material.clone()))
+  }
+
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.intersect(ray, t_min, t_max).map(|(t, _)| t)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.intersect(ray, t_min, t_max).is_some()
   }
 
   fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
@@ -434,6 +693,57 @@ impl Hitable for Sphere {
       &Aabb::new(center1 - sz, center1 + sz)
     )
   }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
+
+  // From outside we sample the cone the sphere subtends, from inside we fall
+  // back to picking a point uniformly on the surface.
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    if let Some(hit) = self.hit(&Ray::new(*origin, *direction, time), 0.001, f64::MAX) {
+      let center = (self.center)(time);
+      let r2 = self.radius * self.radius;
+      let to_center = center - *origin;
+      let distance_squared = Vec3::dot(&to_center, &to_center);
+      if distance_squared > r2 {
+        let cos_theta_max = (1.0 - r2 / distance_squared).sqrt();
+        1.0 / (2.0 * std::f64::consts::PI * (1.0 - cos_theta_max))
+      } else {
+        let to_hit = hit.p - *origin;
+        let cosine = (Vec3::dot(&to_hit.normalized(), &hit.normal)).abs();
+        let area = 4.0 * std::f64::consts::PI * r2;
+        if cosine > 0.0 {
+          Vec3::dot(&to_hit, &to_hit) / (cosine * area)
+        } else {
+          0.0
+        }
+      }
+    } else {
+      0.0
+    }
+  }
+
+  fn random(&self, origin: &Vec3, time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let center = (self.center)(time);
+    let direction = center - *origin;
+    let distance_squared = Vec3::dot(&direction, &direction);
+    let r2 = self.radius * self.radius;
+    if distance_squared > r2 {
+      let uvw = Onb::from_w(&direction);
+      uvw.local(&random_to_sphere(r2, distance_squared, sampler.get_2d()))
+    } else {
+      center + self.radius * sample_unit_sphere(sampler.get_2d()) - *origin
+    }
+  }
+}
+
+fn random_to_sphere(radius_squared: f64, distance_squared: f64, u: (f64, f64)) -> Vec3 {
+  let (r1, r2) = u;
+  let z = 1.0 + r2 * ((1.0 - radius_squared / distance_squared).sqrt() - 1.0);
+  let phi = 2.0 * std::f64::consts::PI * r1;
+  let sin_theta = (1.0 - z*z).max(0.0).sqrt();
+  Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z)
 }
 
 struct AARect {
@@ -470,10 +780,9 @@ impl AARect {
   pub fn hitable_ptr(a_index: usize, b_index: usize, c_index: usize, a0: f64, b0: f64, a1: f64, b1: f64, c: f64, material: MaterialPtr) -> Arc<AARect> {
     Arc::new(AARect::new(a_index, b_index, c_index, a0, b0, a1, b1, c, material))
   }
-}
 
-impl Hitable for AARect {
-  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+  // The distance to the hit and where it is along the a and b axes.
+  fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64, f64)> {
     if ray.direction[self.c_index] == 0.0 {
       // println!("Case 1");
       return None;
@@ -493,6 +802,13 @@ impl Hitable for AARect {
       // println!("Case 4");
       return None
     }
+    Some((t, a, b))
+  }
+}
+
+impl Hitable for AARect {
+  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    let (t, a, b) = self.intersect(ray, t_min, t_max)?;
     let u = (a - self.a0) / self.a_range;
     let v = (b - self.b0) / self.b_range;
 
@@ -502,6 +818,14 @@ impl Hitable for AARect {
     Some(HitRecord::new(t, pt, normal, u, v, self.material.clone()))
   }
 
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.intersect(ray, t_min, t_max).map(|(t, _, _)| t)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.intersect(ray, t_min, t_max).is_some()
+  }
+
   fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
     let eplison = 0.0001;
     let mut b_min = Vec3::zero();
@@ -514,6 +838,34 @@ impl Hitable for AARect {
     b_max[self.c_index] = self.c + eplison;
     Aabb::new(b_min, b_max)
   }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
+
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    if let Some(hit) = self.hit(&Ray::new(*origin, *direction, time), 0.001, f64::MAX) {
+      let area = self.a_range * self.b_range;
+      let distance_squared = hit.t * hit.t * Vec3::dot(direction, direction);
+      let cosine = (Vec3::dot(direction, &hit.normal) / direction.length()).abs();
+      if cosine > 0.0 {
+        distance_squared / (cosine * area)
+      } else {
+        0.0
+      }
+    } else {
+      0.0
+    }
+  }
+
+  fn random(&self, origin: &Vec3, _time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let mut pt = Vec3::zero();
+    let (u, v) = sampler.get_2d();
+    pt[self.a_index] = self.a0 + u * self.a_range;
+    pt[self.b_index] = self.b0 + v * self.b_range;
+    pt[self.c_index] = self.c;
+    pt - *origin
+  }
 }
 
 pub struct Rect {
@@ -564,18 +916,105 @@ impl Hitable for AabbBox {
     self.faces.hit(ray, t_min, t_max)
   }
 
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.faces.hit_distance(ray, t_min, t_max)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.faces.occluded(ray, t_min, t_max)
+  }
+
   fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
     self.aabb.clone()
   }
+
+  fn is_light(&self) -> bool {
+    self.faces.list.iter().all(|face| face.is_light())
+  }
+
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    self.faces.pdf_value(origin, direction, time)
+  }
+
+  fn random(&self, origin: &Vec3, time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    self.faces.random(origin, time, sampler)
+  }
+}
+
+// An infinite plane through `point`, facing along `normal`. Texture
+// coordinates are distances along two axes in the plane, so textures that
+// don't go by the hit point repeat every unit.
+pub struct Plane {
+  point: Vec3,
+  axes: Onb,
+  material: MaterialPtr,
+}
+
+impl Plane {
+  pub fn new(point: Vec3, normal: Vec3, material: MaterialPtr) -> Plane {
+    Plane {
+      point,
+      axes: Onb::from_w(&normal),
+      material
+    }
+  }
+
+  pub fn hitable_ptr(point: Vec3, normal: Vec3, material: MaterialPtr) -> Arc<Plane> {
+    Arc::new(Plane::new(point, normal, material))
+  }
+
+  fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    let denominator = Vec3::dot(&ray.direction, &self.axes.w);
+    if denominator == 0.0 {
+      return None;
+    }
+    let t = Vec3::dot(&(self.point - ray.origin), &self.axes.w) / denominator;
+    if t > t_min && t < t_max {
+      Some(t)
+    } else {
+      None
+    }
+  }
+}
+
+impl Hitable for Plane {
+  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    let t = self.intersect(ray, t_min, t_max)?;
+    let pt = ray.point_at_parameter(t);
+    let offset = pt - self.point;
+    let u = Vec3::dot(&offset, &self.axes.u).rem_euclid(1.0);
+    let v = Vec3::dot(&offset, &self.axes.v).rem_euclid(1.0);
+    Some(HitRecord::new(t, pt, self.axes.w, u, v, self.material.clone()))
+  }
+
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.intersect(ray, t_min, t_max)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    self.intersect(ray, t_min, t_max).is_some()
+  }
+
+  fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
+    Aabb::infinite()
+  }
+
+  fn is_bounded(&self) -> bool {
+    false
+  }
 }
 
 #[cfg(test)]
 mod tests {
 
   use vec3::Vec3;
-  use material::Lambertian;
+  use material::{Lambertian, DiffuseLight};
   use texture::ConstantTexture;
   use ray::Ray;
+  use mat4::Mat4;
+  use sampler::RandomSampler;
+  use rt_rand::Rng;
+  use std::sync::atomic::{AtomicUsize, Ordering};
   use hitable::*;
 
   #[test]
@@ -602,4 +1041,185 @@ mod tests {
       assert_eq!(hit2.normal, Vec3::new(0.0, 1.0, 0.0));
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_transform() {
+    let mat: MaterialPtr = Lambertian::rc(ConstantTexture::rc(Vec3::new(1.0, 1.0, 1.0)));
+    let sphere = Sphere::hitable_ptr(Vec3::zero(), 1.0, mat);
+    // An ellipsoid stretched along x, centred on (5, 0, 0).
+    let ellipsoid = Transform::new(sphere, Mat4::translate(&Vec3::new(5.0, 0.0, 0.0)) * Mat4::scale(&Vec3::new(2.0, 1.0, 1.0)));
+    let hit = ellipsoid.hit(&Ray::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), 0.0), 0.001, f64::MAX).unwrap();
+    assert_eq!(hit.t, 3.0);
+    assert_eq!(hit.p, Vec3::new(3.0, 0.0, 0.0));
+    assert_eq!(hit.normal, Vec3::new(-1.0, 0.0, 0.0));
+    // Normals aren't just stretched along with the surface.
+    let x = 5.0 + 2.0f64.sqrt();
+    let hit = ellipsoid.hit(&Ray::new(Vec3::new(x, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0), 0.001, f64::MAX).unwrap();
+    assert!((hit.p - Vec3::new(x, 0.5f64.sqrt(), 0.0)).length() < 1e-9);
+    assert!((hit.normal - Vec3::new(1.0, 2.0, 0.0).normalized()).length() < 1e-9);
+    let bbox = ellipsoid.bounding_box(0.0, 1.0);
+    assert_eq!((bbox.min, bbox.max), (Vec3::new(3.0, -1.0, -1.0), Vec3::new(7.0, 1.0, 1.0)));
+
+    let mat: MaterialPtr = Lambertian::rc(ConstantTexture::rc(Vec3::new(1.0, 1.0, 1.0)));
+    let cube = AabbBox::hitable_ptr(Aabb::new(Vec3::zero(), Vec3::one()), mat);
+    let bbox = Transform::new(cube, Mat4::rotate_z(45.0)).bounding_box(0.0, 1.0);
+    let h = 0.5f64.sqrt();
+    assert!((bbox.min - Vec3::new(-h, 0.0, 0.0)).length() < 1e-9);
+    assert!((bbox.max - Vec3::new(h, 2.0 * h, 1.0)).length() < 1e-9);
+  }
+
+  #[test]
+  fn test_transformed_light() {
+    let light: MaterialPtr = DiffuseLight::rc(ConstantTexture::rc(Vec3::one()));
+    let unit = Rect::xzrect(0.0, 0.0, 1.0, 1.0, 0.0, Arc::clone(&light));
+    // Scaling unevenly changes how directions spread, so the pdf has to
+    // account for more than the area.
+    let scaled = Transform::new(unit, Mat4::translate(&Vec3::new(0.0, 5.0, 0.0)) * Mat4::scale(&Vec3::new(2.0, 1.0, 3.0)));
+    let rect = Rect::xzrect(0.0, 0.0, 2.0, 3.0, 5.0, light);
+    assert!(scaled.is_light());
+    let origin = Vec3::new(0.5, 0.0, 0.5);
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    for _ in 0..16 {
+      let direction = scaled.random(&origin, 0.0, &mut sampler);
+      let hit = rect.hit(&Ray::new(origin, direction, 0.0), 0.001, f64::MAX).unwrap();
+      assert!((hit.t - 1.0).abs() < 1e-9);
+      let expected = rect.pdf_value(&origin, &direction, 0.0);
+      assert!((scaled.pdf_value(&origin, &direction, 0.0) - expected).abs() < 1e-9 * expected);
+    }
+  }
+
+  #[test]
+  fn test_bvh() {
+    let mat: MaterialPtr = Lambertian::rc(ConstantTexture::rc(Vec3::new(1.0, 1.0, 1.0)));
+    let mut rng = Rng::new(0);
+    let mut list = HitableList::new();
+    for i in 0..200 {
+      let center = Vec3::new(rng.rand_f64(), rng.rand_f64(), rng.rand_f64()) * 20.0;
+      // A few coinciding spheres, which no split can separate.
+      let center = if i % 50 == 0 { Vec3::zero() } else { center };
+      list.add_hitable(Sphere::hitable_ptr(center, 0.5 + rng.rand_f64(), Arc::clone(&mat)));
+    }
+    let bvhs: Vec<Bvh> = [1, 4, 16].iter().map(|&n| Bvh::with_leaf_size(list.list.clone(), 0.0, 1.0, n)).collect();
+    for _ in 0..500 {
+      let origin = Vec3::new(rng.rand_f64(), rng.rand_f64(), rng.rand_f64()) * 30.0 - Vec3::one() * 5.0;
+      let direction = Vec3::new(rng.rand_f64() - 0.5, rng.rand_f64() - 0.5, rng.rand_f64() - 0.5);
+      let ray = Ray::new(origin, direction, 0.0);
+      let expected = list.hit(&ray, 0.001, f64::MAX).map(|hit| hit.t);
+      for bvh in bvhs.iter() {
+        assert_eq!(bvh.hit(&ray, 0.001, f64::MAX).map(|hit| hit.t), expected);
+      }
+    }
+    assert!(Bvh::new(Vec::new(), 0.0, 1.0).hit(&Ray::new(Vec3::zero(), Vec3::one(), 0.0), 0.0, 1.0).is_none());
+  }
+
+  #[test]
+  fn test_occluded() {
+    let mat: MaterialPtr = Lambertian::rc(ConstantTexture::rc(Vec3::new(1.0, 1.0, 1.0)));
+    let mut rng = Rng::new(1);
+    let cube: HitablePtr = AabbBox::hitable_ptr(Aabb::new(Vec3::zero(), Vec3::one()), Arc::clone(&mat));
+    let objects: Vec<HitablePtr> = vec![
+      Sphere::hitable_ptr_moving(Vec3::new(2.0, 0.0, 0.0), Vec3::new(2.0, 1.0, 0.0), 0.0, 1.0, 0.5, Arc::clone(&mat)),
+      FlipNormals::hitable_ptr(Rect::xzrect(-3.0, -3.0, -1.0, -1.0, 0.5, Arc::clone(&mat))),
+      Translate::hitable_ptr(Arc::clone(&cube), Vec3::new(0.0, 2.0, 0.0)),
+      RotateY::hitable_ptr(Arc::clone(&cube), 30.0),
+      Transform::hitable_ptr(cube, Mat4::translate(&Vec3::new(-2.0, 0.0, 2.0)) * Mat4::scale(&Vec3::new(1.0, 0.5, 2.0))),
+      Plane::hitable_ptr(Vec3::new(0.0, -2.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Arc::clone(&mat)),
+    ];
+    let scene = accelerate(objects, 0.0, 1.0);
+    for _ in 0..2000 {
+      let origin = Vec3::new(rng.rand_f64(), rng.rand_f64(), rng.rand_f64()) * 8.0 - Vec3::one() * 4.0;
+      let direction = Vec3::new(rng.rand_f64() - 0.5, rng.rand_f64() - 0.5, rng.rand_f64() - 0.5);
+      let ray = Ray::new(origin, direction, rng.rand_f64());
+      let t_max = rng.rand_f64() * 20.0;
+      let hit = scene.hit(&ray, 0.001, t_max);
+      assert_eq!(scene.occluded(&ray, 0.001, t_max), hit.is_some());
+      assert_eq!(scene.hit_distance(&ray, 0.001, t_max), hit.map(|hit| hit.t));
+    }
+  }
+
+  // Counts the hit records it builds.
+  struct Counted {
+    hitable: HitablePtr,
+    records: Arc<AtomicUsize>,
+  }
+
+  impl Hitable for Counted {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+      let hit = self.hitable.hit(ray, t_min, t_max);
+      if hit.is_some() {
+        self.records.fetch_add(1, Ordering::SeqCst);
+      }
+      hit
+    }
+
+    fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+      self.hitable.hit_distance(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> Aabb {
+      self.hitable.bounding_box(time0, time1)
+    }
+  }
+
+  #[test]
+  fn test_deferred_hit_record() {
+    // A ray through a row of spheres, listed far to near so each one beats
+    // the closest hit so far, still only builds the record of the nearest.
+    let mat: MaterialPtr = Lambertian::rc(ConstantTexture::rc(Vec3::new(1.0, 1.0, 1.0)));
+    let records = Arc::new(AtomicUsize::new(0));
+    let spheres: Vec<HitablePtr> = (0..20).rev().map(|i| -> HitablePtr {
+      let sphere = Sphere::hitable_ptr(Vec3::new(i as f64 * 3.0, 0.0, 0.0), 1.0, Arc::clone(&mat));
+      Arc::new(Counted { hitable: sphere, records: Arc::clone(&records) })
+    }).collect();
+    let ray = Ray::new(Vec3::new(-10.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+    let list = HitableList { list: spheres.clone() };
+    let bvh = Bvh::with_leaf_size(spheres, 0.0, 1.0, 4);
+    for scene in [&list as &dyn Hitable, &bvh].iter() {
+      records.store(0, Ordering::SeqCst);
+      assert_eq!(scene.hit(&ray, 0.001, f64::MAX).unwrap().t, 9.0);
+      assert_eq!(records.load(Ordering::SeqCst), 1);
+    }
+  }
+
+  #[test]
+  fn test_list_bounds() {
+    let mat: MaterialPtr = Lambertian::rc(ConstantTexture::rc(Vec3::new(1.0, 1.0, 1.0)));
+    let mut list = HitableList::new();
+    list.add_hitable(Sphere::hitable_ptr(Vec3::new(-1.0, 0.0, 0.0), 1.0, Arc::clone(&mat)));
+    list.add_hitable(Sphere::hitable_ptr_moving(Vec3::new(3.0, 0.0, 0.0), Vec3::new(3.0, 2.0, 0.0), 0.0, 1.0, 0.5, Arc::clone(&mat)));
+    let aabb = list.bounding_box(0.0, 1.0);
+    assert_eq!(aabb.min, Vec3::new(-2.0, -1.0, -1.0));
+    assert_eq!(aabb.max, Vec3::new(3.5, 2.5, 1.0));
+    // Lists nest in the hitables that need their bounds.
+    let rotated = RotateY::hitable_ptr(Arc::new(list), 90.0);
+    assert!(rotated.hit(&Ray::new(Vec3::new(0.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 1.0), 0.0), 0.001, f64::MAX).is_some());
+    let empty = HitableList::new().bounding_box(0.0, 1.0);
+    let other = Aabb::new(Vec3::zero(), Vec3::one());
+    assert_eq!(Aabb::surrounding_box(&empty, &other).min, other.min);
+    assert_eq!(Aabb::surrounding_box(&empty, &other).max, other.max);
+  }
+
+  #[test]
+  fn test_accelerate_plane() {
+    let mat: MaterialPtr = Lambertian::rc(ConstantTexture::rc(Vec3::new(1.0, 1.0, 1.0)));
+    let plane = Plane::hitable_ptr(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 2.0, 0.0), Arc::clone(&mat));
+    assert!(!plane.is_bounded());
+    assert!(!Translate::hitable_ptr(plane.clone(), Vec3::one()).is_bounded());
+    let hit = plane.hit(&Ray::new(Vec3::new(2.5, 3.0, 0.25), Vec3::new(0.0, -2.0, 0.0), 0.0), 0.001, f64::MAX).unwrap();
+    assert_eq!(hit.t, 2.0);
+    assert_eq!(hit.normal, Vec3::new(0.0, 1.0, 0.0));
+    assert!(hit.u >= 0.0 && hit.u < 1.0 && hit.v >= 0.0 && hit.v < 1.0);
+    assert!(plane.hit(&Ray::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), 0.0), 0.001, f64::MAX).is_none());
+
+    let objects: Vec<HitablePtr> = vec![
+      Sphere::hitable_ptr(Vec3::new(0.0, 1.0, 0.0), 1.0, Arc::clone(&mat)),
+      plane,
+      Sphere::hitable_ptr(Vec3::new(5.0, 1.0, 0.0), 1.0, Arc::clone(&mat)),
+    ];
+    let scene = accelerate(objects, 0.0, 1.0);
+    let down = |x: f64| scene.hit(&Ray::new(Vec3::new(x, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0), 0.001, f64::MAX).unwrap().t;
+    assert_eq!(down(0.0), 8.0);
+    assert_eq!(down(5.0), 8.0);
+    assert_eq!(down(100.0), 11.0);
+  }
+}
@@ -1,15 +1,28 @@
 pub mod vec3;
+pub mod mat4;
 pub mod ray;
 pub mod hitable;
 pub mod material;
 pub mod camera;
 pub mod aabb;
+pub mod bvh;
 pub mod scenes;
 pub mod renderer;
 pub mod rt_rand;
 pub mod texture;
 pub mod perlin;
 pub mod constant_medium;
+pub mod scene_file;
+pub mod mesh;
+pub mod obj;
+pub mod output;
+pub mod exr;
+pub mod onb;
+pub mod microfacet;
+pub mod spectrum;
+pub mod sampler;
+pub mod framebuffer;
+pub mod checkpoint;
 
 #[cfg(test)]
 mod tests {
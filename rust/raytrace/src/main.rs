@@ -1,17 +1,272 @@
 extern crate raytrace;
 extern crate rayon;
-extern crate byteorder;
+extern crate ctrlc;
 
-use std::fs::File;
-use std::io::Write;
-use byteorder::{ByteOrder, LittleEndian};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use rayon::prelude::*;
 use raytrace::renderer::Renderer;
+use raytrace::output::{Encoding, ImageFormat, LdrSettings, Tonemap, write_image};
+use raytrace::exr::ExrPixelType;
+use raytrace::scenes;
+use raytrace::scene_file::load_scene;
+use raytrace::rt_rand::Rng;
+use raytrace::sampler::SamplerType;
+use raytrace::framebuffer::Framebuffer;
+use raytrace::checkpoint::Checkpoint;
+use raytrace::vec3::Vec3;
 use std::fmt;
 
-const NX: u32 = 1000;
-const NY: u32 = 1000;
-const NS: u32 = 10000;
+const USAGE: &str = "Usage: raytrace [options]
+
+Options:
+    --width <pixels>       Image width (default 1000)
+    --height <pixels>      Image height (default 1000)
+    --samples <count>      Samples per pixel (default 10000)
+    --pass-samples <count> Samples per pixel added by every progressive pass
+                           (default 16)
+    --time-limit <seconds> Stop after the pass running when the time is up,
+                           Ctrl-C stops early too
+    --write-interval <seconds>
+                           Write the image so far at most this often while
+                           rendering, 0 only writes at the end (default 60)
+    --target-error <relative>
+                           Stop sampling pixels once the standard error of
+                           them and their neighbours drops below this fraction
+                           of their brightness, e.g. 0.01
+    --adaptive-min-samples <count>
+                           Samples every pixel takes before it may stop early
+                           (default 64)
+    --sample-map <path>    Also write the samples taken per pixel, as a
+                           fraction of --samples
+    --checkpoint <path>    Save the accumulated samples here while rendering
+                           and when done
+    --checkpoint-interval <seconds>
+                           How often to save the checkpoint (default 600)
+    --resume               Carry on from the checkpoint if there is one, the
+                           other options must match the interrupted render
+    --merge <path>         Merge this checkpoint, of a render with another
+                           --seed but the same --scene-seed, into the output
+                           instead of rendering. Repeat for every render to
+                           merge, --checkpoint saves the merged result
+    --min-bounces <count>  Bounces before Russian roulette may end a path
+                           (default 3)
+    --max-bounces <count>  Hard limit on the bounces of a path (default 50)
+    --sampler <name>       Sample generator: random, stratified, sobol or
+                           blue-noise (default sobol)
+    --spectral             Trace sampled wavelengths instead of RGB, which
+                           shows dispersion in glass with a Cauchy or
+                           Sellmeier refractive index
+    --seed <number>        Seed for the sampler, the same seed gives the same
+                           image (default 0)
+    --scene-seed <number>  Seed for laying out the random scenes and noise
+                           textures (default 0)
+    --tile-size <pixels>   Edge length of the square work chunks (default 16)
+    --threads <count>      Worker threads, 0 uses every core (default 0)
+    --scene <name>         Scene to render (default final)
+    --list-scenes          Print the available scene names
+    --scene-file <path>    Load the scene from a scene description file
+    --output <path>        Output image path (default output.pfm)
+    --format <format>      Output format (pfm, exr, ppm, png or jpeg), guessed
+                           from the extension if omitted
+    --exr-float            Write 32 bit floats to EXR instead of halfs
+    --tonemap <operator>   Tonemap for 8 bit formats: clamp, reinhard or aces
+                           (default reinhard)
+    --white-point <lum>    Luminance that maps to white with reinhard
+                           (default infinite)
+    --exposure <stops>     Exposure adjustment for 8 bit formats (default 0)
+    --gamma <gamma>        Encode 8 bit formats with a power curve instead
+                           of sRGB
+    --help                 Print this message";
+
+struct Options {
+    width: u32,
+    height: u32,
+    samples: u32,
+    pass_samples: u32,
+    time_limit: Option<f64>,
+    write_interval: f64,
+    target_error: Option<f64>,
+    adaptive_min_samples: u32,
+    sample_map: Option<String>,
+    checkpoint: Option<String>,
+    checkpoint_interval: f64,
+    resume: bool,
+    merge: Vec<String>,
+    min_bounces: u32,
+    max_bounces: u32,
+    seed: u64,
+    scene_seed: u64,
+    sampler: SamplerType,
+    spectral: bool,
+    tile_size: usize,
+    threads: usize,
+    scene: String,
+    scene_file: Option<String>,
+    output: String,
+    format: ImageFormat,
+    ldr: LdrSettings,
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", flag))?;
+    value.parse::<T>().map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options {
+            width: 1000,
+            height: 1000,
+            samples: 10000,
+            pass_samples: 16,
+            time_limit: None,
+            write_interval: 60.0,
+            target_error: None,
+            adaptive_min_samples: 64,
+            sample_map: None,
+            checkpoint: None,
+            checkpoint_interval: 600.0,
+            resume: false,
+            merge: Vec::new(),
+            min_bounces: 3,
+            max_bounces: 50,
+            seed: 0,
+            scene_seed: 0,
+            sampler: SamplerType::Sobol,
+            spectral: false,
+            tile_size: 16,
+            threads: 0,
+            scene: "final".to_string(),
+            scene_file: None,
+            output: "output.pfm".to_string(),
+            format: ImageFormat::Pfm,
+            ldr: LdrSettings::new(),
+        };
+        let mut format = None;
+        let mut white_point = None;
+        let mut exr_float = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--width" => options.width = parse_value(&arg, args.next())?,
+                "--height" => options.height = parse_value(&arg, args.next())?,
+                "--samples" => options.samples = parse_value(&arg, args.next())?,
+                "--pass-samples" => options.pass_samples = parse_value(&arg, args.next())?,
+                "--time-limit" => options.time_limit = Some(parse_value(&arg, args.next())?),
+                "--write-interval" => options.write_interval = parse_value(&arg, args.next())?,
+                "--target-error" => options.target_error = Some(parse_value(&arg, args.next())?),
+                "--adaptive-min-samples" => options.adaptive_min_samples = parse_value(&arg, args.next())?,
+                "--sample-map" => options.sample_map = Some(parse_value(&arg, args.next())?),
+                "--checkpoint" => options.checkpoint = Some(parse_value(&arg, args.next())?),
+                "--checkpoint-interval" => options.checkpoint_interval = parse_value(&arg, args.next())?,
+                "--resume" => options.resume = true,
+                "--merge" => options.merge.push(parse_value(&arg, args.next())?),
+                "--min-bounces" => options.min_bounces = parse_value(&arg, args.next())?,
+                "--max-bounces" => options.max_bounces = parse_value(&arg, args.next())?,
+                "--sampler" => {
+                    let name: String = parse_value(&arg, args.next())?;
+                    options.sampler = SamplerType::from_name(&name).ok_or_else(|| format!("unknown sampler: {}", name))?;
+                },
+                "--spectral" => options.spectral = true,
+                "--seed" => options.seed = parse_value(&arg, args.next())?,
+                "--scene-seed" => options.scene_seed = parse_value(&arg, args.next())?,
+                "--tile-size" => options.tile_size = parse_value(&arg, args.next())?,
+                "--threads" => options.threads = parse_value(&arg, args.next())?,
+                "--scene" => options.scene = parse_value(&arg, args.next())?,
+                "--output" => options.output = parse_value(&arg, args.next())?,
+                "--format" => {
+                    let name: String = parse_value(&arg, args.next())?;
+                    format = Some(ImageFormat::from_name(&name).ok_or_else(|| format!("unknown format: {}", name))?);
+                },
+                "--tonemap" => {
+                    let name: String = parse_value(&arg, args.next())?;
+                    options.ldr.tonemap = Tonemap::from_name(&name).ok_or_else(|| format!("unknown tonemap: {}", name))?;
+                },
+                "--exr-float" => exr_float = true,
+                "--white-point" => white_point = Some(parse_value(&arg, args.next())?),
+                "--exposure" => options.ldr.exposure = parse_value(&arg, args.next())?,
+                "--gamma" => {
+                    let gamma: f64 = parse_value(&arg, args.next())?;
+                    if gamma <= 0.0 {
+                        return Err("--gamma must be positive".to_string());
+                    }
+                    options.ldr.encoding = Encoding::Gamma(gamma);
+                },
+                "--scene-file" => options.scene_file = Some(parse_value(&arg, args.next())?),
+                "--list-scenes" => {
+                    for name in scenes::scene_names() {
+                        println!("{}", name);
+                    }
+                    process::exit(0);
+                },
+                "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                },
+                _ => return Err(format!("unknown option: {}", arg)),
+            }
+        }
+        if options.width == 0 || options.height == 0 {
+            return Err("image dimensions must be non-zero".to_string());
+        }
+        if options.samples == 0 {
+            return Err("--samples must be at least 1".to_string());
+        }
+        if options.pass_samples == 0 {
+            return Err("--pass-samples must be at least 1".to_string());
+        }
+        if options.time_limit.is_some_and(|limit| limit.is_nan() || limit <= 0.0) {
+            return Err("--time-limit must be positive".to_string());
+        }
+        if options.write_interval.is_nan() || options.write_interval < 0.0 {
+            return Err("--write-interval must not be negative".to_string());
+        }
+        if options.target_error.is_some_and(|target| target.is_nan() || target <= 0.0) {
+            return Err("--target-error must be positive".to_string());
+        }
+        if options.adaptive_min_samples < 2 {
+            return Err("--adaptive-min-samples must be at least 2".to_string());
+        }
+        if options.checkpoint_interval.is_nan() || options.checkpoint_interval <= 0.0 {
+            return Err("--checkpoint-interval must be positive".to_string());
+        }
+        if options.resume && options.checkpoint.is_none() {
+            return Err("--resume needs a --checkpoint to resume from".to_string());
+        }
+        if options.resume && !options.merge.is_empty() {
+            return Err("--resume and --merge can't be combined".to_string());
+        }
+        if options.min_bounces > options.max_bounces {
+            return Err("--min-bounces must not exceed --max-bounces".to_string());
+        }
+        if options.tile_size == 0 {
+            return Err("--tile-size must be at least 1".to_string());
+        }
+        if let Some(white) = white_point {
+            match options.ldr.tonemap {
+                Tonemap::Reinhard { .. } => options.ldr.tonemap = Tonemap::Reinhard { white },
+                _ => return Err("--white-point only applies to the reinhard tonemap".to_string()),
+            }
+        }
+        options.format = format
+            .or_else(|| ImageFormat::from_path(Path::new(&options.output)))
+            .unwrap_or(ImageFormat::Pfm);
+        if exr_float {
+            match options.format {
+                ImageFormat::Exr(_) => options.format = ImageFormat::Exr(ExrPixelType::Float),
+                _ => return Err("--exr-float only applies to EXR output".to_string()),
+            }
+        }
+        Ok(options)
+    }
+}
 
 struct WorkChunk {
     x: usize,
@@ -19,6 +274,8 @@ struct WorkChunk {
     w: usize,
     h: usize,
     result: Vec<f64>,
+    sums_sq: Vec<f64>,
+    counts: Vec<u32>,
 }
 
 impl WorkChunk {
@@ -28,7 +285,9 @@ impl WorkChunk {
             y,
             w,
             h,
-            result: vec![0.0; w*h*3]
+            result: vec![0.0; w*h*3],
+            sums_sq: vec![0.0; w*h*3],
+            counts: vec![0; w*h],
         }
     }
 }
@@ -68,7 +327,7 @@ impl Iterator for Chunker {
             let y_size = (self.height - self.chunk_size * self.chunk_y).min(self.chunk_size);
             let ret = WorkChunk::new(self.chunk_x * self.chunk_size, self.chunk_y * self.chunk_size, x_size, y_size);
             self.chunk_x += 1;
-            if self.chunk_x * self.chunk_size > self.width {
+            if self.chunk_x * self.chunk_size >= self.width {
                 self.chunk_y += 1;
                 self.chunk_x = 0;
             }
@@ -80,46 +339,239 @@ impl Iterator for Chunker {
 }
 
 fn main() {
-    let renderer = Renderer::new(NX, NY, NS);
-    let chunker = Chunker::new(NX as usize, NY as usize, 16);
-    let results: Vec<WorkChunk> = chunker.collect::<Vec<WorkChunk>>().into_par_iter().update(|work| {
-        for y in 0..work.h {
-            for x in 0..work.w {
-                let c = renderer.pixel_color((x+work.x) as u32, (y+work.y) as u32);
-                let offset = (y * work.w + x) * 3;
-                work.result[offset] = c.x;
-                work.result[offset+1] = c.y;
-                work.result[offset+2] = c.z;
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("raytrace: {}\n\n{}", err, USAGE);
+            process::exit(1);
+        }
+    };
+    if !options.merge.is_empty() {
+        merge_checkpoints(&options);
+        return;
+    }
+    if options.threads > 0 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build_global()
+            .expect("Unable to configure the thread pool!");
+    }
+    let (nx, ny) = (options.width, options.height);
+    let mut renderer = if let Some(ref path) = options.scene_file {
+        match load_scene(Path::new(path), nx, ny, &mut Rng::new(options.scene_seed)) {
+            Ok((scene, camera, default_sky)) => Renderer::from_scene(nx, ny, options.samples, scene, camera, default_sky),
+            Err(err) => {
+                eprintln!("raytrace: {}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    } else {
+        match Renderer::with_scene(nx, ny, options.samples, &options.scene, options.scene_seed) {
+            Some(renderer) => renderer,
+            None => {
+                eprintln!("raytrace: unknown scene: {} (try one of: {})", options.scene, scenes::scene_names().join(", "));
+                process::exit(1);
             }
         }
-    }).collect();
-    let mut full_image = vec![0.0; (NX*NY*3) as usize];
-    for work in results {
-        let mut w_offset = 0;
-        for y in 0..work.h {
-            let mut offset = ((((NY-1) as usize - (y+work.y)) * NX as usize) + work.x) * 3;
-            for _ in 0..work.w {
-                for _ in 0..3 {
-                    full_image[offset] = work.result[w_offset];
-                    offset += 1;
-                    w_offset += 1;
+    };
+    renderer.set_seed(options.seed);
+    renderer.set_bounces(options.min_bounces, options.max_bounces);
+    renderer.set_sampler(options.sampler);
+    renderer.set_spectral(options.spectral);
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || {
+            if interrupted.swap(true, Ordering::SeqCst) {
+                process::exit(130);
+            }
+            eprintln!("raytrace: finishing up, press Ctrl-C again to quit without writing the image");
+        }).expect("Unable to install the Ctrl-C handler!");
+    }
+    let start = Instant::now();
+    let deadline = options.time_limit.map(|limit| start + Duration::from_millis((limit * 1000.0) as u64));
+    let should_stop = || interrupted.load(Ordering::SeqCst) || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    let write_interval = Duration::from_millis((options.write_interval * 1000.0) as u64);
+    let checkpoint_interval = Duration::from_millis((options.checkpoint_interval * 1000.0) as u64);
+    let mut checkpoint = Checkpoint {
+        scene: options.scene_file.as_ref().unwrap_or(&options.scene).clone(),
+        scene_seed: options.scene_seed,
+        seeds: vec![options.seed],
+        sampler: options.sampler,
+        samples: options.samples,
+        min_bounces: options.min_bounces,
+        max_bounces: options.max_bounces,
+        spectral: options.spectral,
+        framebuffer: Framebuffer::new(nx, ny),
+    };
+    if options.resume {
+        let path = options.checkpoint.as_ref().unwrap();
+        if Path::new(path).exists() {
+            match resume_checkpoint(&checkpoint, Path::new(path)) {
+                Ok(framebuffer) => checkpoint.framebuffer = framebuffer,
+                Err(err) => {
+                    eprintln!("raytrace: unable to resume from {}: {}", path, err);
+                    process::exit(1);
                 }
             }
+            eprintln!("raytrace: resuming with {} samples taken", checkpoint.framebuffer.total_samples());
+        } else {
+            eprintln!("raytrace: {} doesn't exist yet, starting from scratch", path);
         }
     }
-    // Output a ppm
-    // println!("P3\n{} {}\n255", NX, NY);
-    // for pixel in full_image.chunks(3) {
-    //     println!("{} {} {}", pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
-    // }
-    // Output a PFM
-    let mut f = File::create("output.pfm").expect("Unable to create output.pfm!");
-    write!(f, "PF\n{} {}\n-1.0\n", NX, NY);
+    let mut last_write = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    while !should_stop() {
+        let framebuffer = &checkpoint.framebuffer;
+        // Pixels are tracked rows top to bottom, as in the framebuffer.
+        let active: Vec<bool> = (0..ny).flat_map(|y| (0..nx).map(move |x| (x, y))).map(|(x, y)| {
+            let count = framebuffer.count(x, y);
+            count < options.samples && match options.target_error {
+                Some(target) => count < options.adaptive_min_samples || !framebuffer.converged(x, y, target),
+                None => true,
+            }
+        }).collect();
+        let remaining = active.iter().filter(|a| **a).count();
+        if remaining == 0 {
+            break;
+        }
+        let chunker = Chunker::new(nx as usize, ny as usize, options.tile_size);
+        let results: Vec<WorkChunk> = chunker.collect::<Vec<WorkChunk>>().into_par_iter().update(|work| {
+            for y in 0..work.h {
+                for x in 0..work.w {
+                    // Pixels skipped after a stop just fall a pass behind, the
+                    // framebuffer averages every pixel over its own samples.
+                    if should_stop() {
+                        return;
+                    }
+                    let (i, j) = ((x+work.x) as u32, (y+work.y) as u32);
+                    if !active[((ny-1 - j) * nx + i) as usize] {
+                        continue;
+                    }
+                    // Every pixel carries on from its own count, so converged
+                    // pixels dropping out leave no gaps in their sequences.
+                    let first = framebuffer.count(i, ny-1 - j);
+                    let pass = options.pass_samples.min(options.samples - first);
+                    let (c, c_sq) = renderer.sample_sum(i, j, first, pass);
+                    let offset = (y * work.w + x) * 3;
+                    for k in 0..3 {
+                        work.result[offset+k] = c[k];
+                        work.sums_sq[offset+k] = c_sq[k];
+                    }
+                    work.counts[y * work.w + x] = pass;
+                }
+            }
+        }).collect();
+        for work in results {
+            for y in 0..work.h {
+                for x in 0..work.w {
+                    let offset = (y * work.w + x) * 3;
+                    let sum = Vec3::new(work.result[offset], work.result[offset+1], work.result[offset+2]);
+                    let sum_sq = Vec3::new(work.sums_sq[offset], work.sums_sq[offset+1], work.sums_sq[offset+2]);
+                    checkpoint.framebuffer.add((x+work.x) as u32, ny-1 - (y+work.y) as u32, &sum, &sum_sq, work.counts[y * work.w + x]);
+                }
+            }
+        }
+        eprintln!("raytrace: {} of {} samples per pixel, {} pixels sampled this pass, {:.1}s",
+                  checkpoint.framebuffer.max_count(), options.samples, remaining, elapsed_seconds(start));
+        if write_interval > Duration::from_secs(0) && last_write.elapsed() >= write_interval {
+            if let Err(err) = save_image(&options, &checkpoint.framebuffer, options.samples) {
+                eprintln!("raytrace: unable to write {}: {}", options.output, err);
+            }
+            last_write = Instant::now();
+        }
+        if let Some(ref path) = options.checkpoint {
+            if last_checkpoint.elapsed() >= checkpoint_interval {
+                if let Err(err) = write_atomically(path, |path| checkpoint.save(path)) {
+                    eprintln!("raytrace: unable to write {}: {}", path, err);
+                }
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
+    eprintln!("raytrace: {:.1} samples per pixel on average", checkpoint.framebuffer.total_samples() as f64 / (nx * ny) as f64);
+    if let Some(ref path) = options.checkpoint {
+        if let Err(err) = write_atomically(path, |path| checkpoint.save(path)) {
+            eprintln!("raytrace: unable to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = save_image(&options, &checkpoint.framebuffer, options.samples) {
+        eprintln!("raytrace: unable to write {}: {}", options.output, err);
+        process::exit(1);
+    }
+}
+
+// The framebuffer of the checkpoint at `path`, after making sure it was
+// rendered just like `current` would be.
+fn resume_checkpoint(current: &Checkpoint, path: &Path) -> Result<Framebuffer, Box<dyn Error>> {
+    let loaded = Checkpoint::load(path)?;
+    current.check_compatible(&loaded)?;
+    if loaded.seeds != current.seeds {
+        if loaded.seeds.len() > 1 {
+            return Err("merged checkpoints can't be resumed".into());
+        }
+        return Err(format!("seed {} differs from {}", loaded.seeds[0], current.seeds[0]).into());
+    }
+    Ok(loaded.framebuffer)
+}
 
-    for pixel in full_image {
-        let mut bytes: [u8; 4] = [0,0,0,0];
-        let mut pixel_f32: [f32; 1] = [pixel as f32];
-        LittleEndian::write_f32_into(&pixel_f32, &mut bytes);
-        f.write_all(&bytes).expect("Unable to write!");
+fn merge_checkpoints(options: &Options) {
+    let mut merged: Option<Checkpoint> = None;
+    for path in &options.merge {
+        let result = Checkpoint::load(Path::new(path)).and_then(|checkpoint| {
+            match merged {
+                Some(ref mut merged) => merged.merge(&checkpoint),
+                None => {
+                    merged = Some(checkpoint);
+                    Ok(())
+                }
+            }
+        });
+        if let Err(err) = result {
+            eprintln!("raytrace: unable to merge {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    let merged = merged.unwrap();
+    let framebuffer = &merged.framebuffer;
+    eprintln!("raytrace: merged {} renders, {:.1} samples per pixel on average", merged.seeds.len(),
+              framebuffer.total_samples() as f64 / (framebuffer.width() * framebuffer.height()) as f64);
+    if let Some(ref path) = options.checkpoint {
+        if let Err(err) = write_atomically(path, |path| merged.save(path)) {
+            eprintln!("raytrace: unable to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = save_image(options, framebuffer, merged.samples * merged.seeds.len() as u32) {
+        eprintln!("raytrace: unable to write {}: {}", options.output, err);
+        process::exit(1);
     }
-}
\ No newline at end of file
+}
+
+fn elapsed_seconds(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9
+}
+
+// Writes the image and the sample map, if asked for, which shows the counts
+// relative to `samples`.
+fn save_image(options: &Options, framebuffer: &Framebuffer, samples: u32) -> io::Result<()> {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    write_atomically(&options.output, |path| write_image(path, width, height, &framebuffer.image(), options.format, &options.ldr))?;
+    if let Some(ref map) = options.sample_map {
+        let format = ImageFormat::from_path(Path::new(map)).unwrap_or(ImageFormat::Pfm);
+        // Counts map linearly to grey, without any tonemapping.
+        let ldr = LdrSettings { exposure: 0.0, tonemap: Tonemap::Clamp, encoding: Encoding::Gamma(1.0) };
+        write_atomically(map, |path| write_image(path, width, height, &framebuffer.count_image(samples), format, &ldr))?;
+    }
+    Ok(())
+}
+
+// Writes next to the output first and renames, so the output is always a
+// complete image even when the process dies halfway through a write.
+fn write_atomically<F: FnOnce(&Path) -> io::Result<()>>(output: &str, write: F) -> io::Result<()> {
+    let partial = format!("{}.partial", output);
+    write(Path::new(&partial))?;
+    fs::rename(&partial, output)
+}
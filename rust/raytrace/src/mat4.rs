@@ -0,0 +1,194 @@
+use std::ops;
+use vec3::Vec3;
+
+// A 4x4 matrix for affine transforms, stored by rows and applied to column
+// vectors, so `a * b` transforms by `b` first and then by `a`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mat4 {
+  pub m: [[f64; 4]; 4],
+}
+
+impl Mat4 {
+  pub fn new(m: [[f64; 4]; 4]) -> Mat4 {
+    Mat4 {
+      m
+    }
+  }
+
+  pub fn identity() -> Mat4 {
+    Mat4::new([
+      [1.0, 0.0, 0.0, 0.0],
+      [0.0, 1.0, 0.0, 0.0],
+      [0.0, 0.0, 1.0, 0.0],
+      [0.0, 0.0, 0.0, 1.0],
+    ])
+  }
+
+  pub fn translate(offset: &Vec3) -> Mat4 {
+    Mat4::new([
+      [1.0, 0.0, 0.0, offset.x],
+      [0.0, 1.0, 0.0, offset.y],
+      [0.0, 0.0, 1.0, offset.z],
+      [0.0, 0.0, 0.0, 1.0],
+    ])
+  }
+
+  pub fn scale(factors: &Vec3) -> Mat4 {
+    Mat4::new([
+      [factors.x, 0.0, 0.0, 0.0],
+      [0.0, factors.y, 0.0, 0.0],
+      [0.0, 0.0, factors.z, 0.0],
+      [0.0, 0.0, 0.0, 1.0],
+    ])
+  }
+
+  pub fn rotate_x(angle_degrees: f64) -> Mat4 {
+    Mat4::rotate(&Vec3::new(1.0, 0.0, 0.0), angle_degrees)
+  }
+
+  pub fn rotate_y(angle_degrees: f64) -> Mat4 {
+    Mat4::rotate(&Vec3::new(0.0, 1.0, 0.0), angle_degrees)
+  }
+
+  pub fn rotate_z(angle_degrees: f64) -> Mat4 {
+    Mat4::rotate(&Vec3::new(0.0, 0.0, 1.0), angle_degrees)
+  }
+
+  // Counter clockwise about `axis` when looking down it towards the origin.
+  pub fn rotate(axis: &Vec3, angle_degrees: f64) -> Mat4 {
+    let a = axis.normalized();
+    let radians = angle_degrees * (std::f64::consts::PI / 180.0);
+    let (s, c) = radians.sin_cos();
+    let t = 1.0 - c;
+    Mat4::new([
+      [t*a.x*a.x + c, t*a.x*a.y - s*a.z, t*a.x*a.z + s*a.y, 0.0],
+      [t*a.x*a.y + s*a.z, t*a.y*a.y + c, t*a.y*a.z - s*a.x, 0.0],
+      [t*a.x*a.z - s*a.y, t*a.y*a.z + s*a.x, t*a.z*a.z + c, 0.0],
+      [0.0, 0.0, 0.0, 1.0],
+    ])
+  }
+
+  pub fn transpose(&self) -> Mat4 {
+    let mut ret = Mat4::identity();
+    for i in 0..4 {
+      for j in 0..4 {
+        ret.m[i][j] = self.m[j][i];
+      }
+    }
+    ret
+  }
+
+  // Gauss-Jordan elimination with partial pivoting, None if singular.
+  pub fn inverse(&self) -> Option<Mat4> {
+    let mut a = self.m;
+    let mut inv = Mat4::identity().m;
+    for col in 0..4 {
+      let pivot = (col..4).max_by(|&x, &y| a[x][col].abs().partial_cmp(&a[y][col].abs()).unwrap()).unwrap();
+      if a[pivot][col].abs() < 1e-12 {
+        return None;
+      }
+      a.swap(col, pivot);
+      inv.swap(col, pivot);
+      let scale = 1.0 / a[col][col];
+      for j in 0..4 {
+        a[col][j] *= scale;
+        inv[col][j] *= scale;
+      }
+      for row in 0..4 {
+        if row != col {
+          let factor = a[row][col];
+          for j in 0..4 {
+            a[row][j] -= factor * a[col][j];
+            inv[row][j] -= factor * inv[col][j];
+          }
+        }
+      }
+    }
+    Some(Mat4::new(inv))
+  }
+
+  // Determinant of the upper 3x3, how the transform scales volumes.
+  pub fn determinant3(&self) -> f64 {
+    let m = &self.m;
+    m[0][0] * (m[1][1]*m[2][2] - m[1][2]*m[2][1])
+      - m[0][1] * (m[1][0]*m[2][2] - m[1][2]*m[2][0])
+      + m[0][2] * (m[1][0]*m[2][1] - m[1][1]*m[2][0])
+  }
+
+  pub fn transform_point(&self, p: &Vec3) -> Vec3 {
+    let m = &self.m;
+    Vec3::new(
+      m[0][0]*p.x + m[0][1]*p.y + m[0][2]*p.z + m[0][3],
+      m[1][0]*p.x + m[1][1]*p.y + m[1][2]*p.z + m[1][3],
+      m[2][0]*p.x + m[2][1]*p.y + m[2][2]*p.z + m[2][3],
+    )
+  }
+
+  // Directions ignore the translation.
+  pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+    let m = &self.m;
+    Vec3::new(
+      m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+      m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+      m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z,
+    )
+  }
+}
+
+impl ops::Mul<Mat4> for Mat4 {
+  type Output = Mat4;
+
+  fn mul(self, rhs: Mat4) -> Mat4 {
+    let mut ret = Mat4::new([[0.0; 4]; 4]);
+    for i in 0..4 {
+      for j in 0..4 {
+        for k in 0..4 {
+          ret.m[i][j] += self.m[i][k] * rhs.m[k][j];
+        }
+      }
+    }
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use mat4::*;
+
+  fn assert_close(a: Vec3, b: Vec3) {
+    assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+  }
+
+  #[test]
+  fn test_transforms() {
+    let p = Vec3::new(1.0, 2.0, 3.0);
+    assert_close(Mat4::translate(&Vec3::new(1.0, 0.0, -1.0)).transform_point(&p), Vec3::new(2.0, 2.0, 2.0));
+    assert_close(Mat4::translate(&Vec3::new(1.0, 0.0, -1.0)).transform_vector(&p), p);
+    assert_close(Mat4::scale(&Vec3::new(2.0, 3.0, 4.0)).transform_point(&p), Vec3::new(2.0, 6.0, 12.0));
+    assert_close(Mat4::rotate_z(90.0).transform_vector(&Vec3::new(1.0, 0.0, 0.0)), Vec3::new(0.0, 1.0, 0.0));
+    assert_close(Mat4::rotate_x(90.0).transform_vector(&Vec3::new(0.0, 1.0, 0.0)), Vec3::new(0.0, 0.0, 1.0));
+    assert_close(Mat4::rotate_y(90.0).transform_vector(&Vec3::new(0.0, 0.0, 1.0)), Vec3::new(1.0, 0.0, 0.0));
+    // Scaling first, then moving.
+    let m = Mat4::translate(&Vec3::new(0.0, 1.0, 0.0)) * Mat4::scale(&Vec3::new(2.0, 2.0, 2.0));
+    assert_close(m.transform_point(&p), Vec3::new(2.0, 5.0, 6.0));
+    assert_eq!(m.determinant3(), 8.0);
+  }
+
+  #[test]
+  fn test_inverse() {
+    let m = Mat4::translate(&Vec3::new(3.0, -2.0, 1.0)) * Mat4::rotate(&Vec3::new(1.0, 1.0, 0.0), 33.0) * Mat4::scale(&Vec3::new(0.5, 2.0, 3.0));
+    let inverse = m.inverse().unwrap();
+    let p = Vec3::new(0.3, -4.0, 7.0);
+    assert_close(inverse.transform_point(&m.transform_point(&p)), p);
+    let identity = m * inverse;
+    for i in 0..4 {
+      for j in 0..4 {
+        assert!((identity.m[i][j] - Mat4::identity().m[i][j]).abs() < 1e-12);
+      }
+    }
+    assert_eq!(m.transpose().transpose(), m);
+    assert!(Mat4::scale(&Vec3::new(1.0, 0.0, 1.0)).inverse().is_none());
+  }
+}
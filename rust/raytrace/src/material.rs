@@ -1,22 +1,46 @@
 use std::sync::Arc;
+use std::f64::consts::PI;
 
 use vec3::Vec3;
 use ray::Ray;
 use hitable::HitRecord;
-use rt_rand::*;
-use texture::TexturePtr;
+use sampler::*;
+use texture::{ConstantTexture, TexturePtr};
+use onb::Onb;
+use microfacet::*;
+use spectrum::LAMBDA_D;
 
 pub struct ScatterInfo {
   pub attenuation: Vec3,
   pub scattered: Ray,
+  // Specular bounces follow a single direction, light sampling can't help them.
+  pub specular: bool,
+  // Solid angle density of `scattered`, unused for specular bounces.
+  pub pdf: f64,
 }
 
 pub trait Material {
   // result: attenuation, scatter
-  fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<ScatterInfo>;
+  fn scatter(&self, ray: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterInfo>;
   fn emit(&self, _u: f64, _v: f64, _pt: &Vec3) -> Vec3 {
     Vec3::zero()
   }
+
+  // Hitables with an emissive material are sampled as lights.
+  fn is_emissive(&self) -> bool {
+    false
+  }
+
+  // The BRDF times the cosine term for light arriving from `direction`,
+  // used to weight light samples.
+  fn eval(&self, _ray: &Ray, _hit: &HitRecord, _direction: &Vec3) -> Vec3 {
+    Vec3::zero()
+  }
+
+  // Density with which `scatter` would pick `direction`.
+  fn pdf(&self, _ray: &Ray, _hit: &HitRecord, _direction: &Vec3) -> f64 {
+    0.0
+  }
 }
 
 pub type MaterialPtr = Arc<Material + Sync + Send>;
@@ -38,14 +62,36 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-  fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<ScatterInfo> {
-    let target = hit.p + hit.normal + random_in_unit_sphere();
-    let scattered = Ray::new(hit.p, target - hit.p, ray.time);
+  fn scatter(&self, ray: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterInfo> {
+    // Cosine sampling cancels the BRDF and cosine term, leaving the albedo.
+    let uvw = Onb::from_w(&hit.normal);
+    let direction = uvw.local(&sample_cosine_direction(sampler.get_2d()));
+    let scattered = Ray::new(hit.p, direction, ray.time);
     Some(ScatterInfo {
-      attenuation: self.texture.value(hit.u, hit.v, &hit.p),
-      scattered
+      attenuation: ray.spectrum(&self.texture.value(hit.u, hit.v, &hit.p)),
+      scattered,
+      specular: false,
+      pdf: self.pdf(ray, hit, &direction)
     })
   }
+
+  fn eval(&self, ray: &Ray, hit: &HitRecord, direction: &Vec3) -> Vec3 {
+    let cosine = Vec3::dot(&hit.normal.normalized(), &direction.normalized());
+    if cosine > 0.0 {
+      ray.spectrum(&self.texture.value(hit.u, hit.v, &hit.p)) * (cosine / std::f64::consts::PI)
+    } else {
+      Vec3::zero()
+    }
+  }
+
+  fn pdf(&self, _ray: &Ray, hit: &HitRecord, direction: &Vec3) -> f64 {
+    let cosine = Vec3::dot(&hit.normal.normalized(), &direction.normalized());
+    if cosine > 0.0 {
+      cosine / std::f64::consts::PI
+    } else {
+      0.0
+    }
+  }
 }
 
 pub struct Metal {
@@ -67,13 +113,15 @@ impl Metal {
 }
 
 impl Material for Metal {
-  fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<ScatterInfo> {
+  fn scatter(&self, ray: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterInfo> {
     let reflected = Vec3::reflect(&ray.direction.normalized(), &hit.normal);
-    let scattered = Ray::new(hit.p, reflected + self.fuzz * random_in_unit_sphere(), ray.time);
+    let scattered = Ray::new(hit.p, reflected + self.fuzz * sample_unit_ball(sampler.get_2d(), sampler.get_1d()), ray.time);
     if Vec3::dot(&scattered.direction, &hit.normal) > 0.0 {
       Some(ScatterInfo {
-        attenuation: self.texture.value(hit.u, hit.v, &hit.p),
-        scattered
+        attenuation: ray.spectrum(&self.texture.value(hit.u, hit.v, &hit.p)),
+        scattered,
+        specular: true,
+        pdf: 0.0
       })
     } else {
       None
@@ -81,70 +129,527 @@ impl Material for Metal {
   }
 }
 
-pub struct Dielectric {
-  ref_index: f64,
+// Metal/roughness material as PBR assets author it: GGX reflection over a
+// Lambertian base, with Fresnel deciding how much light gets past the
+// reflection into the base. Metalness fades out the base and tints the
+// reflection with the base color instead. Transmission swaps the base for
+// rough refraction into a dielectric of index `ior`, tinted by the base
+// color, for frosted glass. Roughness, metalness and transmission read the
+// first channel of their textures.
+pub struct Microfacet {
+  base_color: TexturePtr,
+  roughness: TexturePtr,
+  metalness: TexturePtr,
+  transmission: TexturePtr,
+  ior: f64,
 }
 
-impl Dielectric {
-  pub fn new(ref_index: f64) -> Dielectric {
-    return Dielectric {
-      ref_index
+// The material at a hit, with `wo` in a shading frame facing the viewer.
+struct MicrofacetSample {
+  frame: Onb,
+  wo: Vec3,
+  base_color: Vec3,
+  alpha: f64,
+  metalness: f64,
+  transmission: f64,
+  // Refractive index behind the surface over the one in front of it.
+  eta: f64,
+  // Chance to sample the reflection or refraction rather than the base.
+  specular_prob: f64,
+}
+
+impl MicrofacetSample {
+  // Reflectance of the specular lobe, metal and dielectric mixed.
+  fn fresnel(&self, cosine: f64) -> Vec3 {
+    fresnel_schlick(cosine, &self.base_color) * self.metalness
+      + Vec3::one() * ((1.0 - self.metalness) * fresnel_dielectric(cosine, self.eta))
+  }
+
+  // Of the light the specular lobe takes, the part it reflects rather than
+  // transmits, which is how often it samples a reflection.
+  fn reflect_prob(&self, cosine: f64) -> f64 {
+    let reflected = average(&self.fresnel(cosine));
+    let transmitted = (1.0 - self.metalness) * self.transmission * (1.0 - fresnel_dielectric(cosine, self.eta));
+    if reflected + transmitted > 0.0 {
+      reflected / (reflected + transmitted)
+    } else {
+      1.0
     }
   }
+}
 
-  pub fn rc(ref_index: f64) -> Arc<Dielectric> {
-    Arc::new(Dielectric::new(ref_index))
+impl Microfacet {
+  pub fn new(base_color: TexturePtr, roughness: TexturePtr, metalness: TexturePtr) -> Microfacet {
+    Microfacet::with_transmission(base_color, roughness, metalness, ConstantTexture::rc(Vec3::zero()), 1.5)
+  }
+
+  pub fn with_transmission(base_color: TexturePtr, roughness: TexturePtr, metalness: TexturePtr, transmission: TexturePtr, ior: f64) -> Microfacet {
+    Microfacet {
+      base_color,
+      roughness,
+      metalness,
+      transmission,
+      ior
+    }
+  }
+
+  pub fn rc(base_color: TexturePtr, roughness: TexturePtr, metalness: TexturePtr) -> Arc<Microfacet> {
+    Arc::new(Microfacet::new(base_color, roughness, metalness))
+  }
+
+  fn at(&self, ray: &Ray, hit: &HitRecord) -> MicrofacetSample {
+    let scalar = |texture: &TexturePtr| texture.value(hit.u, hit.v, &hit.p).x.clamp(0.0, 1.0);
+    let view = ray.direction.normalized() * -1.0;
+    let entering = Vec3::dot(&view, &hit.normal) >= 0.0;
+    let frame = Onb::from_w(&if entering { hit.normal } else { hit.normal * -1.0 });
+    let wo = frame.to_local(&view);
+    let base_color = ray.spectrum(&self.base_color.value(hit.u, hit.v, &hit.p));
+    let metalness = scalar(&self.metalness);
+    let transmission = scalar(&self.transmission);
+    // Only see-through surfaces have an inside, opaque ones look the same
+    // from either side.
+    let eta = if entering || transmission == 0.0 { self.ior } else { 1.0 / self.ior };
+    let mut m = MicrofacetSample {
+      frame,
+      wo,
+      base_color,
+      alpha: roughness_to_alpha(scalar(&self.roughness)),
+      metalness,
+      transmission,
+      eta,
+      specular_prob: 1.0,
+    };
+    let dielectric = fresnel_dielectric(wo.z, eta);
+    let specular = average(&m.fresnel(wo.z)) + (1.0 - metalness) * transmission * (1.0 - dielectric);
+    let diffuse = (1.0 - metalness) * (1.0 - transmission) * (1.0 - dielectric) * average(&base_color);
+    if specular + diffuse > 0.0 {
+      m.specular_prob = specular / (specular + diffuse);
+    }
+    m
+  }
+
+  // The BSDF times the cosine term and the density of picking `wi`.
+  fn evaluate(&self, m: &MicrofacetSample, wi: &Vec3) -> (Vec3, f64) {
+    let wo = &m.wo;
+    if wo.z <= 0.0 || wi.z == 0.0 {
+      return (Vec3::zero(), 0.0);
+    }
+    if wi.z > 0.0 {
+      let h = (*wo + *wi).normalized();
+      let cosine = Vec3::dot(wo, &h);
+      let specular = m.fresnel(cosine) * (ggx_d(&h, m.alpha) * smith_g2(wo, wi, m.alpha) / (4.0 * wo.z));
+      let diffuse = m.base_color * ((1.0 - m.metalness) * (1.0 - m.transmission) * (1.0 - fresnel_dielectric(cosine, m.eta)) * wi.z / PI);
+      let pdf = m.specular_prob * m.reflect_prob(cosine) * visible_normal_pdf(wo, &h, m.alpha) / (4.0 * cosine)
+        + (1.0 - m.specular_prob) * wi.z / PI;
+      (specular + diffuse, pdf)
+    } else {
+      // Transmission (Walter et al. 2007) through the microfacet halfway
+      // between `wo` and the refracted `wi`.
+      let weight = (1.0 - m.metalness) * m.transmission;
+      let mut h = (*wo + *wi * m.eta).normalized();
+      if h.z < 0.0 {
+        h = h * -1.0;
+      }
+      let (cos_o, cos_i) = (Vec3::dot(wo, &h), Vec3::dot(wi, &h));
+      if weight == 0.0 || cos_o <= 0.0 || cos_i >= 0.0 {
+        return (Vec3::zero(), 0.0);
+      }
+      let denom = cos_o + m.eta * cos_i;
+      // Light crosses the surface twice to get through, tint it halfway each
+      // time.
+      let tint = Vec3::new(m.base_color.x.sqrt(), m.base_color.y.sqrt(), m.base_color.z.sqrt());
+      let f = tint * (weight * (1.0 - fresnel_dielectric(cos_o, m.eta)) * ggx_d(&h, m.alpha) * smith_g2(wo, wi, m.alpha)
+                      * (cos_o * cos_i).abs() / (wo.z * denom * denom));
+      let pdf = m.specular_prob * (1.0 - m.reflect_prob(cos_o)) * visible_normal_pdf(wo, &h, m.alpha)
+        * m.eta * m.eta * cos_i.abs() / (denom * denom);
+      (f, pdf)
+    }
   }
 }
 
-fn schlick(cosine: f64, ref_index: f64) -> f64 {
-  let r0 = (1.0 - ref_index) / (1.0 + ref_index);
-  let r2 = r0*r0;
-  r2 + (1.0 - r2) * (1.0 - cosine).powf(5.0)
+fn average(v: &Vec3) -> f64 {
+  (v.x + v.y + v.z) / 3.0
 }
 
-impl Material for Dielectric {
-  fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<ScatterInfo> {
-    let reflected = Vec3::reflect(&ray.direction, &hit.normal);
-    let ni_over_nt: f64;
-    let outward_normal: Vec3;
-    let cosine: f64;
-    if Vec3::dot(&ray.direction.normalized(), &hit.normal) > 0.0 {
-      outward_normal = hit.normal * -1.0;  // todo: unary
-      ni_over_nt = self.ref_index;
-      let c = Vec3::dot(&ray.direction, &hit.normal) / ray.direction.length();
-      let g = 1.0 - self.ref_index*self.ref_index*(1.0 - c*c);
-      cosine = if g > 0.0 {
-        g.sqrt()
+impl Material for Microfacet {
+  fn scatter(&self, ray: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterInfo> {
+    let m = self.at(ray, hit);
+    let lobe = sampler.get_1d();
+    let u = sampler.get_2d();
+    let reflect_or_refract = sampler.get_1d();
+    let (wi, transmitted) = if lobe < m.specular_prob {
+      let h = sample_visible_normal(&m.wo, m.alpha, u);
+      if reflect_or_refract < m.reflect_prob(Vec3::dot(&m.wo, &h)) {
+        (reflect(&m.wo, &h), false)
       } else {
-        0.0
-      };
+        (refract(&m.wo, &h, m.eta)?, true)
+      }
     } else {
-      outward_normal = hit.normal;
-      ni_over_nt = 1.0 / self.ref_index;
-      cosine = Vec3::dot(&(ray.direction.normalized() * -1.0), &hit.normal.normalized())
-    }
-    let refracted0 = Vec3::refract(&ray.direction, &outward_normal, ni_over_nt);
-    let mut refracted = Vec3::zero();
-    let reflect_prob;
-    if let Some(r) = refracted0 {
-      reflect_prob = schlick(cosine, self.ref_index);
-      refracted = r;
+      (sample_cosine_direction(u), false)
+    };
+    // Reflections off a microfacet can still head below the surface, and
+    // refractions come out on the near side, that's the light bouncing
+    // between microfacets which this model leaves out.
+    if (wi.z < 0.0) != transmitted {
+      return None;
+    }
+    let (f, pdf) = self.evaluate(&m, &wi);
+    if pdf <= 0.0 {
+      return None;
+    }
+    Some(ScatterInfo {
+      attenuation: f / pdf,
+      scattered: Ray::new(hit.p, m.frame.local(&wi), ray.time),
+      specular: false,
+      pdf
+    })
+  }
+
+  fn eval(&self, ray: &Ray, hit: &HitRecord, direction: &Vec3) -> Vec3 {
+    let m = self.at(ray, hit);
+    let wi = m.frame.to_local(&direction.normalized());
+    self.evaluate(&m, &wi).0
+  }
+
+  fn pdf(&self, ray: &Ray, hit: &HitRecord, direction: &Vec3) -> f64 {
+    let m = self.at(ray, hit);
+    let wi = m.frame.to_local(&direction.normalized());
+    self.evaluate(&m, &wi).1
+  }
+}
+
+// Inputs of the principled material. Colors read all three channels of their
+// texture, everything else the first channel, in [0, 1].
+#[derive(Clone)]
+pub struct PrincipledParams {
+  pub base_color: TexturePtr,
+  pub metallic: TexturePtr,
+  pub roughness: TexturePtr,
+  // Reflectance of the non-metal at normal incidence, 0.5 is the 4% of common
+  // dielectrics. It also sets the refractive index for transmission.
+  pub specular: TexturePtr,
+  pub clearcoat: TexturePtr,
+  pub clearcoat_roughness: TexturePtr,
+  // Grazing retroreflection over the diffuse base, for cloth.
+  pub sheen: TexturePtr,
+  // Fraction of the non-metal that is glass instead of diffuse.
+  pub transmission: TexturePtr,
+}
+
+impl PrincipledParams {
+  pub fn new(base_color: TexturePtr) -> PrincipledParams {
+    let constant = |value: f64| -> TexturePtr { ConstantTexture::rc(Vec3::one() * value) };
+    PrincipledParams {
+      base_color,
+      metallic: constant(0.0),
+      roughness: constant(0.5),
+      specular: constant(0.5),
+      clearcoat: constant(0.0),
+      clearcoat_roughness: constant(0.1),
+      sheen: constant(0.0),
+      transmission: constant(0.0),
+    }
+  }
+}
+
+// An uber-material in the spirit of Disney's principled BSDF, blending a
+// diffuse base with sheen, GGX reflection from metals and dielectrics, rough
+// transmission and a clearcoat on top. The layers are weighted by their
+// Fresnel terms rather than simulated, so they don't create energy but do
+// lose what a single microfacet bounce leaves out.
+pub struct Principled {
+  params: PrincipledParams,
+}
+
+// Reflectance of the clearcoat at normal incidence.
+const CLEARCOAT_F0: f64 = 0.04;
+
+// The parameters at a hit, in a shading frame facing the viewer.
+struct PrincipledSample {
+  frame: Onb,
+  wo: Vec3,
+  base_color: Vec3,
+  metallic: f64,
+  alpha: f64,
+  clearcoat: f64,
+  clearcoat_alpha: f64,
+  sheen: f64,
+  transmission: f64,
+  // Refractive index behind the surface over the one in front of it.
+  eta: f64,
+  // Chances of sampling the clearcoat and the specular lobes, the diffuse
+  // lobe takes the rest.
+  coat_prob: f64,
+  specular_prob: f64,
+}
+
+impl PrincipledSample {
+  // Reflectance of the specular lobe, metal and dielectric mixed.
+  fn specular_fresnel(&self, cosine: f64) -> Vec3 {
+    fresnel_schlick(cosine, &self.base_color) * self.metallic
+      + Vec3::one() * ((1.0 - self.metallic) * fresnel_dielectric(cosine, self.eta))
+  }
+
+  fn coat_fresnel(&self, cosine: f64) -> f64 {
+    self.clearcoat * fresnel_schlick(cosine, &(Vec3::one() * CLEARCOAT_F0)).x
+  }
+
+  // Of the light the specular lobes take, the part they reflect rather than
+  // transmit, which is how often they sample a reflection.
+  fn reflect_prob(&self, cosine: f64) -> f64 {
+    let reflected = average(&self.specular_fresnel(cosine));
+    let transmitted = (1.0 - self.metallic) * self.transmission * (1.0 - fresnel_dielectric(cosine, self.eta));
+    if reflected + transmitted > 0.0 {
+      reflected / (reflected + transmitted)
     } else {
-      reflect_prob = 1.0;
+      1.0
     }
-    if rand_f64() < reflect_prob {
-      Some(ScatterInfo {
-        attenuation: Vec3::one(),
-        scattered: Ray::new(hit.p, reflected, ray.time),
-      })
+  }
+}
+
+impl Principled {
+  pub fn new(params: PrincipledParams) -> Principled {
+    Principled {
+      params
+    }
+  }
+
+  pub fn rc(params: PrincipledParams) -> Arc<Principled> {
+    Arc::new(Principled::new(params))
+  }
+
+  fn at(&self, ray: &Ray, hit: &HitRecord) -> PrincipledSample {
+    let scalar = |texture: &TexturePtr| texture.value(hit.u, hit.v, &hit.p).x.clamp(0.0, 1.0);
+    let view = ray.direction.normalized() * -1.0;
+    let entering = Vec3::dot(&view, &hit.normal) >= 0.0;
+    let frame = Onb::from_w(&if entering { hit.normal } else { hit.normal * -1.0 });
+    let wo = frame.to_local(&view);
+    let base_color = ray.spectrum(&self.params.base_color.value(hit.u, hit.v, &hit.p));
+    // Inverting the reflectance at normal incidence, specular 0 would be no
+    // boundary at all so keep a sliver of one.
+    let f0 = (0.08 * scalar(&self.params.specular)).max(1e-4);
+    let ior = (1.0 + f0.sqrt()) / (1.0 - f0.sqrt());
+    let mut m = PrincipledSample {
+      frame,
+      wo,
+      base_color,
+      metallic: scalar(&self.params.metallic),
+      alpha: roughness_to_alpha(scalar(&self.params.roughness)),
+      clearcoat: scalar(&self.params.clearcoat),
+      clearcoat_alpha: roughness_to_alpha(scalar(&self.params.clearcoat_roughness)),
+      sheen: scalar(&self.params.sheen),
+      transmission: scalar(&self.params.transmission),
+      eta: if entering { ior } else { 1.0 / ior },
+      coat_prob: 0.0,
+      specular_prob: 1.0,
+    };
+    // Sample the lobes by how much light they take head-on from `wo`.
+    let coat = m.coat_fresnel(wo.z);
+    let dielectric = fresnel_dielectric(wo.z, m.eta);
+    let specular = (1.0 - coat) * (average(&m.specular_fresnel(wo.z)) + (1.0 - m.metallic) * m.transmission * (1.0 - dielectric));
+    let diffuse = (1.0 - coat) * (1.0 - m.metallic) * (1.0 - m.transmission) * (1.0 - dielectric) * (average(&base_color) + m.sheen);
+    let total = coat + specular + diffuse;
+    if total > 0.0 {
+      m.coat_prob = coat / total;
+      m.specular_prob = specular / total;
+    }
+    m
+  }
+
+  // The BSDF times the cosine term and the density of picking `wi`.
+  fn evaluate(&self, m: &PrincipledSample, wi: &Vec3) -> (Vec3, f64) {
+    let wo = &m.wo;
+    if wo.z <= 0.0 || wi.z == 0.0 {
+      return (Vec3::zero(), 0.0);
+    }
+    if wi.z > 0.0 {
+      let h = (*wo + *wi).normalized();
+      let cosine = Vec3::dot(wo, &h);
+      let coat_fresnel = m.coat_fresnel(cosine);
+      let coat = coat_fresnel * ggx_d(&h, m.clearcoat_alpha) * smith_g2(wo, wi, m.clearcoat_alpha) / (4.0 * wo.z);
+      let specular = m.specular_fresnel(cosine) * (ggx_d(&h, m.alpha) * smith_g2(wo, wi, m.alpha) / (4.0 * wo.z));
+      let sheen = m.sheen * (1.0 - cosine).powi(5);
+      let diffuse = (m.base_color / PI + Vec3::one() * sheen)
+        * ((1.0 - m.metallic) * (1.0 - m.transmission) * (1.0 - fresnel_dielectric(cosine, m.eta)) * wi.z);
+      let f = Vec3::one() * coat + (specular + diffuse) * (1.0 - coat_fresnel);
+      let pdf = m.coat_prob * visible_normal_pdf(wo, &h, m.clearcoat_alpha) / (4.0 * cosine)
+        + m.specular_prob * m.reflect_prob(cosine) * visible_normal_pdf(wo, &h, m.alpha) / (4.0 * cosine)
+        + (1.0 - m.coat_prob - m.specular_prob) * wi.z / PI;
+      (f, pdf)
     } else {
-      Some(ScatterInfo {
-        attenuation: Vec3::one(),
-        scattered: Ray::new(hit.p, refracted, ray.time)
-      })
+      // Transmission (Walter et al. 2007) through the microfacet halfway
+      // between `wo` and the refracted `wi`.
+      let weight = (1.0 - m.metallic) * m.transmission;
+      let mut h = (*wo + *wi * m.eta).normalized();
+      if h.z < 0.0 {
+        h = h * -1.0;
+      }
+      let (cos_o, cos_i) = (Vec3::dot(wo, &h), Vec3::dot(wi, &h));
+      if weight == 0.0 || cos_o <= 0.0 || cos_i >= 0.0 {
+        return (Vec3::zero(), 0.0);
+      }
+      let denom = cos_o + m.eta * cos_i;
+      let transmitted = (1.0 - fresnel_dielectric(cos_o, m.eta)) * (1.0 - m.coat_fresnel(cos_o));
+      // Light crosses the surface twice to get through, tint it halfway each
+      // time.
+      let tint = Vec3::new(m.base_color.x.sqrt(), m.base_color.y.sqrt(), m.base_color.z.sqrt());
+      let f = tint * (weight * transmitted * ggx_d(&h, m.alpha) * smith_g2(wo, wi, m.alpha) * (cos_o * cos_i).abs() / (wo.z * denom * denom));
+      let pdf = m.specular_prob * (1.0 - m.reflect_prob(cos_o)) * visible_normal_pdf(wo, &h, m.alpha)
+        * m.eta * m.eta * cos_i.abs() / (denom * denom);
+      (f, pdf)
+    }
+  }
+}
+
+impl Material for Principled {
+  fn scatter(&self, ray: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterInfo> {
+    let m = self.at(ray, hit);
+    let lobe = sampler.get_1d();
+    let u = sampler.get_2d();
+    let reflect_or_refract = sampler.get_1d();
+    let (wi, transmitted) = if lobe < m.coat_prob {
+      (reflect(&m.wo, &sample_visible_normal(&m.wo, m.clearcoat_alpha, u)), false)
+    } else if lobe < m.coat_prob + m.specular_prob {
+      let h = sample_visible_normal(&m.wo, m.alpha, u);
+      if reflect_or_refract < m.reflect_prob(Vec3::dot(&m.wo, &h)) {
+        (reflect(&m.wo, &h), false)
+      } else {
+        (refract(&m.wo, &h, m.eta)?, true)
+      }
+    } else {
+      (sample_cosine_direction(u), false)
+    };
+    // Like reflections below the surface, refractions that come out on the
+    // near side are lost.
+    if (wi.z < 0.0) != transmitted {
+      return None;
+    }
+    let (f, pdf) = self.evaluate(&m, &wi);
+    if pdf <= 0.0 {
+      return None;
+    }
+    Some(ScatterInfo {
+      attenuation: f / pdf,
+      scattered: Ray::new(hit.p, m.frame.local(&wi), ray.time),
+      specular: false,
+      pdf
+    })
+  }
+
+  fn eval(&self, ray: &Ray, hit: &HitRecord, direction: &Vec3) -> Vec3 {
+    let m = self.at(ray, hit);
+    let wi = m.frame.to_local(&direction.normalized());
+    self.evaluate(&m, &wi).0
+  }
+
+  fn pdf(&self, ray: &Ray, hit: &HitRecord, direction: &Vec3) -> f64 {
+    let m = self.at(ray, hit);
+    let wi = m.frame.to_local(&direction.normalized());
+    self.evaluate(&m, &wi).1
+  }
+}
+
+// How the refractive index of a dielectric varies with the wavelength, in
+// nanometers. Coefficients are for wavelengths in micrometers, as glass
+// catalogs list them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefractiveIndex {
+  Constant(f64),
+  // n = a + b / λ²
+  Cauchy { a: f64, b: f64 },
+  // n² = 1 + Σ b[i] λ² / (λ² - c[i])
+  Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl RefractiveIndex {
+  pub fn at(&self, lambda: f64) -> f64 {
+    let l2 = (lambda * 1e-3) * (lambda * 1e-3);
+    match *self {
+      RefractiveIndex::Constant(n) => n,
+      RefractiveIndex::Cauchy { a, b } => a + b / l2,
+      RefractiveIndex::Sellmeier { b, c } => (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt(),
     }
   }
+
+  pub fn is_dispersive(&self) -> bool {
+    !matches!(*self, RefractiveIndex::Constant(_))
+  }
+}
+
+// Glass, optionally colored by absorbing light along the way inside it
+// (Beer-Lambert). A tinted dielectric with absorption `a` turns white light
+// `tint` colored over 1 / `a` units and deeper colored further in. Absorption
+// is accounted when a ray reaches the surface from inside, so the inside
+// should be free of other objects. Dispersion only shows in spectral
+// renders, RGB ones use the index at the d line.
+pub struct Dielectric {
+  ref_index: RefractiveIndex,
+  tint: Vec3,
+  absorption: f64,
+}
+
+impl Dielectric {
+  pub fn new(ref_index: f64) -> Dielectric {
+    Dielectric::with_absorption(ref_index, Vec3::one(), 0.0)
+  }
+
+  pub fn with_absorption(ref_index: f64, tint: Vec3, absorption: f64) -> Dielectric {
+    Dielectric::with_refractive_index(RefractiveIndex::Constant(ref_index), tint, absorption)
+  }
+
+  pub fn with_refractive_index(ref_index: RefractiveIndex, tint: Vec3, absorption: f64) -> Dielectric {
+    Dielectric {
+      ref_index,
+      tint,
+      absorption
+    }
+  }
+
+  pub fn rc(ref_index: f64) -> Arc<Dielectric> {
+    Arc::new(Dielectric::new(ref_index))
+  }
+}
+
+impl Material for Dielectric {
+  fn scatter(&self, ray: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterInfo> {
+    // Every wavelength would bend its own way, so a spectral path follows
+    // its hero wavelength, which from then on stands in for all three.
+    let (ref_index, wavelengths) = match ray.wavelengths {
+      Some(ref wavelengths) if self.ref_index.is_dispersive() => (self.ref_index.at(wavelengths.lambda[0]), Some(*wavelengths)),
+      _ => (self.ref_index.at(LAMBDA_D), None),
+    };
+    let direction = ray.direction.normalized();
+    let cosine = Vec3::dot(&direction, &hit.normal);
+    let (outward_normal, ni_over_nt, cos_i, attenuation) = if cosine > 0.0 {
+      // Leaving, or reflecting inside, after crossing the glass to get here.
+      let depth = self.absorption * hit.t * ray.direction.length();
+      let tint = ray.spectrum(&self.tint);
+      let transmittance = |t: f64| t.clamp(1e-6, 1.0).powf(depth);
+      (hit.normal * -1.0, ref_index, cosine, Vec3::new(transmittance(tint.x), transmittance(tint.y), transmittance(tint.z)))
+    } else {
+      (hit.normal, 1.0 / ref_index, -cosine, Vec3::one())
+    };
+    let attenuation = match wavelengths {
+      Some(ref wavelengths) if !wavelengths.hero_only => Vec3::new(3.0 * attenuation.x, 0.0, 0.0),
+      Some(_) => Vec3::new(attenuation.x, 0.0, 0.0),
+      None => attenuation,
+    };
+    // Total internal reflection comes out of both as a certain reflection.
+    let reflect_prob = fresnel_dielectric(cos_i, 1.0 / ni_over_nt);
+    let u = sampler.get_1d();
+    let scattered = match Vec3::refract(&direction, &outward_normal, ni_over_nt) {
+      Some(refracted) if u >= reflect_prob => refracted,
+      _ => Vec3::reflect(&direction, &outward_normal),
+    };
+    let mut scattered = Ray::new(hit.p, scattered, ray.time);
+    scattered.wavelengths = wavelengths.map(|wavelengths| wavelengths.hero_only());
+    Some(ScatterInfo {
+      attenuation,
+      scattered,
+      specular: true,
+      pdf: 0.0
+    })
+  }
 }
 
 pub struct DiffuseLight {
@@ -164,11 +669,288 @@ impl DiffuseLight {
 }
 
 impl Material for DiffuseLight {
-  fn scatter(&self, _ray: &Ray, _hit: &HitRecord) -> Option<ScatterInfo> {
+  fn scatter(&self, _ray: &Ray, _hit: &HitRecord, _sampler: &mut dyn Sampler) -> Option<ScatterInfo> {
     None
   }
 
   fn emit(&self, u: f64, v: f64, pt: &Vec3) -> Vec3 {
     self.texture.value(u, v, pt)
   }
+
+  fn is_emissive(&self) -> bool {
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use ray::Ray;
+  use hitable::HitRecord;
+  use sampler::RandomSampler;
+  use texture::{ConstantTexture, TexturePtr};
+  use spectrum::Wavelengths;
+  use material::*;
+
+  fn constant(value: f64) -> TexturePtr {
+    ConstantTexture::rc(Vec3::one() * value)
+  }
+
+  fn microfacet(color: Vec3, roughness: f64, metalness: f64) -> MaterialPtr {
+    Microfacet::rc(ConstantTexture::rc(color), constant(roughness), constant(metalness))
+  }
+
+  fn hit_floor(material: &MaterialPtr) -> HitRecord {
+    HitRecord::new(1.0, Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 0.0, 0.0, Arc::clone(material))
+  }
+
+  // Scattering agrees with `eval` and `pdf`, and reflection is reciprocal.
+  fn check_sampling(material: &MaterialPtr, ray: &Ray, sampler: &mut dyn Sampler) {
+    let hit = hit_floor(material);
+    for _ in 0..1000 {
+      if let Some(info) = material.scatter(ray, &hit, sampler) {
+        let direction = info.scattered.direction;
+        let pdf = material.pdf(ray, &hit, &direction);
+        assert!((info.pdf - pdf).abs() <= 1e-9 * pdf);
+        let expected = material.eval(ray, &hit, &direction) / pdf;
+        assert!((info.attenuation - expected).length() < 1e-9 * expected.length().max(1.0));
+        if direction.z * ray.direction.z < 0.0 {
+          let back = Ray::new(direction, direction * -1.0, 0.0);
+          let forward = material.eval(&back, &hit, &(ray.direction * -1.0)) / ray.direction.normalized().z.abs();
+          let reverse = material.eval(ray, &hit, &direction) / direction.normalized().z.abs();
+          assert!((forward - reverse).length() < 1e-9 * reverse.length().max(1.0));
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_microfacet_sampling() {
+    let ray = Ray::new(Vec3::new(-1.0, 0.2, 0.6), Vec3::new(1.0, -0.2, -0.6), 0.0);
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    for &(roughness, metalness) in [(0.1, 1.0), (0.5, 0.0), (0.8, 0.3)].iter() {
+      check_sampling(&microfacet(Vec3::new(0.9, 0.6, 0.3), roughness, metalness), &ray, &mut sampler);
+    }
+    let from_below = Ray::new(Vec3::new(-1.0, 0.2, -0.6), Vec3::new(1.0, -0.2, 0.6), 0.0);
+    for &(metalness, transmission) in [(0.0, 1.0), (0.3, 0.5)].iter() {
+      let material: MaterialPtr = Arc::new(Microfacet::with_transmission(ConstantTexture::rc(Vec3::new(0.9, 0.6, 0.3)), constant(0.4),
+                                                                        constant(metalness), constant(transmission), 1.5));
+      check_sampling(&material, &ray, &mut sampler);
+      check_sampling(&material, &from_below, &mut sampler);
+    }
+  }
+
+  #[test]
+  fn test_microfacet_transmission() {
+    // Rough glass is the transmissive principled material with the IOR its
+    // 4% reflectance implies, from either side.
+    let color = Vec3::new(0.9, 0.6, 0.3);
+    let glass: MaterialPtr = Arc::new(Microfacet::with_transmission(ConstantTexture::rc(color), constant(0.3), constant(0.0), constant(1.0), 1.5));
+    let mut params = PrincipledParams::new(ConstantTexture::rc(color));
+    params.roughness = constant(0.3);
+    params.transmission = constant(1.0);
+    let principled: MaterialPtr = Principled::rc(params);
+    let hit = hit_floor(&glass);
+    let from_above = Ray::new(Vec3::new(-1.0, 0.2, 0.6), Vec3::new(1.0, -0.2, -0.6), 0.0);
+    let from_below = Ray::new(Vec3::new(-1.0, 0.2, -0.6), Vec3::new(1.0, -0.2, 0.6), 0.0);
+    let directions = [Vec3::new(1.0, -0.2, 0.6), Vec3::new(0.8, -0.1, -0.7), Vec3::new(0.3, 0.5, -0.2), Vec3::new(0.0, 0.0, 1.0)];
+    let mut transmitted = 0;
+    for ray in [&from_above, &from_below].iter() {
+      for direction in directions.iter() {
+        let expected = principled.eval(ray, &hit, direction);
+        assert!((glass.eval(ray, &hit, direction) - expected).length() < 1e-12);
+        assert!((glass.pdf(ray, &hit, direction) - principled.pdf(ray, &hit, direction)).abs() < 1e-12);
+        if direction.z * ray.direction.z > 0.0 && expected.length() > 0.0 {
+          transmitted += 1;
+        }
+      }
+    }
+    assert!(transmitted >= 2);
+    // Without transmission there's no inside, the back looks like the front.
+    let opaque = microfacet(color, 0.3, 0.0);
+    let mirrored = Ray::new(Vec3::new(-1.0, 0.2, -0.6), Vec3::new(1.0, -0.2, 0.6), 0.0);
+    let front = opaque.eval(&from_above, &hit, &Vec3::new(0.3, 0.5, 0.2));
+    let back = opaque.eval(&mirrored, &hit, &Vec3::new(0.3, 0.5, -0.2));
+    assert!((front - back).length() < 1e-12);
+    assert!(opaque.eval(&from_above, &hit, &Vec3::new(0.8, -0.1, -0.7)).length() == 0.0);
+  }
+
+  #[test]
+  fn test_microfacet_furnace() {
+    // A white metal only loses the light that would bounce between
+    // microfacets, none when smooth and a lot when rough. The expected
+    // albedos come from integrating the BRDF numerically.
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    let ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+    for &(roughness, expected) in [(0.0, 1.0), (0.5, 0.886), (1.0, 0.377)].iter() {
+      let material = microfacet(Vec3::one(), roughness, 1.0);
+      let hit = hit_floor(&material);
+      let n = 20000;
+      let mut albedo = 0.0;
+      for _ in 0..n {
+        if let Some(info) = material.scatter(&ray, &hit, &mut sampler) {
+          albedo += info.attenuation.x / n as f64;
+        }
+      }
+      assert!((albedo - expected).abs() < 0.01, "roughness {}: {}", roughness, albedo);
+    }
+    // A smooth metal is a mirror.
+    let material = microfacet(Vec3::one(), 0.0, 1.0);
+    let info = material.scatter(&ray, &hit_floor(&material), &mut sampler).unwrap();
+    assert!((info.scattered.direction - Vec3::new(1.0, 0.0, 1.0).normalized()).length() < 1e-2);
+  }
+
+  #[test]
+  fn test_principled_sampling() {
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    let from_above = Ray::new(Vec3::new(-1.0, 0.2, 0.6), Vec3::new(1.0, -0.2, -0.6), 0.0);
+    let from_below = Ray::new(Vec3::new(-1.0, 0.2, -0.6), Vec3::new(1.0, -0.2, 0.6), 0.0);
+    let lobes = [
+      (0.0, 0.5, 0.0, 0.0, 0.0),
+      (1.0, 0.3, 1.0, 0.0, 0.0),
+      (0.0, 0.3, 0.0, 0.0, 1.0),
+      (0.2, 0.7, 0.5, 0.5, 0.5),
+    ];
+    for &(metallic, roughness, clearcoat, sheen, transmission) in lobes.iter() {
+      let mut params = PrincipledParams::new(ConstantTexture::rc(Vec3::new(0.9, 0.6, 0.3)));
+      params.metallic = constant(metallic);
+      params.roughness = constant(roughness);
+      params.clearcoat = constant(clearcoat);
+      params.sheen = constant(sheen);
+      params.transmission = constant(transmission);
+      let material: MaterialPtr = Principled::rc(params);
+      check_sampling(&material, &from_above, &mut sampler);
+      check_sampling(&material, &from_below, &mut sampler);
+    }
+  }
+
+  #[test]
+  fn test_principled_lobes() {
+    // On its own the metal lobe is the microfacet material.
+    let color = Vec3::new(0.9, 0.6, 0.3);
+    let mut params = PrincipledParams::new(ConstantTexture::rc(color));
+    params.metallic = constant(1.0);
+    params.roughness = constant(0.4);
+    let principled: MaterialPtr = Principled::rc(params);
+    let metal = microfacet(color, 0.4, 1.0);
+    let ray = Ray::new(Vec3::new(-1.0, 0.2, 0.6), Vec3::new(1.0, -0.2, -0.6), 0.0);
+    let hit = hit_floor(&metal);
+    for &direction in [Vec3::new(1.0, -0.2, 0.6), Vec3::new(0.3, 0.5, 0.2), Vec3::new(0.0, 0.0, 1.0)].iter() {
+      let expected = metal.eval(&ray, &hit, &direction);
+      assert!((principled.eval(&ray, &hit, &direction) - expected).length() < 1e-12);
+    }
+
+    // Smooth clear glass reflects 4% head on and lets the rest through,
+    // squeezed into a smaller solid angle.
+    let mut params = PrincipledParams::new(constant(1.0));
+    params.roughness = constant(0.0);
+    params.transmission = constant(1.0);
+    let glass: MaterialPtr = Principled::rc(params);
+    let ray = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let hit = hit_floor(&glass);
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    let n = 10000;
+    let (mut reflected, mut transmitted) = (0.0, 0.0);
+    for _ in 0..n {
+      let info = glass.scatter(&ray, &hit, &mut sampler).unwrap();
+      if info.scattered.direction.z > 0.0 {
+        reflected += info.attenuation.x / n as f64;
+      } else {
+        assert!(info.scattered.direction.normalized().z < -0.999);
+        transmitted += info.attenuation.x / n as f64;
+      }
+    }
+    assert!((reflected - 0.04).abs() < 0.005, "{}", reflected);
+    assert!((transmitted - 0.96 / (1.5 * 1.5)).abs() < 0.01, "{}", transmitted);
+  }
+
+  // Fraction of the rays the dielectric reflects.
+  fn reflectance(material: &MaterialPtr, ray: &Ray, sampler: &mut dyn Sampler) -> f64 {
+    let hit = hit_floor(material);
+    let n = 40000;
+    let reflected = (0..n).filter(|_| {
+      let info = material.scatter(ray, &hit, sampler).unwrap();
+      info.scattered.direction.z * ray.direction.z < 0.0
+    }).count();
+    reflected as f64 / n as f64
+  }
+
+  #[test]
+  fn test_dielectric() {
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    let glass: MaterialPtr = Dielectric::rc(1.5);
+    let head_on = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -3.0), 0.0);
+    assert!((reflectance(&glass, &head_on, &mut sampler) - 0.04).abs() < 0.005);
+    // Reflections come out as unit directions, whatever the ray's length.
+    let grazing = Ray::new(Vec3::new(-1.0, 0.0, 0.1), Vec3::new(10.0, 0.0, -1.0), 0.0);
+    let hit = hit_floor(&glass);
+    loop {
+      let info = glass.scatter(&grazing, &hit, &mut sampler).unwrap();
+      if info.scattered.direction.z > 0.0 {
+        assert!((info.scattered.direction - Vec3::new(10.0, 0.0, 1.0).normalized()).length() < 1e-12);
+        break;
+      }
+    }
+    // Light refracted in at 60 degrees comes back out at the same angle, and
+    // is reflected equally often on either side.
+    let (sin_i, sin_t) = (60f64.to_radians().sin(), 60f64.to_radians().sin() / 1.5);
+    let outside = Ray::new(Vec3::zero(), Vec3::new(sin_i, 0.0, -(1.0 - sin_i * sin_i).sqrt()), 0.0);
+    let inside = Ray::new(Vec3::zero(), Vec3::new(sin_t, 0.0, (1.0 - sin_t * sin_t).sqrt()), 0.0);
+    let expected = fresnel_dielectric((1.0 - sin_i * sin_i).sqrt(), 1.5);
+    assert!((reflectance(&glass, &outside, &mut sampler) - expected).abs() < 0.005);
+    assert!((reflectance(&glass, &inside, &mut sampler) - expected).abs() < 0.005);
+    // Past the critical angle everything is reflected.
+    let steep = Ray::new(Vec3::zero(), Vec3::new(0.8, 0.0, 0.6), 0.0);
+    assert_eq!(reflectance(&glass, &steep, &mut sampler), 1.0);
+  }
+
+  #[test]
+  fn test_absorption() {
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    let glass: MaterialPtr = Arc::new(Dielectric::with_absorption(1.5, Vec3::new(0.5, 1.0, 0.25), 0.5));
+    // Crossing two units at an absorption of 0.5 gives the tint, entering the
+    // glass absorbs nothing.
+    let leaving = Ray::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 0.5), 0.0);
+    let hit = HitRecord::new(4.0, Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 0.0, 0.0, Arc::clone(&glass));
+    let info = glass.scatter(&leaving, &hit, &mut sampler).unwrap();
+    assert!((info.attenuation - Vec3::new(0.5, 1.0, 0.25)).length() < 1e-12);
+    let entering = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let info = glass.scatter(&entering, &hit_floor(&glass), &mut sampler).unwrap();
+    assert_eq!(info.attenuation, Vec3::one());
+  }
+
+  #[test]
+  fn test_dispersion() {
+    // Schott N-BK7.
+    let bk7 = RefractiveIndex::Sellmeier { b: [1.03961212, 0.231792344, 1.01046945], c: [0.00600069867, 0.0200179144, 103.560653] };
+    assert!((bk7.at(587.56) - 1.5168).abs() < 1e-4);
+    assert!(bk7.at(450.0) > bk7.at(650.0));
+    let cauchy = RefractiveIndex::Cauchy { a: 1.5, b: 0.01 };
+    assert!((cauchy.at(500.0) - 1.54).abs() < 1e-12);
+    assert!(!RefractiveIndex::Constant(1.5).is_dispersive());
+
+    // Spectral paths through dispersive glass carry on with the hero only,
+    // RGB ones as usual.
+    let glass: MaterialPtr = Arc::new(Dielectric::with_refractive_index(bk7, Vec3::one(), 0.0));
+    let hit = hit_floor(&glass);
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    let mut ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+    assert_eq!(glass.scatter(&ray, &hit, &mut sampler).unwrap().attenuation, Vec3::one());
+    // Blue bends more than red.
+    let mut refracted = |lambda: f64| loop {
+      let wavelengths = Wavelengths { lambda: [lambda, 500.0, 600.0], pdf: [1.0; 3], hero_only: false };
+      ray.wavelengths = Some(wavelengths);
+      let info = glass.scatter(&ray, &hit, &mut sampler).unwrap();
+      assert_eq!(info.attenuation, Vec3::new(3.0, 0.0, 0.0));
+      // Only the first glass a path meets drops the other wavelengths.
+      assert_eq!(info.scattered.wavelengths, Some(wavelengths.hero_only()));
+      let again = glass.scatter(&info.scattered, &hit, &mut sampler).unwrap();
+      assert_eq!(again.attenuation, Vec3::new(1.0, 0.0, 0.0));
+      if info.scattered.direction.z < 0.0 {
+        return info.scattered.direction.normalized();
+      }
+    };
+    assert!(refracted(450.0).x < refracted(650.0).x);
+  }
 }
@@ -0,0 +1,456 @@
+use std::sync::Arc;
+
+use vec3::Vec3;
+use ray::Ray;
+use aabb::Aabb;
+use bvh::{BvhTree, DEFAULT_BVH_LEAF_SIZE};
+use hitable::{Hitable, HitRecord};
+use material::MaterialPtr;
+use sampler::Sampler;
+
+// Möller–Trumbore, returns the distance and the barycentric weights of p1, p2.
+fn intersect_triangle(ray: &Ray, p0: &Vec3, p1: &Vec3, p2: &Vec3, t_min: f64, t_max: f64) -> Option<(f64, f64, f64)> {
+  let e1 = *p1 - *p0;
+  let e2 = *p2 - *p0;
+  let pvec = Vec3::cross(&ray.direction, &e2);
+  let det = Vec3::dot(&e1, &pvec);
+  if det.abs() < 1e-12 {
+    return None;
+  }
+  let inv_det = 1.0 / det;
+  let tvec = ray.origin - *p0;
+  let b1 = Vec3::dot(&tvec, &pvec) * inv_det;
+  if !(0.0..=1.0).contains(&b1) {
+    return None;
+  }
+  let qvec = Vec3::cross(&tvec, &e1);
+  let b2 = Vec3::dot(&ray.direction, &qvec) * inv_det;
+  if b2 < 0.0 || b1 + b2 > 1.0 {
+    return None;
+  }
+  let t = Vec3::dot(&e2, &qvec) * inv_det;
+  if t > t_min && t < t_max {
+    Some((t, b1, b2))
+  } else {
+    None
+  }
+}
+
+fn interpolate(a: Vec3, b: Vec3, c: Vec3, b1: f64, b2: f64) -> Vec3 {
+  (1.0 - b1 - b2) * a + b1 * b + b2 * c
+}
+
+// Geometric normal from the winding order, replaced by the interpolated vertex
+// normal when the triangle has them.
+fn surface_normal(p: &[Vec3; 3], normals: Option<[Vec3; 3]>, b1: f64, b2: f64) -> Vec3 {
+  if let Some(n) = normals {
+    let shading = interpolate(n[0], n[1], n[2], b1, b2).normalized();
+    if shading.length() > 0.0 {
+      return shading;
+    }
+  }
+  Vec3::cross(&(p[1] - p[0]), &(p[2] - p[0])).normalized()
+}
+
+fn triangle_area(p: &[Vec3; 3]) -> f64 {
+  Vec3::cross(&(p[1] - p[0]), &(p[2] - p[0])).length() * 0.5
+}
+
+// A point distributed uniformly over the triangle.
+fn sample_triangle(p: &[Vec3; 3], u: (f64, f64)) -> Vec3 {
+  let s = u.0.sqrt();
+  (1.0 - s) * p[0] + s * (1.0 - u.1) * p[1] + s * u.1 * p[2]
+}
+
+// Solid angle density of finding the point `t` along `direction` on the
+// triangle, when points are picked uniformly over `area`.
+fn solid_angle_pdf(p: &[Vec3; 3], direction: &Vec3, t: f64, area: f64) -> f64 {
+  let normal = Vec3::cross(&(p[1] - p[0]), &(p[2] - p[0])).normalized();
+  let distance_squared = t * t * Vec3::dot(direction, direction);
+  let cosine = (Vec3::dot(direction, &normal) / direction.length()).abs();
+  if cosine > 0.0 {
+    distance_squared / (cosine * area)
+  } else {
+    0.0
+  }
+}
+
+fn triangle_bounds(p: &[Vec3; 3]) -> Aabb {
+  // Pad so triangles lying in an axis plane still have a hittable box.
+  let eplison = Vec3::one() * 0.0001;
+  let mut min = p[0];
+  let mut max = p[0];
+  for v in p.iter().skip(1) {
+    for c in 0..3 {
+      min[c] = min[c].min(v[c]);
+      max[c] = max[c].max(v[c]);
+    }
+  }
+  Aabb::new(min - eplison, max + eplison)
+}
+
+pub struct Triangle {
+  positions: [Vec3; 3],
+  normals: Option<[Vec3; 3]>,
+  uvs: [(f64, f64); 3],
+  material: MaterialPtr,
+}
+
+impl Triangle {
+  pub fn new(p0: Vec3, p1: Vec3, p2: Vec3, material: MaterialPtr) -> Triangle {
+    Triangle::with_attributes([p0, p1, p2], None, [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)], material)
+  }
+
+  pub fn with_attributes(positions: [Vec3; 3], normals: Option<[Vec3; 3]>, uvs: [(f64, f64); 3], material: MaterialPtr) -> Triangle {
+    Triangle {
+      positions,
+      normals,
+      uvs,
+      material
+    }
+  }
+
+  pub fn hitable_ptr(p0: Vec3, p1: Vec3, p2: Vec3, material: MaterialPtr) -> Arc<Triangle> {
+    Arc::new(Triangle::new(p0, p1, p2, material))
+  }
+}
+
+impl Hitable for Triangle {
+  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    let p = &self.positions;
+    let (t, b1, b2) = intersect_triangle(ray, &p[0], &p[1], &p[2], t_min, t_max)?;
+    let uv = &self.uvs;
+    let u = (1.0 - b1 - b2) * uv[0].0 + b1 * uv[1].0 + b2 * uv[2].0;
+    let v = (1.0 - b1 - b2) * uv[0].1 + b1 * uv[1].1 + b2 * uv[2].1;
+    let normal = surface_normal(p, self.normals, b1, b2);
+    Some(HitRecord::new(t, ray.point_at_parameter(t), normal, u, v, self.material.clone()))
+  }
+
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    let p = &self.positions;
+    intersect_triangle(ray, &p[0], &p[1], &p[2], t_min, t_max).map(|(t, _, _)| t)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    let p = &self.positions;
+    intersect_triangle(ray, &p[0], &p[1], &p[2], t_min, t_max).is_some()
+  }
+
+  fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
+    triangle_bounds(&self.positions)
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive()
+  }
+
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    let p = &self.positions;
+    match intersect_triangle(&Ray::new(*origin, *direction, time), &p[0], &p[1], &p[2], 0.001, f64::MAX) {
+      Some((t, _, _)) => solid_angle_pdf(p, direction, t, triangle_area(p)),
+      None => 0.0
+    }
+  }
+
+  fn random(&self, origin: &Vec3, _time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    sample_triangle(&self.positions, sampler.get_2d()) - *origin
+  }
+}
+
+// Indices into the mesh attribute arrays for the three corners of a face.
+#[derive(Clone, Debug)]
+pub struct MeshFace {
+  pub positions: [usize; 3],
+  pub normals: Option<[usize; 3]>,
+  pub uvs: Option<[usize; 3]>,
+}
+
+impl MeshFace {
+  pub fn new(positions: [usize; 3]) -> MeshFace {
+    MeshFace {
+      positions,
+      normals: None,
+      uvs: None
+    }
+  }
+}
+
+// An indexed triangle mesh sharing one material. The faces sit in their own
+// BVH so a mesh is a single entry in the scene no matter how big it is.
+pub struct TriangleMesh {
+  positions: Vec<Vec3>,
+  normals: Vec<Vec3>,
+  uvs: Vec<(f64, f64)>,
+  faces: Vec<MeshFace>,
+  material: MaterialPtr,
+  tree: BvhTree,
+  // Running total of the face areas, light sampling picks faces by area.
+  area_cdf: Vec<f64>,
+}
+
+impl TriangleMesh {
+  // Panics if a face refers to a vertex attribute that doesn't exist.
+  pub fn new(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(f64, f64)>, faces: Vec<MeshFace>, material: MaterialPtr) -> TriangleMesh {
+    for face in faces.iter() {
+      assert!(face.positions.iter().all(|&i| i < positions.len()), "Mesh face position index out of range!");
+      assert!(face.normals.is_none_or(|n| n.iter().all(|&i| i < normals.len())), "Mesh face normal index out of range!");
+      assert!(face.uvs.is_none_or(|t| t.iter().all(|&i| i < uvs.len())), "Mesh face uv index out of range!");
+    }
+    let boxes: Vec<Aabb> = faces.iter().map(|face| triangle_bounds(&face_positions(&positions, face))).collect();
+    let (tree, order) = BvhTree::build(&boxes, DEFAULT_BVH_LEAF_SIZE);
+    let faces: Vec<MeshFace> = order.iter().map(|&i| faces[i].clone()).collect();
+    let mut total = 0.0;
+    let area_cdf = faces.iter().map(|face| {
+      total += triangle_area(&face_positions(&positions, face));
+      total
+    }).collect();
+    TriangleMesh {
+      positions,
+      normals,
+      uvs,
+      faces,
+      material,
+      tree,
+      area_cdf,
+    }
+  }
+
+  pub fn hitable_ptr(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(f64, f64)>, faces: Vec<MeshFace>, material: MaterialPtr) -> Arc<TriangleMesh> {
+    Arc::new(TriangleMesh::new(positions, normals, uvs, faces, material))
+  }
+
+  pub fn num_faces(&self) -> usize {
+    self.faces.len()
+  }
+
+  fn face_positions(&self, face: &MeshFace) -> [Vec3; 3] {
+    face_positions(&self.positions, face)
+  }
+
+  fn area(&self) -> f64 {
+    self.area_cdf.last().cloned().unwrap_or(0.0)
+  }
+
+  // The closest face hit as (face index, t, b1, b2).
+  fn closest_face(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(usize, f64, f64, f64)> {
+    let mut closest = t_max;
+    let mut found: Option<(usize, f64, f64)> = None;
+    self.tree.traverse(ray, t_min, t_max, |range| {
+      for face_index in range {
+        let p = self.face_positions(&self.faces[face_index]);
+        if let Some((t, b1, b2)) = intersect_triangle(ray, &p[0], &p[1], &p[2], t_min, closest) {
+          closest = t;
+          found = Some((face_index, b1, b2));
+        }
+      }
+      Some(closest)
+    });
+    found.map(|(face_index, b1, b2)| (face_index, closest, b1, b2))
+  }
+}
+
+fn face_positions(positions: &[Vec3], face: &MeshFace) -> [Vec3; 3] {
+  [positions[face.positions[0]], positions[face.positions[1]], positions[face.positions[2]]]
+}
+
+impl Hitable for TriangleMesh {
+  fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    // Only the closest face pays for its normal and uv.
+    let (face_index, closest, b1, b2) = self.closest_face(ray, t_min, t_max)?;
+    let face = &self.faces[face_index];
+    let p = self.face_positions(face);
+    let normals = face.normals.map(|n| [self.normals[n[0]], self.normals[n[1]], self.normals[n[2]]]);
+    let normal = surface_normal(&p, normals, b1, b2);
+    let (u, v) = match face.uvs {
+      Some(t) => {
+        let (uv0, uv1, uv2) = (self.uvs[t[0]], self.uvs[t[1]], self.uvs[t[2]]);
+        ((1.0 - b1 - b2) * uv0.0 + b1 * uv1.0 + b2 * uv2.0, (1.0 - b1 - b2) * uv0.1 + b1 * uv1.1 + b2 * uv2.1)
+      },
+      None => (b1, b2)
+    };
+    Some(HitRecord::new(closest, ray.point_at_parameter(closest), normal, u, v, self.material.clone()))
+  }
+
+  fn hit_distance(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    self.closest_face(ray, t_min, t_max).map(|(_, t, _, _)| t)
+  }
+
+  fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    let mut occluded = false;
+    self.tree.traverse(ray, t_min, t_max, |range| {
+      occluded = self.faces[range].iter().any(|face| {
+        let p = self.face_positions(face);
+        intersect_triangle(ray, &p[0], &p[1], &p[2], t_min, t_max).is_some()
+      });
+      if occluded { None } else { Some(t_max) }
+    });
+    occluded
+  }
+
+  fn bounding_box(&self, _time0: f64, _time1: f64) -> Aabb {
+    self.tree.bounding_box()
+  }
+
+  fn is_light(&self) -> bool {
+    self.material.is_emissive() && self.area() > 0.0
+  }
+
+  // A direction may pass through several faces, any of which could have
+  // been the one picked.
+  fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f64) -> f64 {
+    let ray = Ray::new(*origin, *direction, time);
+    let area = self.area();
+    let mut pdf = 0.0;
+    self.tree.traverse(&ray, 0.001, f64::MAX, |range| {
+      for face in self.faces[range].iter() {
+        let p = self.face_positions(face);
+        if let Some((t, _, _)) = intersect_triangle(&ray, &p[0], &p[1], &p[2], 0.001, f64::MAX) {
+          pdf += solid_angle_pdf(&p, direction, t, area);
+        }
+      }
+      Some(f64::MAX)
+    });
+    pdf
+  }
+
+  fn random(&self, origin: &Vec3, _time: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let target = sampler.get_1d() * self.area();
+    let index = match self.area_cdf.binary_search_by(|total| total.partial_cmp(&target).unwrap()) {
+      Ok(index) => index + 1,
+      Err(index) => index,
+    }.min(self.faces.len() - 1);
+    sample_triangle(&self.face_positions(&self.faces[index]), sampler.get_2d()) - *origin
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use ray::Ray;
+  use material::{DiffuseLight, Lambertian, MaterialPtr};
+  use sampler::RandomSampler;
+  use texture::ConstantTexture;
+  use hitable::*;
+  use mesh::*;
+
+  fn material() -> MaterialPtr {
+    Lambertian::rc(ConstantTexture::rc(Vec3::one()))
+  }
+
+  #[test]
+  fn test_triangle_attributes() {
+    let positions = [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)];
+    let normals = [Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0)];
+    let uvs = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+    let tri = Triangle::with_attributes(positions, Some(normals), uvs, material());
+    let ray = Ray::new(Vec3::new(0.5, 0.25, 2.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let hit = tri.hit(&ray, 0.0, f64::MAX).unwrap();
+    assert_eq!(hit.t, 2.0);
+    assert_eq!(hit.p, Vec3::new(0.5, 0.25, 0.0));
+    assert_eq!((hit.u, hit.v), (0.5, 0.25));
+    assert_eq!(hit.normal, Vec3::new(0.5, 0.0, 0.5).normalized());
+
+    let miss = Ray::new(Vec3::new(0.75, 0.75, 2.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    assert!(tri.hit(&miss, 0.0, f64::MAX).is_none());
+  }
+
+  #[test]
+  fn test_mesh_matches_triangles() {
+    // A bumpy grid, big enough to need several levels of the face BVH.
+    let n = 16;
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    for j in 0..n + 1 {
+      for i in 0..n + 1 {
+        let (x, z) = (i as f64, j as f64);
+        positions.push(Vec3::new(x, (x * 0.7).sin() * (z * 0.3).cos(), z));
+        uvs.push((x / n as f64, z / n as f64));
+      }
+    }
+    let mut faces = Vec::new();
+    let mut triangles = HitableList::new();
+    for j in 0..n {
+      for i in 0..n {
+        let a = j * (n + 1) + i;
+        let quad = [[a, a + n + 1, a + 1], [a + 1, a + n + 1, a + n + 2]];
+        for f in quad.iter() {
+          let mut face = MeshFace::new(*f);
+          face.uvs = Some(*f);
+          faces.push(face);
+          triangles.add_hitable(Arc::new(Triangle::with_attributes(
+            [positions[f[0]], positions[f[1]], positions[f[2]]], None, [uvs[f[0]], uvs[f[1]], uvs[f[2]]], material())));
+        }
+      }
+    }
+    let mesh = TriangleMesh::new(positions, vec![], uvs, faces, material());
+    assert_eq!(mesh.num_faces(), 2 * n * n);
+
+    for k in 0..200 {
+      let s = k as f64;
+      let origin = Vec3::new((s * 0.37).sin() * 12.0 + 8.0, 5.0, (s * 0.91).cos() * 12.0 + 8.0);
+      let direction = Vec3::new((s * 1.3).cos(), -1.0 - (s * 0.17).sin().abs(), (s * 2.1).sin());
+      let ray = Ray::new(origin, direction, 0.0);
+      let expected = triangles.hit(&ray, 0.001, f64::MAX);
+      let actual = mesh.hit(&ray, 0.001, f64::MAX);
+      assert_eq!(expected.is_some(), actual.is_some());
+      assert_eq!(mesh.occluded(&ray, 0.001, f64::MAX), actual.is_some());
+      if let Some(ref actual) = actual {
+        assert!(!mesh.occluded(&ray, 0.001, actual.t * 0.999));
+      }
+      if let (Some(expected), Some(actual)) = (expected, actual) {
+        assert_eq!(expected.t, actual.t);
+        assert_eq!(expected.normal, actual.normal);
+        assert!((expected.u - actual.u).abs() < 1e-9 && (expected.v - actual.v).abs() < 1e-9);
+      }
+    }
+  }
+
+  #[test]
+  fn test_emissive() {
+    // Two unit squares, one above the other, which a direction can pass
+    // through both of. Rects of the same size give the densities to expect.
+    let light: MaterialPtr = DiffuseLight::rc(ConstantTexture::rc(Vec3::one()));
+    let mut positions = Vec::new();
+    let mut faces = Vec::new();
+    for &y in [1.0, 2.0].iter() {
+      let base = positions.len();
+      positions.extend_from_slice(&[Vec3::new(0.0, y, 0.0), Vec3::new(1.0, y, 0.0), Vec3::new(1.0, y, 1.0), Vec3::new(0.0, y, 1.0)]);
+      faces.push(MeshFace::new([base, base + 1, base + 2]));
+      faces.push(MeshFace::new([base, base + 2, base + 3]));
+    }
+    let mesh = TriangleMesh::new(positions, vec![], vec![], faces, Arc::clone(&light));
+    assert!(mesh.is_light());
+    assert!(!TriangleMesh::new(vec![], vec![], vec![], vec![], Arc::clone(&light)).is_light());
+    let lower = Rect::xzrect(0.0, 0.0, 1.0, 1.0, 1.0, Arc::clone(&light));
+    let upper = Rect::xzrect(0.0, 0.0, 1.0, 1.0, 2.0, Arc::clone(&light));
+    let origin = Vec3::new(0.25, 0.0, 0.5);
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    let (mut lower_hits, n) = (0, 1000);
+    for _ in 0..n {
+      let direction = mesh.random(&origin, 0.0, &mut sampler);
+      let p = origin + direction;
+      assert!(p.x >= 0.0 && p.x <= 1.0 && p.z >= 0.0 && p.z <= 1.0, "{:?}", p);
+      if (p.y - 1.0).abs() < 1e-9 {
+        lower_hits += 1;
+      } else {
+        assert!((p.y - 2.0).abs() < 1e-9, "{:?}", p);
+      }
+      // Each square holds half the area.
+      let expected = 0.5 * (lower.pdf_value(&origin, &direction, 0.0) + upper.pdf_value(&origin, &direction, 0.0));
+      assert!((mesh.pdf_value(&origin, &direction, 0.0) - expected).abs() < 1e-9 * expected);
+    }
+    assert!((lower_hits as f64 / n as f64 - 0.5).abs() < 0.05);
+
+    let triangle = Triangle::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 1.0), light);
+    assert!(triangle.is_light());
+    assert!(!Triangle::new(Vec3::zero(), Vec3::one(), Vec3::new(0.0, 1.0, 0.0), material()).is_light());
+    for _ in 0..100 {
+      let direction = triangle.random(&origin, 0.0, &mut sampler);
+      let p = origin + direction;
+      assert!((p.y - 1.0).abs() < 1e-9 && p.z <= p.x, "{:?}", p);
+      let expected = 2.0 * lower.pdf_value(&origin, &direction, 0.0);
+      assert!((triangle.pdf_value(&origin, &direction, 0.0) - expected).abs() < 1e-9 * expected);
+    }
+  }
+}
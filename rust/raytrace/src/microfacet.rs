@@ -0,0 +1,196 @@
+// The GGX (Trowbridge-Reitz) microfacet distribution with Smith masking and
+// shadowing. Everything works in a shading frame with the normal along +z.
+// `alpha` is the width of the distribution, materials square their
+// perceptual roughness to get it.
+
+use std::f64::consts::PI;
+use vec3::Vec3;
+
+// Narrower distributions are mirrors for all we can tell, and their peak
+// density gets out of hand.
+pub const MIN_ALPHA: f64 = 1e-3;
+
+pub fn roughness_to_alpha(roughness: f64) -> f64 {
+  let roughness = roughness.clamp(0.0, 1.0);
+  (roughness * roughness).max(MIN_ALPHA)
+}
+
+// Density of microfacet normals, normalized so their projected area adds up
+// to one: the integral of D(h) h.z over the hemisphere.
+pub fn ggx_d(h: &Vec3, alpha: f64) -> f64 {
+  if h.z <= 0.0 {
+    return 0.0;
+  }
+  let a2 = alpha * alpha;
+  let d = h.z * h.z * (a2 - 1.0) + 1.0;
+  a2 / (PI * d * d)
+}
+
+fn ggx_lambda(w: &Vec3, alpha: f64) -> f64 {
+  let cos2 = w.z * w.z;
+  let tan2 = (1.0 - cos2).max(0.0) / cos2;
+  ((1.0 + alpha * alpha * tan2).sqrt() - 1.0) * 0.5
+}
+
+// The fraction of microfacets facing `h`-wards that `w` sees.
+pub fn smith_g1(w: &Vec3, alpha: f64) -> f64 {
+  1.0 / (1.0 + ggx_lambda(w, alpha))
+}
+
+// Height correlated masking and shadowing of the pair of directions.
+pub fn smith_g2(wo: &Vec3, wi: &Vec3, alpha: f64) -> f64 {
+  1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha))
+}
+
+// Picks a microfacet normal among those `wo` sees, in proportion to their
+// projected area (Heitz 2018). Stretching the view direction turns the
+// distribution into a hemisphere, whose visible part is a disk to sample.
+pub fn sample_visible_normal(wo: &Vec3, alpha: f64, u: (f64, f64)) -> Vec3 {
+  let v = Vec3::new(alpha * wo.x, alpha * wo.y, wo.z).normalized();
+  let len2 = v.x * v.x + v.y * v.y;
+  let t1 = if len2 > 0.0 {
+    Vec3::new(-v.y, v.x, 0.0) / len2.sqrt()
+  } else {
+    Vec3::new(1.0, 0.0, 0.0)
+  };
+  let t2 = Vec3::cross(&v, &t1);
+  let r = u.0.sqrt();
+  let phi = 2.0 * PI * u.1;
+  let p1 = r * phi.cos();
+  // The disk is cut in half when seen from grazing angles.
+  let s = 0.5 * (1.0 + v.z);
+  let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+  let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+  Vec3::new(alpha * n.x, alpha * n.y, n.z.max(1e-6)).normalized()
+}
+
+// Density of `sample_visible_normal` picking `h` when looking from `wo`.
+pub fn visible_normal_pdf(wo: &Vec3, h: &Vec3, alpha: f64) -> f64 {
+  let cosine = Vec3::dot(wo, h);
+  if cosine <= 0.0 || wo.z <= 0.0 {
+    return 0.0;
+  }
+  smith_g1(wo, alpha) * cosine * ggx_d(h, alpha) / wo.z
+}
+
+// Mirrors `wo` about the microfacet normal `h`.
+pub fn reflect(wo: &Vec3, h: &Vec3) -> Vec3 {
+  2.0 * Vec3::dot(wo, h) * *h - *wo
+}
+
+// Refracts `wo` through the microfacet normal `h` on its side, `eta` is the
+// refractive index on the far side over the one on the side of `wo`. None
+// past the critical angle.
+pub fn refract(wo: &Vec3, h: &Vec3, eta: f64) -> Option<Vec3> {
+  let cos_i = Vec3::dot(wo, h);
+  let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+  if sin2_t >= 1.0 {
+    return None;
+  }
+  let cos_t = (1.0 - sin2_t).sqrt();
+  Some((cos_i / eta - cos_t) * *h - *wo / eta)
+}
+
+// Unpolarized reflectance of the boundary between two dielectrics, with
+// `eta` as for `refract`. Total internal reflection gives one.
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+  let cos_i = cos_i.clamp(0.0, 1.0);
+  let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+  if sin2_t >= 1.0 {
+    return 1.0;
+  }
+  let cos_t = (1.0 - sin2_t).sqrt();
+  let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+  let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+  0.5 * (rs * rs + rp * rp)
+}
+
+// Schlick's approximation of the reflectance, given the one at normal
+// incidence per channel.
+pub fn fresnel_schlick(cosine: f64, f0: &Vec3) -> Vec3 {
+  let m = (1.0 - cosine).clamp(0.0, 1.0);
+  *f0 + (Vec3::one() - *f0) * (m * m * m * m * m)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::f64::consts::PI;
+  use vec3::Vec3;
+  use sampler::{RandomSampler, Sampler};
+  use microfacet::*;
+
+  fn spherical(theta: f64, phi: f64) -> Vec3 {
+    Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+  }
+
+  // Midpoint rule over the hemisphere.
+  fn integrate<F: Fn(&Vec3) -> f64>(f: F) -> f64 {
+    let (n_theta, n_phi) = (400, 200);
+    let (d_theta, d_phi) = (0.5 * PI / n_theta as f64, 2.0 * PI / n_phi as f64);
+    let mut sum = 0.0;
+    for i in 0..n_theta {
+      let theta = (i as f64 + 0.5) * d_theta;
+      for j in 0..n_phi {
+        let phi = (j as f64 + 0.5) * d_phi;
+        sum += f(&spherical(theta, phi)) * theta.sin() * d_theta * d_phi;
+      }
+    }
+    sum
+  }
+
+  #[test]
+  fn test_distribution() {
+    for &alpha in [0.1, 0.5, 1.0].iter() {
+      let area = integrate(|h| ggx_d(h, alpha) * h.z);
+      assert!((area - 1.0).abs() < 1e-3, "alpha {}: {}", alpha, area);
+    }
+    let up = Vec3::new(0.0, 0.0, 1.0);
+    assert_eq!(smith_g1(&up, 0.5), 1.0);
+    let grazing = spherical(1.5, 0.0);
+    assert!(smith_g1(&grazing, 0.5) < 0.5);
+    assert!(smith_g2(&grazing, &up, 0.5) <= smith_g1(&grazing, 0.5));
+    assert_eq!(roughness_to_alpha(0.5), 0.25);
+    assert_eq!(roughness_to_alpha(0.0), MIN_ALPHA);
+  }
+
+  #[test]
+  fn test_visible_normals() {
+    let alpha = 0.4;
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    for &theta in [0.0, 0.8, 1.4].iter() {
+      let wo = spherical(theta, 0.3);
+      let total = integrate(|h| visible_normal_pdf(&wo, h, alpha));
+      assert!((total - 1.0).abs() < 1e-2, "theta {}: {}", theta, total);
+      // The samples follow the density.
+      let expected = integrate(|h| visible_normal_pdf(&wo, h, alpha) * h.x);
+      let n = 100000;
+      let mut mean = 0.0;
+      for _ in 0..n {
+        let h = sample_visible_normal(&wo, alpha, sampler.get_2d());
+        assert!(Vec3::dot(&wo, &h) >= 0.0);
+        mean += h.x / n as f64;
+      }
+      assert!((mean - expected).abs() < 5e-3, "theta {}: {} != {}", theta, mean, expected);
+    }
+  }
+
+  #[test]
+  fn test_fresnel() {
+    let f0 = Vec3::new(0.04, 0.5, 1.0);
+    assert_eq!(fresnel_schlick(1.0, &f0), f0);
+    assert_eq!(fresnel_schlick(0.0, &f0), Vec3::one());
+    let wo = spherical(0.7, 1.0);
+    let h = Vec3::new(0.0, 0.0, 1.0);
+    assert!((reflect(&wo, &h) - spherical(0.7, 1.0 + PI)).length() < 1e-12);
+    assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-12);
+    assert_eq!(fresnel_dielectric(0.2, 1.0 / 1.5), 1.0);
+    // Snell's law, and back the same way.
+    let t = refract(&wo, &h, 1.5).unwrap();
+    assert!((0.7f64.sin() - 1.5 * (1.0 - t.z * t.z).sqrt()).abs() < 1e-12);
+    assert!(t.z < 0.0 && (t.length() - 1.0).abs() < 1e-12);
+    let back = refract(&t, &(h * -1.0), 1.0 / 1.5).unwrap();
+    assert!((back - wo).length() < 1e-12);
+    assert!(refract(&spherical(1.2, 0.0), &h, 1.0 / 1.5).is_none());
+  }
+}
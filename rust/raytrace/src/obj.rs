@@ -0,0 +1,443 @@
+// Wavefront OBJ and MTL import.
+//
+// Faces are grouped by their `usemtl` material and each group becomes one
+// TriangleMesh, polygons are fan triangulated. MTL materials are mapped onto
+// the materials we have:
+//
+//   Ke (non black)                        -> DiffuseLight
+//   any of Pr, Pm, map_Pr or map_Pm       -> Microfacet using map_Kd or Kd,
+//                                            roughness defaults to 0.5 and
+//                                            metalness to 0, Ni sets the IOR
+//                                            and transparency below makes it
+//                                            rough glass
+//   d < 1, Tr > 0 or illum 4, 6, 7        -> Dielectric using Ni
+//   illum 3, or a black Kd with a Ks      -> Metal using Ks, Ns sets the fuzz
+//   everything else                       -> Lambertian using map_Kd or Kd
+//
+// Statements we don't understand (groups, smoothing, lines, ...) are skipped.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use vec3::Vec3;
+use hitable::{Bvh, HitablePtr};
+use material::*;
+use texture::{ConstantTexture, ImageTexture, TexturePtr};
+use mesh::{MeshFace, TriangleMesh};
+
+#[derive(Debug)]
+pub enum ObjError {
+  Io(PathBuf, io::Error),
+  Parse { file: PathBuf, line: usize, message: String },
+}
+
+impl fmt::Display for ObjError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ObjError::Io(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+      ObjError::Parse { ref file, line, ref message } => write!(f, "{}:{}: {}", file.display(), line, message),
+    }
+  }
+}
+
+impl Error for ObjError {}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+  let mut text = String::new();
+  File::open(path)
+    .and_then(|mut f| f.read_to_string(&mut text))
+    .map_err(|err| ObjError::Io(path.to_path_buf(), err))?;
+  Ok(text)
+}
+
+fn parse_error(file: &Path, line: usize, message: String) -> ObjError {
+  ObjError::Parse { file: file.to_path_buf(), line, message }
+}
+
+fn parse_f64(token: Option<&str>, what: &str) -> Result<f64, String> {
+  let token = token.ok_or_else(|| format!("expected {}", what))?;
+  token.parse::<f64>().map_err(|_| format!("expected {}, found '{}'", what, token))
+}
+
+fn parse_vec3(tokens: &mut dyn Iterator<Item = &str>, what: &str) -> Result<Vec3, String> {
+  Ok(Vec3::new(parse_f64(tokens.next(), what)?, parse_f64(tokens.next(), what)?, parse_f64(tokens.next(), what)?))
+}
+
+// Options come before the file name, which is always last.
+fn parse_map(tokens: &mut dyn Iterator<Item = &str>, base_dir: &Path) -> Result<TexturePtr, String> {
+  match tokens.last() {
+    Some(name) => {
+      let image_path = base_dir.join(name);
+      ImageTexture::open(&image_path)
+        .map(|texture| Arc::new(texture) as TexturePtr)
+        .map_err(|err| format!("unable to load image {}: {}", image_path.display(), err))
+    },
+    None => Err("expected texture file".to_string()),
+  }
+}
+
+// The v coordinate is optional for 1D textures.
+fn parse_uv(tokens: &mut dyn Iterator<Item = &str>) -> Result<(f64, f64), String> {
+  let u = parse_f64(tokens.next(), "texture coordinate")?;
+  let v = match tokens.next() {
+    Some(v) => parse_f64(Some(v), "texture coordinate")?,
+    None => 0.0,
+  };
+  Ok((u, v))
+}
+
+// Loads an OBJ file along with any MTL libraries it references. When
+// `material` is given it's used for every face and MTL files are ignored.
+pub fn load_obj(path: &Path, material: Option<MaterialPtr>) -> Result<HitablePtr, ObjError> {
+  let text = read_file(path)?;
+  parse_obj(&text, path, material)
+}
+
+// `path` names the OBJ file for error messages and locating MTL libraries.
+pub fn parse_obj(text: &str, path: &Path, material: Option<MaterialPtr>) -> Result<HitablePtr, ObjError> {
+  let base_dir = path.parent().unwrap_or(Path::new(""));
+  let mut positions: Vec<Vec3> = Vec::new();
+  let mut normals: Vec<Vec3> = Vec::new();
+  let mut uvs: Vec<(f64, f64)> = Vec::new();
+  let mut materials: HashMap<String, MaterialPtr> = HashMap::new();
+  // Faces grouped by material, in the order the materials were first used.
+  let mut groups: Vec<(String, Vec<MeshFace>)> = vec![(String::new(), Vec::new())];
+  let mut current = 0;
+
+  for (index, line) in text.lines().enumerate() {
+    let line_number = index + 1;
+    let line = line.split('#').next().unwrap_or("");
+    let mut tokens = line.split_whitespace();
+    let keyword = match tokens.next() {
+      Some(keyword) => keyword,
+      None => continue,
+    };
+    let result: Result<(), String> = match keyword {
+      "v" => parse_vec3(&mut tokens, "vertex position").map(|p| positions.push(p)),
+      "vn" => parse_vec3(&mut tokens, "vertex normal").map(|n| normals.push(n)),
+      "vt" => parse_uv(&mut tokens).map(|uv| uvs.push(uv)),
+      "f" => {
+        let corners = tokens
+          .map(|corner| parse_corner(corner, positions.len(), uvs.len(), normals.len()))
+          .collect::<Result<Vec<_>, String>>();
+        corners.and_then(|corners| {
+          if corners.len() < 3 {
+            return Err(format!("face needs at least 3 vertices, found {}", corners.len()));
+          }
+          for i in 1..corners.len() - 1 {
+            let c = [corners[0], corners[i], corners[i + 1]];
+            let mut face = MeshFace::new([c[0].0, c[1].0, c[2].0]);
+            if let (Some(t0), Some(t1), Some(t2)) = (c[0].1, c[1].1, c[2].1) {
+              face.uvs = Some([t0, t1, t2]);
+            }
+            if let (Some(n0), Some(n1), Some(n2)) = (c[0].2, c[1].2, c[2].2) {
+              face.normals = Some([n0, n1, n2]);
+            }
+            groups[current].1.push(face);
+          }
+          Ok(())
+        })
+      },
+      "mtllib" if material.is_none() => {
+        let name = line.trim_start()[keyword.len()..].trim();
+        if name.is_empty() {
+          Err("expected material library path".to_string())
+        } else {
+          let mtl_path = base_dir.join(name);
+          let mtl_text = read_file(&mtl_path)?;
+          materials.extend(parse_mtl(&mtl_text, &mtl_path)?);
+          Ok(())
+        }
+      },
+      "usemtl" if material.is_none() => {
+        match tokens.next() {
+          Some(name) if materials.contains_key(name) => {
+            current = match groups.iter().position(|g| g.0 == name) {
+              Some(group) => group,
+              None => {
+                groups.push((name.to_string(), Vec::new()));
+                groups.len() - 1
+              }
+            };
+            Ok(())
+          },
+          Some(name) => Err(format!("unknown material '{}'", name)),
+          None => Err("expected material name".to_string()),
+        }
+      },
+      _ => Ok(())
+    };
+    result.map_err(|message| parse_error(path, line_number, message))?;
+  }
+
+  let default_material: MaterialPtr = match material {
+    Some(material) => material,
+    None => Lambertian::rc(ConstantTexture::rc(Vec3::new(0.8, 0.8, 0.8))),
+  };
+  let mut meshes: Vec<HitablePtr> = Vec::new();
+  for (name, faces) in groups {
+    if faces.is_empty() {
+      continue;
+    }
+    let material = materials.get(&name).cloned().unwrap_or_else(|| default_material.clone());
+    meshes.push(compact_mesh(&positions, &normals, &uvs, faces, material));
+  }
+  match meshes.len() {
+    0 => Err(parse_error(path, text.lines().count(), "file has no faces".to_string())),
+    1 => Ok(meshes.pop().unwrap()),
+    _ => Ok(Arc::new(Bvh::new(meshes, 0.0, 1.0)))
+  }
+}
+
+// Resolves one "v", "v/vt", "v//vn" or "v/vt/vn" face corner into zero based
+// indices. Negative indices count back from the most recent element.
+fn parse_corner(corner: &str, num_positions: usize, num_uvs: usize, num_normals: usize) -> Result<(usize, Option<usize>, Option<usize>), String> {
+  let resolve = |token: &str, count: usize, what: &str| -> Result<usize, String> {
+    let index = token.parse::<i64>().map_err(|_| format!("invalid {} index '{}'", what, token))?;
+    let resolved = if index > 0 {
+      index - 1
+    } else {
+      count as i64 + index
+    };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+      return Err(format!("{} index {} out of range", what, index));
+    }
+    Ok(resolved as usize)
+  };
+  let mut parts = corner.split('/');
+  let position = resolve(parts.next().unwrap_or(""), num_positions, "vertex")?;
+  let uv = match parts.next() {
+    Some(t) if !t.is_empty() => Some(resolve(t, num_uvs, "texture coordinate")?),
+    _ => None
+  };
+  let normal = match parts.next() {
+    Some(n) if !n.is_empty() => Some(resolve(n, num_normals, "normal")?),
+    _ => None
+  };
+  if parts.next().is_some() {
+    return Err(format!("invalid face vertex '{}'", corner));
+  }
+  Ok((position, uv, normal))
+}
+
+// Builds a mesh holding only the attributes its faces use.
+fn compact_mesh(positions: &[Vec3], normals: &[Vec3], uvs: &[(f64, f64)], mut faces: Vec<MeshFace>, material: MaterialPtr) -> HitablePtr {
+  fn remap<T: Copy>(indices: &mut [usize; 3], source: &[T], dest: &mut Vec<T>, map: &mut HashMap<usize, usize>) {
+    for index in indices.iter_mut() {
+      *index = *map.entry(*index).or_insert_with(|| {
+        dest.push(source[*index]);
+        dest.len() - 1
+      });
+    }
+  }
+  let mut mesh_positions = Vec::new();
+  let mut mesh_normals = Vec::new();
+  let mut mesh_uvs = Vec::new();
+  let mut position_map = HashMap::new();
+  let mut normal_map = HashMap::new();
+  let mut uv_map = HashMap::new();
+  for face in faces.iter_mut() {
+    remap(&mut face.positions, positions, &mut mesh_positions, &mut position_map);
+    if let Some(ref mut n) = face.normals {
+      remap(n, normals, &mut mesh_normals, &mut normal_map);
+    }
+    if let Some(ref mut t) = face.uvs {
+      remap(t, uvs, &mut mesh_uvs, &mut uv_map);
+    }
+  }
+  TriangleMesh::hitable_ptr(mesh_positions, mesh_normals, mesh_uvs, faces, material)
+}
+
+#[derive(Default)]
+struct MtlDescription {
+  diffuse: Option<Vec3>,
+  diffuse_map: Option<TexturePtr>,
+  specular: Option<Vec3>,
+  emissive: Option<Vec3>,
+  shininess: Option<f64>,
+  ior: Option<f64>,
+  dissolve: Option<f64>,
+  illum: Option<u32>,
+  roughness: Option<f64>,
+  roughness_map: Option<TexturePtr>,
+  metalness: Option<f64>,
+  metalness_map: Option<TexturePtr>,
+}
+
+impl MtlDescription {
+  fn material(&self) -> MaterialPtr {
+    let black = Vec3::zero();
+    let max = |c: Vec3| c.x.max(c.y).max(c.z);
+    let emissive = self.emissive.unwrap_or(black);
+    let specular = self.specular.unwrap_or(black);
+    let diffuse = self.diffuse.unwrap_or(Vec3::new(0.8, 0.8, 0.8));
+    let transparent = self.dissolve.is_some_and(|d| d < 1.0) || matches!(self.illum, Some(4) | Some(6) | Some(7));
+    let physically_based = self.roughness.is_some() || self.roughness_map.is_some() || self.metalness.is_some() || self.metalness_map.is_some();
+    let base_color = || match self.diffuse_map {
+      Some(ref texture) => texture.clone(),
+      None => ConstantTexture::rc(diffuse),
+    };
+    if max(emissive) > 0.0 {
+      DiffuseLight::rc(ConstantTexture::rc(emissive))
+    } else if physically_based {
+      let roughness = self.roughness_map.clone().unwrap_or_else(|| ConstantTexture::rc(Vec3::one() * self.roughness.unwrap_or(0.5)));
+      let metalness = self.metalness_map.clone().unwrap_or_else(|| ConstantTexture::rc(Vec3::one() * self.metalness.unwrap_or(0.0)));
+      let transmission = ConstantTexture::rc(Vec3::one() * if transparent { 1.0 } else { 0.0 });
+      Arc::new(Microfacet::with_transmission(base_color(), roughness, metalness, transmission, self.ior.unwrap_or(1.5)))
+    } else if transparent {
+      Dielectric::rc(self.ior.unwrap_or(1.5))
+    } else if max(specular) > 0.0 && (self.illum == Some(3) || (max(diffuse) == 0.0 && self.diffuse_map.is_none())) {
+      // Map the Phong exponent onto a fuzz, sharp highlights mean little fuzz.
+      let fuzz = (2.0 / (self.shininess.unwrap_or(0.0).max(0.0) + 2.0)).sqrt();
+      Metal::rc(ConstantTexture::rc(specular), fuzz)
+    } else {
+      Lambertian::rc(base_color())
+    }
+  }
+}
+
+// Parses an MTL library, `path` locates texture maps and names the file in
+// errors.
+pub fn parse_mtl(text: &str, path: &Path) -> Result<HashMap<String, MaterialPtr>, ObjError> {
+  let base_dir = path.parent().unwrap_or(Path::new(""));
+  let mut descriptions: Vec<(String, MtlDescription)> = Vec::new();
+  for (index, line) in text.lines().enumerate() {
+    let line = line.split('#').next().unwrap_or("");
+    let mut tokens = line.split_whitespace();
+    let keyword = match tokens.next() {
+      Some(keyword) => keyword,
+      None => continue,
+    };
+    let result: Result<(), String> = if keyword == "newmtl" {
+      match tokens.next() {
+        Some(name) => {
+          descriptions.push((name.to_string(), MtlDescription::default()));
+          Ok(())
+        },
+        None => Err("expected material name".to_string()),
+      }
+    } else if let Some(&mut (_, ref mut mtl)) = descriptions.last_mut() {
+      match keyword {
+        "Kd" => parse_vec3(&mut tokens, "diffuse color").map(|c| mtl.diffuse = Some(c)),
+        "Ks" => parse_vec3(&mut tokens, "specular color").map(|c| mtl.specular = Some(c)),
+        "Ke" => parse_vec3(&mut tokens, "emissive color").map(|c| mtl.emissive = Some(c)),
+        "Ns" => parse_f64(tokens.next(), "specular exponent").map(|n| mtl.shininess = Some(n)),
+        "Ni" => parse_f64(tokens.next(), "index of refraction").map(|n| mtl.ior = Some(n)),
+        "d" => parse_f64(tokens.next(), "dissolve").map(|d| mtl.dissolve = Some(d)),
+        "Tr" => parse_f64(tokens.next(), "transparency").map(|t| mtl.dissolve = Some(1.0 - t)),
+        "illum" => {
+          let token = tokens.next().unwrap_or("");
+          token.parse::<u32>()
+            .map(|i| mtl.illum = Some(i))
+            .map_err(|_| format!("expected illumination model, found '{}'", token))
+        },
+        "Pr" => parse_f64(tokens.next(), "roughness").map(|r| mtl.roughness = Some(r)),
+        "Pm" => parse_f64(tokens.next(), "metalness").map(|m| mtl.metalness = Some(m)),
+        "map_Kd" => parse_map(&mut tokens, base_dir).map(|t| mtl.diffuse_map = Some(t)),
+        "map_Pr" => parse_map(&mut tokens, base_dir).map(|t| mtl.roughness_map = Some(t)),
+        "map_Pm" => parse_map(&mut tokens, base_dir).map(|t| mtl.metalness_map = Some(t)),
+        _ => Ok(())
+      }
+    } else {
+      Err(format!("'{}' before any newmtl", keyword))
+    };
+    result.map_err(|message| parse_error(path, index + 1, message))?;
+  }
+  Ok(descriptions.into_iter().map(|(name, mtl)| (name, mtl.material())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::path::Path;
+  use vec3::Vec3;
+  use ray::Ray;
+  use obj::*;
+
+  const QUAD: &str = "
+    # unit quad in the xy plane facing +z
+    v 0 0 0
+    v 1 0 0
+    v 1 1 0
+    v 0 1 0
+    vt 0 0
+    vt 1 0
+    vt 1 1
+    vt 0 1
+    vn 0 0 1
+    g quad
+    s off
+    f -4/-4/1 -3/-3/1 -2/-2/1 -1/-1/1
+  ";
+
+  #[test]
+  fn test_parse_quad() {
+    let mesh = parse_obj(QUAD, Path::new("quad.obj"), None).unwrap();
+    let ray = Ray::new(Vec3::new(0.25, 0.75, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let hit = mesh.hit(&ray, 0.0, f64::MAX).unwrap();
+    assert_eq!(hit.t, 1.0);
+    assert_eq!(hit.normal, Vec3::new(0.0, 0.0, 1.0));
+    assert!((hit.u - 0.25).abs() < 1e-12 && (hit.v - 0.75).abs() < 1e-12);
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    let line_of = |text: &str| match parse_obj(text, Path::new("bad.obj"), None) {
+      Err(ObjError::Parse { line, .. }) => line,
+      Err(err) => panic!("unexpected error {}", err),
+      Ok(_) => panic!("expected an error"),
+    };
+    assert_eq!(line_of("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4"), 4);
+    assert_eq!(line_of("v 0 0 zero"), 1);
+    assert_eq!(line_of("v 0 0 0\nv 1 0 0\nf 1 2"), 3);
+    assert_eq!(line_of("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3/1"), 4);
+    assert_eq!(line_of("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2"), 4);
+    assert_eq!(line_of("v 0 0 0\nusemtl missing"), 2);
+    assert_eq!(line_of("v 0 0 0\n"), 1);
+    match parse_obj("mtllib missing.mtl", Path::new("bad.obj"), None) {
+      Err(ObjError::Io(..)) => {},
+      _ => panic!("a missing material library should be an io error"),
+    }
+  }
+
+  #[test]
+  fn test_parse_mtl() {
+    let text = "
+      newmtl light
+      Ke 4 4 4
+      newmtl glass
+      Ni 1.33
+      d 0.2
+      newmtl chrome
+      Kd 0 0 0
+      Ks 0.9 0.9 0.9
+      Ns 500
+      newmtl clay
+      Kd 0.7 0.5 0.3
+      Ks 0.5 0.5 0.5
+      illum 2
+      newmtl gold
+      Kd 1 0.78 0.34
+      Pr 0.3
+      Pm 1
+    ";
+    let materials = parse_mtl(text, Path::new("test.mtl")).unwrap();
+    assert_eq!(materials.len(), 5);
+    assert_eq!(materials["light"].emit(0.0, 0.0, &Vec3::zero()), Vec3::new(4.0, 4.0, 4.0));
+    assert_eq!(materials["clay"].emit(0.0, 0.0, &Vec3::zero()), Vec3::zero());
+    match parse_mtl("Kd 1 1 1", Path::new("test.mtl")) {
+      Err(ObjError::Parse { line: 1, .. }) => {},
+      _ => panic!("properties before newmtl should be rejected"),
+    }
+    match parse_mtl("newmtl rough\nPr\nPm 0", Path::new("test.mtl")) {
+      Err(ObjError::Parse { line: 2, .. }) => {},
+      _ => panic!("a missing roughness should be rejected"),
+    }
+  }
+}
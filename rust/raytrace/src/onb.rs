@@ -0,0 +1,35 @@
+use vec3::Vec3;
+
+// Orthonormal basis, used to turn directions sampled around +z into world
+// space directions around `w`.
+pub struct Onb {
+  pub u: Vec3,
+  pub v: Vec3,
+  pub w: Vec3,
+}
+
+impl Onb {
+  pub fn from_w(n: &Vec3) -> Onb {
+    let w = n.normalized();
+    let a = if w.x.abs() > 0.9 {
+      Vec3::new(0.0, 1.0, 0.0)
+    } else {
+      Vec3::new(1.0, 0.0, 0.0)
+    };
+    let v = Vec3::cross(&w, &a).normalized();
+    let u = Vec3::cross(&w, &v);
+    Onb {
+      u,
+      v,
+      w
+    }
+  }
+
+  pub fn local(&self, a: &Vec3) -> Vec3 {
+    a.x * self.u + a.y * self.v + a.z * self.w
+  }
+
+  pub fn to_local(&self, a: &Vec3) -> Vec3 {
+    Vec3::new(Vec3::dot(a, &self.u), Vec3::dot(a, &self.v), Vec3::dot(a, &self.w))
+  }
+}
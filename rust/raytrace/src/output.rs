@@ -0,0 +1,216 @@
+extern crate image;
+extern crate byteorder;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use self::byteorder::{ByteOrder, LittleEndian};
+use self::image::ColorType;
+use self::image::png::PNGEncoder;
+use self::image::jpeg::JPEGEncoder;
+
+use vec3::Vec3;
+use exr::{ExrImage, ExrPixelType};
+
+// Images are handed around as rows of RGB floats, top row first.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+  Pfm,
+  Ppm,
+  Png,
+  Jpeg,
+  Exr(ExrPixelType),
+}
+
+impl ImageFormat {
+  pub fn from_name(name: &str) -> Option<ImageFormat> {
+    match name.to_lowercase().as_str() {
+      "pfm" => Some(ImageFormat::Pfm),
+      "ppm" => Some(ImageFormat::Ppm),
+      "png" => Some(ImageFormat::Png),
+      "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+      "exr" => Some(ImageFormat::Exr(ExrPixelType::Half)),
+      _ => None
+    }
+  }
+
+  pub fn from_path(path: &Path) -> Option<ImageFormat> {
+    path.extension()
+      .and_then(|ext| ext.to_str())
+      .and_then(ImageFormat::from_name)
+  }
+
+  // HDR formats store the radiance as is, the rest are tonemapped.
+  pub fn is_hdr(&self) -> bool {
+    matches!(*self, ImageFormat::Pfm | ImageFormat::Exr(_))
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tonemap {
+  Clamp,
+  // Luminance based Reinhard, colors above the white point burn out.
+  Reinhard { white: f64 },
+  // Narkowicz's fit of the ACES filmic curve.
+  Aces,
+}
+
+impl Tonemap {
+  pub fn from_name(name: &str) -> Option<Tonemap> {
+    match name.to_lowercase().as_str() {
+      "clamp" => Some(Tonemap::Clamp),
+      "reinhard" => Some(Tonemap::Reinhard { white: f64::INFINITY }),
+      "aces" => Some(Tonemap::Aces),
+      _ => None
+    }
+  }
+
+  pub fn apply(&self, c: &Vec3) -> Vec3 {
+    match *self {
+      Tonemap::Clamp => *c,
+      Tonemap::Reinhard { white } => {
+        let lum = 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+        if lum <= 0.0 {
+          return Vec3::zero();
+        }
+        let mapped = (lum * (1.0 + (lum / (white * white)))) / (lum + 1.0);
+        let scale = mapped / lum;
+        *c * scale
+      },
+      Tonemap::Aces => {
+        let curve = |x: f64| {
+          let x = x.max(0.0);
+          (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+        };
+        Vec3::new(curve(c.x), curve(c.y), curve(c.z))
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+  Linear,
+  Srgb,
+  Gamma(f64),
+}
+
+impl Encoding {
+  pub fn encode(&self, v: f64) -> f64 {
+    let v = v.clamp(0.0, 1.0);
+    match *self {
+      Encoding::Linear => v,
+      Encoding::Srgb => if v <= 0.0031308 {
+        12.92 * v
+      } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+      },
+      Encoding::Gamma(gamma) => v.powf(1.0 / gamma),
+    }
+  }
+}
+
+// How radiance is squeezed into 8 bits per channel.
+#[derive(Clone, Copy, Debug)]
+pub struct LdrSettings {
+  pub exposure: f64,
+  pub tonemap: Tonemap,
+  pub encoding: Encoding,
+}
+
+impl Default for LdrSettings {
+  fn default() -> LdrSettings {
+    LdrSettings::new()
+  }
+}
+
+impl LdrSettings {
+  pub fn new() -> LdrSettings {
+    LdrSettings {
+      exposure: 0.0,
+      tonemap: Tonemap::Reinhard { white: f64::INFINITY },
+      encoding: Encoding::Srgb,
+    }
+  }
+
+  pub fn to_ldr(&self, pixels: &[f64]) -> Vec<u8> {
+    let scale = 2.0f64.powf(self.exposure);
+    let mut ret = Vec::with_capacity(pixels.len());
+    for pixel in pixels.chunks(3) {
+      let c = self.tonemap.apply(&(Vec3::new(pixel[0], pixel[1], pixel[2]) * scale));
+      for i in 0..3 {
+        ret.push((self.encoding.encode(c[i]) * 255.0 + 0.5) as u8);
+      }
+    }
+    ret
+  }
+}
+
+pub fn write_image(path: &Path, width: u32, height: u32, pixels: &[f64], format: ImageFormat, ldr: &LdrSettings) -> io::Result<()> {
+  assert_eq!(pixels.len(), (width * height * 3) as usize);
+  let mut f = BufWriter::new(File::create(path)?);
+  match format {
+    ImageFormat::Pfm => write_pfm(&mut f, width, height, pixels)?,
+    ImageFormat::Ppm => {
+      write!(f, "P6\n{} {}\n255\n", width, height)?;
+      f.write_all(&ldr.to_ldr(pixels))?;
+    },
+    ImageFormat::Png => PNGEncoder::new(&mut f).encode(&ldr.to_ldr(pixels), width, height, ColorType::RGB(8))?,
+    ImageFormat::Jpeg => JPEGEncoder::new_with_quality(&mut f, 95).encode(&ldr.to_ldr(pixels), width, height, ColorType::RGB(8))?,
+    ImageFormat::Exr(pixel_type) => {
+      let mut exr = ExrImage::new(width, height);
+      exr.add_rgb("", pixels);
+      exr.write(&mut f, pixel_type)?;
+    },
+  }
+  f.flush()
+}
+
+// PFM stores rows bottom to top, a negative scale marks little endian data.
+pub fn write_pfm<W: Write>(w: &mut W, width: u32, height: u32, pixels: &[f64]) -> io::Result<()> {
+  write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+  let mut bytes = vec![0; width as usize * 3 * 4];
+  for row in pixels.chunks(width as usize * 3).rev() {
+    for (value, out) in row.iter().zip(bytes.chunks_mut(4)) {
+      LittleEndian::write_f32(out, *value as f32);
+    }
+    w.write_all(&bytes)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use output::*;
+
+  #[test]
+  fn test_pfm_rows() {
+    // Two rows, the top one red and the bottom one blue.
+    let pixels = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+    let mut data = Vec::new();
+    write_pfm(&mut data, 1, 2, &pixels).unwrap();
+    let header = b"PF\n1 2\n-1.0\n";
+    assert_eq!(&data[..header.len()], &header[..]);
+    let floats: Vec<f32> = data[header.len()..].chunks(4).map(LittleEndian::read_f32).collect();
+    assert_eq!(floats, vec![0.0, 0.0, 1.0, 1.0, 0.0, 0.0]);
+  }
+
+  #[test]
+  fn test_ldr() {
+    let reinhard = Tonemap::from_name("reinhard").unwrap();
+    assert_eq!(reinhard.apply(&Vec3::one()), Vec3::one() * 0.5);
+    assert_eq!(reinhard.apply(&Vec3::zero()), Vec3::zero());
+    assert_eq!(Tonemap::Reinhard { white: 1.0 }.apply(&Vec3::one()), Vec3::one());
+
+    let mut ldr = LdrSettings::new();
+    ldr.tonemap = Tonemap::Clamp;
+    assert_eq!(ldr.to_ldr(&[-1.0, 0.5, 4.0]), vec![0, 188, 255]);
+    ldr.encoding = Encoding::Gamma(2.0);
+    ldr.exposure = -1.0;
+    assert_eq!(ldr.to_ldr(&[0.5, 2.0, 0.0]), vec![128, 255, 0]);
+  }
+}
@@ -9,12 +9,12 @@ pub struct Perlin {
 }
 
 impl Perlin {
-  pub fn new() -> Perlin {
+  pub fn new(rng: &mut Rng) -> Perlin {
     Perlin {
-      ran_vec: generate(),
-      perm_x: generate_perm(),
-      perm_y: generate_perm(),
-      perm_z: generate_perm(),
+      ran_vec: generate(rng),
+      perm_x: generate_perm(rng),
+      perm_y: generate_perm(rng),
+      perm_z: generate_perm(rng),
     }
   }
 
@@ -71,22 +71,22 @@ fn perlin_interp(c: &[[[Vec3; 2]; 2]; 2], u: f64, v: f64, w: f64) -> f64 {
   accum
 }
 
-fn generate() -> Vec<Vec3> {
+fn generate(rng: &mut Rng) -> Vec<Vec3> {
   let mut ret: Vec<Vec3> = Vec::with_capacity(256);
   for _i in 0..256 {
-    ret.push(Vec3::new(-1.0 + 2.0 * rand_f64(), -1.0 + 2.0 * rand_f64(), -1.0 + 2.0 * rand_f64()).normalized());
+    ret.push(Vec3::new(-1.0 + 2.0 * rng.rand_f64(), -1.0 + 2.0 * rng.rand_f64(), -1.0 + 2.0 * rng.rand_f64()).normalized());
     // ret.push(Vec3::new(0.0, 0.25, 0.0));
   }
   ret
 }
 
-fn generate_perm() -> Vec<usize> {
+fn generate_perm(rng: &mut Rng) -> Vec<usize> {
   let mut ret: Vec<usize> = Vec::with_capacity(256);
   for i in 0..256 {
     ret.push(i)
   }
   for i in 0..256 {
-    let target = rand_usize() & 255;
+    let target = rng.rand_usize() & 255;
     ret.swap(i, target);
   }
   ret
@@ -1,10 +1,17 @@
 use vec3::Vec3;
+use spectrum::Wavelengths;
 
 #[derive(Debug)]
 pub struct Ray {
   pub origin: Vec3,
   pub direction: Vec3,
   pub time: f64,
+  // Componentwise 1 / direction for box tests, infinite along axes the ray
+  // doesn't move on.
+  pub inv_direction: Vec3,
+  // What the channels of colors along a spectral path stand for, None when
+  // rendering RGB.
+  pub wavelengths: Option<Wavelengths>,
 }
 
 impl Ray {
@@ -12,11 +19,21 @@ impl Ray {
     Ray {
       origin,
       direction,
-      time
+      time,
+      inv_direction: Vec3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z),
+      wavelengths: None
     }
   }
 
   pub fn point_at_parameter(&self, t: f64) -> Vec3 {
     self.origin + self.direction * t
   }
+
+  // An RGB color as this ray's path sees it.
+  pub fn spectrum(&self, rgb: &Vec3) -> Vec3 {
+    match self.wavelengths {
+      Some(ref wavelengths) => wavelengths.from_rgb(rgb),
+      None => *rgb,
+    }
+  }
 }
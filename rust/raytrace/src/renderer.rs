@@ -1,61 +1,199 @@
 use camera::Camera;
-use hitable::HitablePtr;
+use hitable::{HitablePtr, HitRecord, collect_lights};
 use scenes::*;
 use vec3::Vec3;
 use ray::Ray;
-use rt_rand::*;
+use rt_rand::Rng;
+use sampler::*;
+use spectrum::SpectralFilm;
 
 pub struct Renderer {
   scene: HitablePtr,
+  // Emissive rects and spheres, sampled directly at every diffuse bounce.
+  lights: Vec<HitablePtr>,
   camera: Camera,
   nx: u32,
   ny: u32,
   num_samples: u32,
-  default_sky: bool
+  default_sky: bool,
+  // Paths always survive `min_bounces` scattering events, then face Russian
+  // roulette, and never go past `max_bounces`.
+  min_bounces: u32,
+  max_bounces: u32,
+  // Every pixel sample draws from its own sampler derived from the seed, so a
+  // render only depends on the seed and never on thread scheduling.
+  seed: u64,
+  sampler: SamplerType,
+  blue_noise: Option<BlueNoiseMask>,
+  // Traces sampled wavelengths instead of RGB when set.
+  spectral: Option<SpectralFilm>,
 }
 
+// Relative slack on where a shadow ray meets its light, which the scene may
+// find a few ulps off through the wrappers around the light.
+const SHADOW_MARGIN: f64 = 1e-9;
+
 impl Renderer {
-  // TODO: Pick scene externally.
   pub fn new(nx: u32, ny: u32, ns: u32) -> Renderer {
-    let (scene, camera, default_sky) = scene_final(nx, ny);
+    Renderer::with_scene(nx, ny, ns, "final", 0).unwrap()
+  }
+
+  // The scene seed lays out the random scenes, it's kept apart from the seed
+  // of the sampling so renders with other samples show the same scene.
+  pub fn with_scene(nx: u32, ny: u32, ns: u32, scene_name: &str, scene_seed: u64) -> Option<Renderer> {
+    let (scene, camera, default_sky) = scene_by_name(scene_name, nx, ny, &mut Rng::new(scene_seed))?;
+    Some(Renderer::from_scene(nx, ny, ns, scene, camera, default_sky))
+  }
+
+  pub fn from_scene(nx: u32, ny: u32, ns: u32, scene: HitablePtr, camera: Camera, default_sky: bool) -> Renderer {
+    let mut lights = Vec::new();
+    collect_lights(&scene, &mut lights);
     Renderer {
-      scene, camera, nx, ny, num_samples: ns, default_sky
+      scene, lights, camera, nx, ny, num_samples: ns, default_sky,
+      min_bounces: 3,
+      max_bounces: 50,
+      seed: 0,
+      sampler: SamplerType::Sobol,
+      blue_noise: None,
+      spectral: None
     }
   }
 
+  pub fn set_sampler(&mut self, sampler: SamplerType) {
+    if sampler == SamplerType::BlueNoise && self.blue_noise.is_none() {
+      self.blue_noise = Some(BlueNoiseMask::new(64, &mut Rng::new(0)));
+    }
+    self.sampler = sampler;
+  }
+
+  pub fn set_spectral(&mut self, spectral: bool) {
+    self.spectral = if spectral {
+      Some(SpectralFilm::new())
+    } else {
+      None
+    };
+  }
+
+  pub fn set_seed(&mut self, seed: u64) {
+    self.seed = seed;
+  }
+
+  pub fn set_bounces(&mut self, min_bounces: u32, max_bounces: u32) {
+    assert!(min_bounces <= max_bounces, "min_bounces must not exceed max_bounces!");
+    self.min_bounces = min_bounces;
+    self.max_bounces = max_bounces;
+  }
+
+  pub fn num_lights(&self) -> usize {
+    self.lights.len()
+  }
+
+  pub fn num_samples(&self) -> u32 {
+    self.num_samples
+  }
+
   pub fn pixel_color(&self, i: u32, j: u32) -> Vec3 {
+    self.sample_sum(i, j, 0, self.num_samples).0 / self.num_samples as f64
+  }
+
+  // The sum of samples `first` to `first + count` of a pixel, so a render can
+  // be split into passes that together take the same samples as one go. The
+  // per channel sum of their squares comes along for variance estimates.
+  pub fn sample_sum(&self, i: u32, j: u32, first: u32, count: u32) -> (Vec3, Vec3) {
+    assert!(first + count <= self.num_samples, "Sample range out of bounds!");
     let mut c = Vec3::zero();
-    for _ in 0..self.num_samples {
-        let u = ((i as f64) + rand_f64()) / self.nx as f64;
-        let v = ((j as f64) + rand_f64()) / self.ny as f64;
-        let r = self.camera.get_ray(u, v);
-        let p = self.color(&r, &self.scene, 0);
+    let mut c_sq = Vec3::zero();
+    let pixel = j as u64 * self.nx as u64 + i as u64;
+    for s in first..first + count {
+        let p = match self.sampler {
+            SamplerType::Random => self.sample(i, j, &mut RandomSampler::new(self.seed, pixel, s)),
+            SamplerType::Stratified => self.sample(i, j, &mut StratifiedSampler::new(self.seed, pixel, s, self.num_samples)),
+            SamplerType::Sobol => self.sample(i, j, &mut SobolSampler::new(self.seed, pixel, s)),
+            SamplerType::BlueNoise => {
+                let mask = self.blue_noise.as_ref().expect("Blue noise mask missing!");
+                self.sample(i, j, &mut BlueNoiseSampler::new(mask, self.seed, i, j, s))
+            }
+        };
         c = c + p;
+        c_sq = c_sq + p * p;
     }
-    c = c / self.num_samples as f64;
-    c
+    (c, c_sq)
   }
 
-  pub fn tonemap(&self, c: &Vec3) -> Vec3 {
-    let lum = 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
-    let mapped = (lum * (1.0 + (lum / 1.0))) / (lum + 1.0);
-    let scale = mapped / lum;
-
-    *c * scale
+  fn sample(&self, i: u32, j: u32, sampler: &mut dyn Sampler) -> Vec3 {
+    let (du, dv) = sampler.get_2d();
+    let u = ((i as f64) + du) / self.nx as f64;
+    let v = ((j as f64) + dv) / self.ny as f64;
+    let mut r = self.camera.get_ray(u, v, sampler);
+    match self.spectral {
+      Some(ref film) => {
+        let wavelengths = film.sample_wavelengths(sampler.get_1d());
+        r.wavelengths = Some(wavelengths);
+        film.to_rgb(&wavelengths, &self.color(&r, sampler))
+      },
+      None => self.color(&r, sampler),
+    }
   }
 
-  fn color(&self, r: &Ray, scene: &HitablePtr, depth: u32) -> Vec3 {
-    if let Some(scene_hit) = scene.hit(r, 0.001, std::f64::MAX) {
-        if depth >= 50 {
-            return Vec3::zero();
+  fn color(&self, r: &Ray, sampler: &mut dyn Sampler) -> Vec3 {
+    let mut radiance = Vec3::zero();
+    let mut throughput = Vec3::one();
+    let mut ray = Ray::new(r.origin, r.direction, r.time);
+    ray.wavelengths = r.wavelengths;
+    // Density the last diffuse bounce picked `ray` with, emission found this
+    // way is weighted against light sampling. None for the camera ray and
+    // after specular bounces.
+    let mut bsdf_pdf = None;
+    let mut bounces = 0;
+    loop {
+        let scene_hit = match self.scene.hit(&ray, 0.001, f64::MAX) {
+            Some(hit) => hit,
+            None => {
+                radiance = radiance + throughput * ray.spectrum(&self.background(&ray));
+                break;
+            }
+        };
+        let mut emitted = ray.spectrum(&scene_hit.material.emit(scene_hit.u, scene_hit.v, &scene_hit.p));
+        if let Some(pdf) = bsdf_pdf {
+            if emitted != Vec3::zero() {
+                emitted = emitted * power_heuristic(pdf, self.light_pdf(&ray, scene_hit.t));
+            }
+        }
+        radiance = radiance + throughput * emitted;
+        if bounces >= self.max_bounces {
+            break;
         }
-        let emitted = scene_hit.material.emit(scene_hit.u, scene_hit.v, &scene_hit.p);
-        if let Some(scatter) = scene_hit.material.scatter(&r, &scene_hit) {
-            return emitted + (scatter.attenuation * self.color(&scatter.scattered, scene, depth+1));
+        let scatter = match scene_hit.material.scatter(&ray, &scene_hit, sampler) {
+            Some(scatter) => scatter,
+            None => break
+        };
+        if scatter.specular {
+            bsdf_pdf = None;
         } else {
-            return emitted;
+            radiance = radiance + throughput * self.sample_light(&ray, &scene_hit, sampler);
+            bsdf_pdf = Some(scatter.pdf);
+        }
+        throughput = throughput * scatter.attenuation;
+        // Materials only hand on wavelengths when they change them.
+        let wavelengths = ray.wavelengths;
+        ray = scatter.scattered;
+        if ray.wavelengths.is_none() {
+            ray.wavelengths = wavelengths;
+        }
+        bounces += 1;
+        if bounces >= self.min_bounces {
+            // Dim paths are likely to stop, survivors carry the lost energy.
+            let survival = throughput.x.max(throughput.y).max(throughput.z).min(1.0);
+            if survival <= 0.0 || sampler.get_1d() >= survival {
+                break;
+            }
+            throughput = throughput / survival;
         }
     }
+    radiance
+  }
+
+  fn background(&self, r: &Ray) -> Vec3 {
     if !self.default_sky {
       Vec3::zero()
     } else {
@@ -64,4 +202,195 @@ impl Renderer {
       (1.0 - t)*Vec3::one() + t*Vec3::new(0.5, 0.7, 1.0)
     }
   }
-}
\ No newline at end of file
+
+  // Density of light sampling finding what `ray` first meets at `t`. Only
+  // lights there count, light samples of those further along end up occluded
+  // and add nothing.
+  fn light_pdf(&self, ray: &Ray, t: f64) -> f64 {
+    if self.lights.is_empty() {
+      return 0.0;
+    }
+    let sum: f64 = self.lights.iter()
+      .filter(|light| light.hit(ray, 0.001, t * (1.0 + SHADOW_MARGIN)).is_some())
+      .map(|light| light.pdf_value(&ray.origin, &ray.direction, ray.time))
+      .sum();
+    sum / self.lights.len() as f64
+  }
+
+  // Direct light at `hit` from one light picked uniformly, weighted against
+  // the material picking the same direction. The shadow ray only has to
+  // tell whether anything is in the way, so it's an occlusion test.
+  fn sample_light(&self, r: &Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Vec3 {
+    if self.lights.is_empty() {
+      return Vec3::zero();
+    }
+    let index = ((sampler.get_1d() * self.lights.len() as f64) as usize).min(self.lights.len() - 1);
+    let light = &self.lights[index];
+    let shadow_ray = Ray::new(hit.p, light.random(&hit.p, r.time, sampler), r.time);
+    let light_hit = match light.hit(&shadow_ray, 0.001, f64::MAX) {
+      Some(light_hit) => light_hit,
+      None => return Vec3::zero()
+    };
+    let pdf = self.light_pdf(&shadow_ray, light_hit.t);
+    if pdf <= 0.0 {
+      return Vec3::zero();
+    }
+    let f = hit.material.eval(r, hit, &shadow_ray.direction);
+    if f == Vec3::zero() || self.scene.occluded(&shadow_ray, 0.001, light_hit.t * (1.0 - SHADOW_MARGIN)) {
+      return Vec3::zero();
+    }
+    let emitted = r.spectrum(&light_hit.material.emit(light_hit.u, light_hit.v, &light_hit.p));
+    let weight = power_heuristic(pdf, hit.material.pdf(r, hit, &shadow_ray.direction));
+    f * emitted * (weight / pdf)
+  }
+}
+
+// Veach's power heuristic with an exponent of two.
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+  let a = pdf * pdf;
+  let b = other_pdf * other_pdf;
+  if a + b > 0.0 {
+    a / (a + b)
+  } else {
+    0.0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::sync::Arc;
+  use vec3::Vec3;
+  use ray::Ray;
+  use camera::Camera;
+  use hitable::{Bvh, FlipNormals, HitablePtr, HitableList, Rect, RotateY, Sphere, Transform, Translate};
+  use mat4::Mat4;
+  use sampler::RandomSampler;
+  use material::{DiffuseLight, Lambertian};
+  use texture::ConstantTexture;
+  use scenes::scene_names;
+  use renderer::{Renderer, power_heuristic};
+
+  #[test]
+  fn test_named_scenes() {
+    for name in scene_names() {
+      assert!(Renderer::with_scene(4, 4, 1, name, 0).is_some(), "scene {} failed to build", name);
+    }
+    assert!(Renderer::with_scene(4, 4, 1, "no_such_scene", 0).is_none());
+  }
+
+  #[test]
+  fn test_lights() {
+    assert_eq!(Renderer::with_scene(4, 4, 1, "cornell", 0).unwrap().num_lights(), 1);
+    assert_eq!(Renderer::with_scene(4, 4, 1, "simple_light", 0).unwrap().num_lights(), 2);
+    assert_eq!(Renderer::with_scene(4, 4, 1, "final", 0).unwrap().num_lights(), 1);
+    assert_eq!(Renderer::with_scene(4, 4, 1, "random", 0).unwrap().num_lights(), 0);
+  }
+
+  #[test]
+  fn test_instanced_lights() {
+    // A lamp with a light in it, placed twice, brings two lights along and
+    // both are sampled where their instance put them.
+    let light = Rect::xzrect(-0.5, -0.5, 0.5, 0.5, 1.0, DiffuseLight::rc(ConstantTexture::rc(Vec3::one())));
+    let shade = Sphere::hitable_ptr(Vec3::zero(), 0.5, Lambertian::rc(ConstantTexture::rc(Vec3::one())));
+    let lamp: HitablePtr = Arc::new(Bvh::new(vec![light, shade], 0.0, 1.0));
+    let camera = || Camera::new(&Vec3::new(0.0, 0.0, 3.0), &Vec3::zero(), &Vec3::new(0.0, 1.0, 0.0), 20.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+    let mut list = HitableList::new();
+    list.add_hitable(Arc::clone(&lamp));
+    assert_eq!(Renderer::from_scene(4, 4, 1, Arc::new(list), camera(), false).num_lights(), 1);
+    let mut list = HitableList::new();
+    list.add_hitable(Transform::hitable_ptr(Arc::clone(&lamp), Mat4::translate(&Vec3::new(-3.0, 0.0, 0.0))));
+    list.add_hitable(Translate::hitable_ptr(RotateY::hitable_ptr(FlipNormals::hitable_ptr(lamp), 90.0), Vec3::new(3.0, 0.0, 0.0)));
+    let renderer = Renderer::from_scene(4, 4, 1, Arc::new(list), camera(), false);
+    assert_eq!(renderer.num_lights(), 2);
+    let origin = Vec3::new(0.0, 5.0, 0.0);
+    let mut sampler = RandomSampler::new(0, 0, 0);
+    for (light, x) in renderer.lights.iter().zip([-3.0, 3.0].iter()) {
+      let direction = light.random(&origin, 0.0, &mut sampler);
+      let p = origin + direction;
+      assert!((p.y - 1.0).abs() < 1e-9 && (p.x - x).abs() <= 0.5, "{:?}", p);
+      assert!(light.pdf_value(&origin, &direction, 0.0) > 0.0);
+    }
+  }
+
+  #[test]
+  fn test_roulette() {
+    // A convex diffuse object lit by a uniform enclosure reflects its albedo
+    // times the emission, however early the paths get cut.
+    let camera = Camera::new(&Vec3::new(0.0, 0.0, 3.0), &Vec3::zero(), &Vec3::new(0.0, 1.0, 0.0), 20.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+    let mut list = HitableList::new();
+    list.add_hitable(Sphere::hitable_ptr(Vec3::zero(), 10.0, DiffuseLight::rc(ConstantTexture::rc(Vec3::one()))));
+    list.add_hitable(Sphere::hitable_ptr(Vec3::zero(), 1.0, Lambertian::rc(ConstantTexture::rc(Vec3::one() * 0.5))));
+    let mut renderer = Renderer::from_scene(8, 8, 4000, Arc::new(list), camera, false);
+    renderer.set_bounces(0, 50);
+    let c = renderer.pixel_color(4, 4);
+    for i in 0..3 {
+      assert!((c[i] - 0.5).abs() < 0.03, "{:?}", c);
+    }
+  }
+
+  #[test]
+  fn test_spectral() {
+    // Colors come out of a spectral render as they went in, on average.
+    let camera = Camera::new(&Vec3::new(0.0, 0.0, 3.0), &Vec3::zero(), &Vec3::new(0.0, 1.0, 0.0), 20.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+    let mut list = HitableList::new();
+    list.add_hitable(Sphere::hitable_ptr(Vec3::zero(), 10.0, DiffuseLight::rc(ConstantTexture::rc(Vec3::new(1.0, 0.9, 0.8)))));
+    list.add_hitable(Sphere::hitable_ptr(Vec3::zero(), 1.0, Lambertian::rc(ConstantTexture::rc(Vec3::new(0.8, 0.4, 0.1)))));
+    let mut renderer = Renderer::from_scene(8, 8, 4000, Arc::new(list), camera, false);
+    renderer.set_spectral(true);
+    renderer.set_bounces(1, 1);
+    let c = renderer.pixel_color(4, 4);
+    let expected = Vec3::new(0.8, 0.36, 0.08);
+    for i in 0..3 {
+      assert!((c[i] - expected[i]).abs() < 0.03, "{:?}", c);
+    }
+  }
+
+  fn seeded(scene_seed: u64, seed: u64) -> Renderer {
+    let mut renderer = Renderer::with_scene(8, 8, 4, "random", scene_seed).unwrap();
+    renderer.set_seed(seed);
+    renderer
+  }
+
+  // Where the scene has its surfaces, looking straight down on a grid.
+  fn probe(renderer: &Renderer) -> Vec<Option<f64>> {
+    (0..256).map(|i| {
+      let origin = Vec3::new((i % 16) as f64 * 0.6 - 4.5, 5.0, (i / 16) as f64 * 0.6 - 4.5);
+      let ray = Ray::new(origin, Vec3::new(0.0, -1.0, 0.0), 0.0);
+      renderer.scene.hit(&ray, 0.001, f64::MAX).map(|hit| hit.t)
+    }).collect()
+  }
+
+  #[test]
+  fn test_reproducible() {
+    let a = seeded(7, 7);
+    let b = seeded(7, 7);
+    let c = seeded(7, 8);
+    assert_eq!(a.pixel_color(3, 2), b.pixel_color(3, 2));
+    assert!(a.pixel_color(3, 2) != c.pixel_color(3, 2));
+    // The sampling seed leaves the scene alone, the scene seed doesn't.
+    assert_eq!(probe(&a), probe(&c));
+    assert!(probe(&a) != probe(&seeded(8, 7)));
+  }
+
+  #[test]
+  fn test_power_heuristic() {
+    assert_eq!(power_heuristic(2.0, 0.0), 1.0);
+    assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+    assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+    assert_eq!(power_heuristic(1.0, 3.0) + power_heuristic(3.0, 1.0), 1.0);
+  }
+
+  #[test]
+  fn test_from_scene() {
+    let look_from = Vec3::zero();
+    let look_at = Vec3::new(0.0, 0.0, -1.0);
+    let camera = Camera::new(&look_from, &look_at, &Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+    let emission = Vec3::new(0.25, 0.5, 1.0);
+    let enclosure = Sphere::hitable_ptr(Vec3::zero(), 10.0, DiffuseLight::rc(ConstantTexture::rc(emission)));
+    let mut renderer = Renderer::from_scene(8, 8, 4, enclosure, camera, true);
+    assert_eq!(renderer.pixel_color(3, 5), emission);
+    renderer.set_bounces(0, 0);
+    assert_eq!(renderer.pixel_color(3, 5), emission);
+  }
+}
@@ -1,48 +1,73 @@
 extern crate rand;
+extern crate rand_pcg;
 
-use vec3::Vec3;
+use rt_rand::rand::RngCore;
+use rt_rand::rand_pcg::Pcg32;
 
-use rt_rand::rand::random;
+// All randomness goes through an explicit Rng so renders are reproducible.
+// The renderer seeds one per pixel sample, which makes the result independent
+// of which thread happens to trace it.
+pub struct Rng {
+  pcg: Pcg32,
+}
+
+impl Rng {
+  pub fn new(seed: u64) -> Rng {
+    Rng {
+      pcg: Pcg32::new(mix(seed), 0x0a02_bdbf_7bb3_c0a7)
+    }
+  }
+
+  pub fn for_sample(seed: u64, pixel: u64, sample: u64) -> Rng {
+    Rng::new(mix(mix(seed ^ mix(pixel)) ^ sample))
+  }
+
+  // Uniform in [0, 1).
+  pub fn rand_f64(&mut self) -> f64 {
+    (self.pcg.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+  }
+
+  pub fn rand_usize(&mut self) -> usize {
+    self.pcg.next_u64() as usize
+  }
+}
 
-pub fn rand_f64() -> f64 {
-  return random::<f64>()
+// The splitmix64 finalizer, scrambles all the bits of `z`.
+pub fn mix(z: u64) -> u64 {
+  let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  z ^ (z >> 31)
 }
 
-pub fn rand_usize() -> usize {
-  return random::<usize>()
+const HASH_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+pub fn hash(values: &[u64]) -> u64 {
+  values.iter().fold(HASH_SEED, |h, v| mix(h ^ v))
 }
 
-// thread_local! {
-//   static PCG: RefCell<rand_pcg::Mcg128Xsl64> = RefCell::new(rand_pcg::Mcg128Xsl64::new(2084));
-// }
-
-// pub fn rand_f64() -> f64 {
-//   PCG.with(|pcg| {
-//     pcg.borrow_mut().next_u64() as f64 / (std::u64::MAX as f64)
-//   })
-// }
-
-// pub fn rand_usize() -> usize {
-//   PCG.with(|pcg| {
-//     pcg.borrow_mut().next_u64() as usize
-//   })
-// }
-
-pub fn random_in_unit_disk() -> Vec3 {
-  loop {
-    let p = 2.0 * Vec3::new(rand_f64(), rand_f64(), 0.0) - Vec3::new(1.0, 1.0, 0.0);
-    if Vec3::dot(&p, &p) < 1.0  {
-      break p;
-    }
-  }
+// A uniform value in [0, 1) derived from `values`, for the rare places that
+// have no sampler at hand but must still be deterministic.
+pub fn hash_f64(values: &[f64]) -> f64 {
+  let h = values.iter().fold(HASH_SEED, |h, v| mix(h ^ v.to_bits()));
+  (h >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
 }
 
-pub fn random_in_unit_sphere() -> Vec3 {
-    // Generate a vector with spherical coords y0
-    loop {
-        let p = 2.0 * Vec3::new(rand_f64(), rand_f64(), rand_f64()) - Vec3::one();
-        if Vec3::dot(&p, &p) < 1.0 {
-            break p;
-        }
-    }
+#[cfg(test)]
+mod tests {
+
+  use rt_rand::*;
+
+  #[test]
+  fn test_reproducible() {
+    let mut rng = Rng::for_sample(1, 2, 3);
+    let a: Vec<f64> = (0..8).map(|_| rng.rand_f64()).collect();
+    let mut rng = Rng::for_sample(1, 2, 3);
+    let b: Vec<f64> = (0..8).map(|_| rng.rand_f64()).collect();
+    assert_eq!(a, b);
+    assert!(a.iter().all(|v| *v >= 0.0 && *v < 1.0));
+    // Neighbouring pixels, samples and seeds all get their own sequence.
+    assert!(Rng::for_sample(1, 2, 3).rand_f64() != Rng::for_sample(1, 3, 3).rand_f64());
+    assert!(Rng::for_sample(1, 2, 3).rand_f64() != Rng::for_sample(1, 2, 4).rand_f64());
+    assert!(Rng::for_sample(1, 2, 3).rand_f64() != Rng::for_sample(2, 2, 3).rand_f64());
+  }
 }
@@ -0,0 +1,557 @@
+use vec3::Vec3;
+use rt_rand::*;
+
+// Samplers hand out the random numbers for a single pixel sample, one
+// dimension at a time. The camera, lights and materials pull what they need
+// in a fixed order, so the low discrepancy samplers line the same dimension
+// up across the samples of a pixel. All of them are stateless hashes of the
+// seed, pixel, sample index and dimension, so samples can be taken in any
+// order.
+pub trait Sampler {
+  // Uniform in [0, 1).
+  fn get_1d(&mut self) -> f64;
+  fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerType {
+  Random,
+  Stratified,
+  Sobol,
+  BlueNoise,
+}
+
+impl SamplerType {
+  pub fn from_name(name: &str) -> Option<SamplerType> {
+    match name.to_lowercase().as_str() {
+      "random" => Some(SamplerType::Random),
+      "stratified" => Some(SamplerType::Stratified),
+      "sobol" => Some(SamplerType::Sobol),
+      "blue-noise" | "bluenoise" => Some(SamplerType::BlueNoise),
+      _ => None
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match *self {
+      SamplerType::Random => "random",
+      SamplerType::Stratified => "stratified",
+      SamplerType::Sobol => "sobol",
+      SamplerType::BlueNoise => "blue-noise",
+    }
+  }
+}
+
+// Independent uniform randoms, the baseline the others are measured against.
+pub struct RandomSampler {
+  rng: Rng,
+}
+
+impl RandomSampler {
+  pub fn new(seed: u64, pixel: u64, index: u32) -> RandomSampler {
+    RandomSampler {
+      rng: Rng::for_sample(seed, pixel, index as u64)
+    }
+  }
+}
+
+impl Sampler for RandomSampler {
+  fn get_1d(&mut self) -> f64 {
+    self.rng.rand_f64()
+  }
+
+  fn get_2d(&mut self) -> (f64, f64) {
+    (self.rng.rand_f64(), self.rng.rand_f64())
+  }
+}
+
+// Kensler's correlated multi-jittered sampling. Every dimension of the
+// `count` samples in a pixel is stratified on its own, 2D pairs are jittered
+// on an m x n grid, and each dimension gets its own shuffle so they don't
+// correlate.
+pub struct StratifiedSampler {
+  seed: u64,
+  pixel: u64,
+  index: u32,
+  count: u32,
+  dimension: u64,
+}
+
+impl StratifiedSampler {
+  pub fn new(seed: u64, pixel: u64, index: u32, count: u32) -> StratifiedSampler {
+    assert!(index < count, "Sample index out of range!");
+    StratifiedSampler {
+      seed,
+      pixel,
+      index,
+      count,
+      dimension: 0
+    }
+  }
+
+  fn pattern(&mut self) -> u32 {
+    self.dimension += 1;
+    hash(&[self.seed, self.pixel, self.dimension]) as u32
+  }
+}
+
+impl Sampler for StratifiedSampler {
+  fn get_1d(&mut self) -> f64 {
+    let p = self.pattern();
+    let stratum = permute(self.index, self.count, p);
+    (stratum as f64 + hash_to_unit(self.index, p.wrapping_mul(0x967a_889b))) / self.count as f64
+  }
+
+  fn get_2d(&mut self) -> (f64, f64) {
+    let p = self.pattern();
+    let n = self.count;
+    let m = ((n as f64).sqrt() as u32).max(1);
+    let rows = n.div_ceil(m);
+    let s = permute(self.index, n, p.wrapping_mul(0x5163_3e2d));
+    let sx = permute(s % m, m, p.wrapping_mul(0x68bc_21eb));
+    let sy = permute(s / m, rows, p.wrapping_mul(0x02e5_be93));
+    let jx = hash_to_unit(s, p.wrapping_mul(0x967a_889b));
+    let jy = hash_to_unit(s, p.wrapping_mul(0x368c_c8b7));
+    let x = (sx as f64 + (sy as f64 + jx) / rows as f64) / m as f64;
+    let y = (s as f64 + jy) / n as f64;
+    (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+  }
+}
+
+// The first two dimensions of the Sobol sequence with Burley's hash based
+// Owen scrambling. Each dimension pair shuffles the sample order and
+// scrambles the points with its own seed, which keeps the pairs from
+// correlating while every pair stays a (0, 2)-sequence.
+pub struct SobolSampler {
+  seed: u64,
+  index: u32,
+  dimension: u64,
+}
+
+impl SobolSampler {
+  pub fn new(seed: u64, pixel: u64, index: u32) -> SobolSampler {
+    SobolSampler {
+      seed: hash(&[seed, pixel]),
+      index,
+      dimension: 0
+    }
+  }
+}
+
+impl Sampler for SobolSampler {
+  fn get_1d(&mut self) -> f64 {
+    self.get_2d().0
+  }
+
+  fn get_2d(&mut self) -> (f64, f64) {
+    self.dimension += 1;
+    shuffled_scrambled_sobol_2d(self.index, hash(&[self.seed, self.dimension]))
+  }
+}
+
+// Owen scrambled Sobol points shared by every pixel, each pixel shifts them
+// by a value from a blue noise mask (Cranley-Patterson rotation). Neighbouring
+// pixels get very different shifts, so at low sample counts the error looks
+// like high frequency noise instead of blotches.
+pub struct BlueNoiseSampler<'a> {
+  mask: &'a BlueNoiseMask,
+  seed: u64,
+  x: u32,
+  y: u32,
+  index: u32,
+  dimension: u64,
+}
+
+impl<'a> BlueNoiseSampler<'a> {
+  pub fn new(mask: &'a BlueNoiseMask, seed: u64, x: u32, y: u32, index: u32) -> BlueNoiseSampler<'a> {
+    BlueNoiseSampler {
+      mask,
+      seed,
+      x,
+      y,
+      index,
+      dimension: 0
+    }
+  }
+
+  // Every dimension reads the mask at its own toroidal offset.
+  fn shift(&self, channel: u64) -> f64 {
+    let offset = hash(&[self.dimension, channel]);
+    let x = self.x as usize + (offset as u32) as usize;
+    let y = self.y as usize + (offset >> 32) as usize;
+    self.mask.value(x, y)
+  }
+}
+
+impl<'a> Sampler for BlueNoiseSampler<'a> {
+  fn get_1d(&mut self) -> f64 {
+    self.get_2d().0
+  }
+
+  fn get_2d(&mut self) -> (f64, f64) {
+    self.dimension += 1;
+    let (x, y) = shuffled_scrambled_sobol_2d(self.index, hash(&[self.seed, self.dimension]));
+    let x = x + self.shift(0);
+    let y = y + self.shift(1);
+    ((x - x.floor()).min(ONE_MINUS_EPSILON), (y - y.floor()).min(ONE_MINUS_EPSILON))
+  }
+}
+
+// A tileable blue noise texture of values evenly spread over [0, 1), made
+// with Ulichney's void and cluster method.
+pub struct BlueNoiseMask {
+  size: usize,
+  values: Vec<f64>,
+}
+
+impl BlueNoiseMask {
+  pub fn new(size: usize, rng: &mut Rng) -> BlueNoiseMask {
+    let n = size * size;
+    // Gaussian energy falloff for every toroidal offset.
+    let sigma = 1.5;
+    let mut kernel = vec![0.0; n];
+    for dy in 0..size {
+      for dx in 0..size {
+        let x = dx.min(size - dx) as f64;
+        let y = dy.min(size - dy) as f64;
+        kernel[dy * size + dx] = (-(x * x + y * y) / (2.0 * sigma * sigma)).exp();
+      }
+    }
+    let mut pattern = Pattern {
+      size,
+      kernel,
+      set: vec![false; n],
+      energy: vec![0.0; n],
+    };
+
+    // Start from a random tenth of the pixels and move the tightest cluster
+    // into the largest void until that stops changing anything, or until it
+    // has had plenty of chances to.
+    let initial = (n / 10).max(1);
+    let mut placed = 0;
+    while placed < initial {
+      let p = rng.rand_usize() % n;
+      if !pattern.set[p] {
+        pattern.toggle(p);
+        placed += 1;
+      }
+    }
+    for _ in 0..n {
+      let cluster = pattern.tightest_cluster();
+      pattern.toggle(cluster);
+      let void = pattern.largest_void();
+      pattern.toggle(void);
+      if void == cluster {
+        break;
+      }
+    }
+
+    let mut ranks = vec![0; n];
+    let prototype = pattern.set.clone();
+    let prototype_energy = pattern.energy.clone();
+    // Rank the initial points by taking out the tightest clusters first.
+    let mut rank = initial;
+    while rank > 0 {
+      let cluster = pattern.tightest_cluster();
+      pattern.toggle(cluster);
+      rank -= 1;
+      ranks[cluster] = rank;
+    }
+    // Then fill the largest voids up to half of the pixels.
+    pattern.set = prototype;
+    pattern.energy = prototype_energy;
+    rank = initial;
+    while rank < n / 2 {
+      let void = pattern.largest_void();
+      pattern.toggle(void);
+      ranks[void] = rank;
+      rank += 1;
+    }
+    // Past half the empty pixels are the minority, so fill the tightest
+    // clusters of empty pixels instead.
+    pattern.invert();
+    while rank < n {
+      let cluster = pattern.tightest_cluster();
+      pattern.toggle(cluster);
+      ranks[cluster] = rank;
+      rank += 1;
+    }
+
+    BlueNoiseMask {
+      size,
+      values: ranks.iter().map(|r| (*r as f64 + 0.5) / n as f64).collect(),
+    }
+  }
+
+  pub fn size(&self) -> usize {
+    self.size
+  }
+
+  pub fn value(&self, x: usize, y: usize) -> f64 {
+    self.values[(y % self.size) * self.size + x % self.size]
+  }
+}
+
+// Binary pattern with the summed kernel energy of its set pixels.
+struct Pattern {
+  size: usize,
+  kernel: Vec<f64>,
+  set: Vec<bool>,
+  energy: Vec<f64>,
+}
+
+impl Pattern {
+  fn toggle(&mut self, p: usize) {
+    let sign = if self.set[p] { -1.0 } else { 1.0 };
+    self.set[p] = !self.set[p];
+    let size = self.size;
+    let (px, py) = (p % size, p / size);
+    for y in 0..size {
+      let dy = (y + size - py) % size;
+      for x in 0..size {
+        let dx = (x + size - px) % size;
+        self.energy[y * size + x] += sign * self.kernel[dy * size + dx];
+      }
+    }
+  }
+
+  fn invert(&mut self) {
+    let size = self.size;
+    self.set = self.set.iter().map(|s| !s).collect();
+    self.energy = vec![0.0; size * size];
+    let set: Vec<usize> = (0..size * size).filter(|p| self.set[*p]).collect();
+    for p in set {
+      self.set[p] = false;
+      self.toggle(p);
+    }
+  }
+
+  fn tightest_cluster(&self) -> usize {
+    (0..self.set.len())
+      .filter(|p| self.set[*p])
+      .max_by(|a, b| self.energy[*a].partial_cmp(&self.energy[*b]).unwrap())
+      .unwrap()
+  }
+
+  fn largest_void(&self) -> usize {
+    (0..self.set.len())
+      .filter(|p| !self.set[*p])
+      .min_by(|a, b| self.energy[*a].partial_cmp(&self.energy[*b]).unwrap())
+      .unwrap()
+  }
+}
+
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// Kensler's hashed permutation of 0..len, different for every pattern.
+fn permute(index: u32, len: u32, pattern: u32) -> u32 {
+  let mut w = len.wrapping_sub(1);
+  w |= w >> 1;
+  w |= w >> 2;
+  w |= w >> 4;
+  w |= w >> 8;
+  w |= w >> 16;
+  let p = pattern;
+  let mut i = index;
+  loop {
+    i ^= p;
+    i = i.wrapping_mul(0xe170_893d);
+    i ^= p >> 16;
+    i ^= (i & w) >> 4;
+    i ^= p >> 8;
+    i = i.wrapping_mul(0x0929_eb3f);
+    i ^= p >> 23;
+    i ^= (i & w) >> 1;
+    i = i.wrapping_mul(1 | p >> 27);
+    i = i.wrapping_mul(0x6935_fa69);
+    i ^= (i & w) >> 11;
+    i = i.wrapping_mul(0x74dc_b303);
+    i ^= (i & w) >> 2;
+    i = i.wrapping_mul(0x9e50_1cc3);
+    i ^= (i & w) >> 2;
+    i = i.wrapping_mul(0xc860_a3df);
+    i &= w;
+    i ^= i >> 5;
+    if i < len {
+      break;
+    }
+  }
+  (i.wrapping_add(p)) % len
+}
+
+// Kensler's hash of `index` to [0, 1).
+fn hash_to_unit(index: u32, pattern: u32) -> f64 {
+  let p = pattern;
+  let mut i = index;
+  i ^= p;
+  i ^= i >> 17;
+  i ^= i >> 10;
+  i = i.wrapping_mul(0xb365_34e5);
+  i ^= i >> 12;
+  i ^= i >> 21;
+  i = i.wrapping_mul(0x93fc_4795);
+  i ^= 0xdf6e_307f;
+  i ^= i >> 17;
+  i = i.wrapping_mul(1 | p >> 18);
+  i as f64 / 4_294_967_808.0
+}
+
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+  let mut x = x.wrapping_add(seed);
+  x ^= x.wrapping_mul(0x6c50_b47c);
+  x ^= x.wrapping_mul(0xb82f_1e52);
+  x ^= x.wrapping_mul(0xc7af_e638);
+  x ^= x.wrapping_mul(0x8d22_f6e6);
+  x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+  laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Sobol dimension 0 is the bit reversed index, dimension 1 is generated by
+// the polynomial x + 1.
+fn sobol_2d(index: u32) -> (u32, u32) {
+  let mut y = 0;
+  let mut v = 1 << 31;
+  let mut i = index;
+  while i != 0 {
+    if i & 1 == 1 {
+      y ^= v;
+    }
+    v ^= v >> 1;
+    i >>= 1;
+  }
+  (index.reverse_bits(), y)
+}
+
+fn shuffled_scrambled_sobol_2d(index: u32, seed: u64) -> (f64, f64) {
+  let index = nested_uniform_scramble(index, seed as u32);
+  let (x, y) = sobol_2d(index);
+  let x = nested_uniform_scramble(x, hash(&[seed, 0]) as u32);
+  let y = nested_uniform_scramble(y, hash(&[seed, 1]) as u32);
+  let scale = 1.0 / 4_294_967_296.0;
+  (x as f64 * scale, y as f64 * scale)
+}
+
+// Warps from the unit square, these replace the old rejection sampling.
+
+// Shirley and Chiu's concentric mapping onto the unit disk in the xy plane.
+pub fn sample_unit_disk(u: (f64, f64)) -> Vec3 {
+  let a = 2.0 * u.0 - 1.0;
+  let b = 2.0 * u.1 - 1.0;
+  if a == 0.0 && b == 0.0 {
+    return Vec3::zero();
+  }
+  let quarter_pi = std::f64::consts::PI / 4.0;
+  let (r, phi) = if a.abs() > b.abs() {
+    (a, quarter_pi * (b / a))
+  } else {
+    (b, 2.0 * quarter_pi - quarter_pi * (a / b))
+  };
+  Vec3::new(r * phi.cos(), r * phi.sin(), 0.0)
+}
+
+// A uniformly distributed unit vector.
+pub fn sample_unit_sphere(u: (f64, f64)) -> Vec3 {
+  let z = 1.0 - 2.0 * u.0;
+  let r = (1.0 - z * z).max(0.0).sqrt();
+  let phi = 2.0 * std::f64::consts::PI * u.1;
+  Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// A point uniformly distributed inside the unit ball, `u_radius` picks the
+// distance from the center.
+pub fn sample_unit_ball(u: (f64, f64), u_radius: f64) -> Vec3 {
+  sample_unit_sphere(u) * u_radius.cbrt()
+}
+
+// Cosine weighted direction on the hemisphere around +z, its pdf is z / PI.
+pub fn sample_cosine_direction(u: (f64, f64)) -> Vec3 {
+  let phi = 2.0 * std::f64::consts::PI * u.0;
+  let r = u.1.sqrt();
+  Vec3::new(phi.cos() * r, phi.sin() * r, (1.0 - u.1).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use sampler::*;
+
+  // Fraction of the unit square's n x n cells holding exactly one sample.
+  fn stratification(points: &[(f64, f64)], n: usize) -> f64 {
+    let mut cells = vec![0; n * n];
+    for p in points {
+      cells[(p.1 * n as f64) as usize * n + (p.0 * n as f64) as usize] += 1;
+    }
+    cells.iter().filter(|c| **c == 1).count() as f64 / cells.len() as f64
+  }
+
+  #[test]
+  fn test_stratified() {
+    let count = 16;
+    let mut xs = Vec::new();
+    let mut points = Vec::new();
+    for i in 0..count {
+      let mut sampler = StratifiedSampler::new(1, 5, i, count);
+      xs.push(sampler.get_1d());
+      points.push(sampler.get_2d());
+    }
+    // One sample in every 1D stratum, and in every row and column of the grid.
+    let mut strata: Vec<usize> = xs.iter().map(|x| (x * count as f64) as usize).collect();
+    strata.sort();
+    assert_eq!(strata, (0..count as usize).collect::<Vec<usize>>());
+    assert_eq!(stratification(&points, 4), 1.0);
+  }
+
+  #[test]
+  fn test_sobol() {
+    // Owen scrambling keeps the (0, 2)-sequence property, any power of two
+    // prefix has one point in every elementary interval.
+    for dimension in 0..4 {
+      let points: Vec<(f64, f64)> = (0..64).map(|i| {
+        let mut sampler = SobolSampler::new(3, 11, i);
+        for _ in 0..dimension {
+          sampler.get_2d();
+        }
+        sampler.get_2d()
+      }).collect();
+      assert_eq!(stratification(&points[..16], 4), 1.0);
+      assert_eq!(stratification(&points, 8), 1.0);
+    }
+    // Different pixels get different points.
+    assert!(SobolSampler::new(3, 11, 0).get_2d() != SobolSampler::new(3, 12, 0).get_2d());
+  }
+
+  #[test]
+  fn test_blue_noise_mask() {
+    let mask = BlueNoiseMask::new(16, &mut Rng::new(0));
+    // Every rank shows up exactly once.
+    let mut values: Vec<f64> = (0..256).map(|i| mask.value(i % 16, i / 16)).collect();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    for (i, v) in values.iter().enumerate() {
+      assert_eq!(*v, (i as f64 + 0.5) / 256.0);
+    }
+    // Blue noise has little low frequency energy, neighbours differ by a lot
+    // more than they would in white noise, where the average is 1/3.
+    let mut difference = 0.0;
+    for y in 0..16 {
+      for x in 0..16 {
+        difference += (mask.value(x, y) - mask.value(x + 1, y)).abs();
+      }
+    }
+    assert!(difference / 256.0 > 0.4, "{}", difference / 256.0);
+  }
+
+  #[test]
+  fn test_warps() {
+    for i in 0..64 {
+      let mut sampler = SobolSampler::new(0, 0, i);
+      let d = sample_unit_disk(sampler.get_2d());
+      assert!(d.length() <= 1.0 && d.z == 0.0);
+      assert!((sample_unit_sphere(sampler.get_2d()).length() - 1.0).abs() < 1e-9);
+      assert!(sample_cosine_direction(sampler.get_2d()).z >= 0.0);
+      assert!(sample_unit_ball(sampler.get_2d(), sampler.get_1d()).length() <= 1.0);
+    }
+  }
+}
@@ -0,0 +1,635 @@
+// Loader for the plain text scene description format.
+//
+// A scene file is a list of statements, one per line. Blank lines are skipped
+// and everything after a '#' is a comment. Arguments are separated by
+// whitespace and may be wrapped in double quotes if they contain spaces.
+//
+//   camera look_from X Y Z look_at X Y Z [up X Y Z] [vfov DEG] [aperture A]
+//          [focus_dist D] [time T0 T1]
+//   sky gradient|black
+//   texture NAME constant R G B
+//   texture NAME checker ODD_TEXTURE EVEN_TEXTURE
+//   texture NAME noise SCALE
+//   texture NAME image PATH
+//   material NAME lambertian TEXTURE
+//   material NAME metal TEXTURE FUZZ
+//   material NAME microfacet BASE_COLOR ROUGHNESS METALNESS
+//            [transmission TEXTURE] [ior N]
+//     where all are textures that read their first channel but the base
+//     color. Transmission makes it rough glass of refractive index N, 1.5 by
+//     default
+//   material NAME principled BASE_COLOR [metallic TEXTURE]
+//            [roughness TEXTURE] [specular TEXTURE] [clearcoat TEXTURE]
+//            [clearcoat_roughness TEXTURE] [sheen TEXTURE]
+//            [transmission TEXTURE]
+//     with the same conventions, defaulting to a rough dielectric with 4%
+//     reflectance
+//   material NAME dielectric REF_INDEX [tint R G B] [absorption A]
+//     where light turns the tint color over 1 / A units inside, A defaults to
+//     1 with a tint and the glass is clear without. For dispersion in
+//     spectral renders REF_INDEX can also be cauchy A B or
+//     sellmeier B1 B2 B3 C1 C2 C3, with wavelengths in micrometers
+//   material NAME diffuse_light TEXTURE
+//   material NAME isotropic TEXTURE
+//   object NAME sphere X Y Z RADIUS MATERIAL
+//   object NAME moving_sphere X0 Y0 Z0 X1 Y1 Z1 T0 T1 RADIUS MATERIAL
+//   object NAME xy_rect X0 Y0 X1 Y1 Z MATERIAL
+//   object NAME xz_rect X0 Z0 X1 Z1 Y MATERIAL
+//   object NAME yz_rect Y0 Z0 Y1 Z1 X MATERIAL
+//   object NAME box X0 Y0 Z0 X1 Y1 Z1 MATERIAL
+//   object NAME triangle X0 Y0 Z0 X1 Y1 Z1 X2 Y2 Z2 MATERIAL
+//   object NAME plane X Y Z NORMAL_X NORMAL_Y NORMAL_Z MATERIAL
+//   object NAME obj PATH [MATERIAL]
+//   object NAME constant_medium BOUNDARY DENSITY TEXTURE
+//   object NAME translate OBJECT X Y Z
+//   object NAME rotate_y OBJECT DEGREES
+//   object NAME flip_normals OBJECT
+//   object NAME transform OBJECT STEP...
+//     where every STEP is one of translate X Y Z, scale X Y Z, rotate_x DEG,
+//     rotate_y DEG, rotate_z DEG or rotate AXIS_X AXIS_Y AXIS_Z DEG, applied
+//     in the order given
+//   object NAME group OBJECT...
+//   add OBJECT...
+//
+// Textures, materials and objects must be declared before they are used.
+// Declaring an object doesn't place it in the scene, `add` does. Relative
+// image and obj paths are resolved against the directory holding the scene
+// file. Transforming a group places a shared copy of it, so a group can be
+// instanced any number of times without duplicating its BVH. The scene and
+// every group are put in a BVH, planes are infinite and are tested
+// separately, so a few are fine but they don't scale.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use vec3::Vec3;
+use aabb::Aabb;
+use mat4::Mat4;
+use camera::Camera;
+use hitable::*;
+use material::*;
+use texture::*;
+use constant_medium::{ConstantMedium, Isotropic};
+use mesh::Triangle;
+use obj::load_obj;
+use rt_rand::Rng;
+
+#[derive(Debug)]
+pub enum SceneError {
+  Io(io::Error),
+  Parse { line: usize, message: String },
+  Invalid(String),
+}
+
+impl fmt::Display for SceneError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      SceneError::Io(ref err) => write!(f, "{}", err),
+      SceneError::Parse { line, ref message } => write!(f, "line {}: {}", line, message),
+      SceneError::Invalid(ref message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl Error for SceneError {}
+
+impl From<io::Error> for SceneError {
+  fn from(err: io::Error) -> SceneError {
+    SceneError::Io(err)
+  }
+}
+
+pub fn load_scene(path: &Path, nx: u32, ny: u32, rng: &mut Rng) -> Result<(HitablePtr, Camera, bool), SceneError> {
+  let mut text = String::new();
+  File::open(path)?.read_to_string(&mut text)?;
+  let base_dir = path.parent().unwrap_or(Path::new(""));
+  parse_scene(&text, base_dir, nx, ny, rng)
+}
+
+pub fn parse_scene(text: &str, base_dir: &Path, nx: u32, ny: u32, rng: &mut Rng) -> Result<(HitablePtr, Camera, bool), SceneError> {
+  let mut parser = SceneParser::new(base_dir, nx, ny, rng);
+  for (index, line) in text.lines().enumerate() {
+    let tokens = tokenize(line).map_err(|message| SceneError::Parse { line: index + 1, message })?;
+    if tokens.is_empty() {
+      continue;
+    }
+    parser.statement(&tokens).map_err(|message| SceneError::Parse { line: index + 1, message })?;
+  }
+  parser.finish()
+}
+
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+  let mut tokens = Vec::new();
+  let mut chars = line.chars().peekable();
+  loop {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+      chars.next();
+    }
+    match chars.peek().cloned() {
+      None | Some('#') => break,
+      Some('"') => {
+        chars.next();
+        let mut token = String::new();
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some(c) => token.push(c),
+            None => return Err("unterminated string".to_string()),
+          }
+        }
+        tokens.push(token);
+      },
+      Some(_) => {
+        let mut token = String::new();
+        while let Some(&c) = chars.peek() {
+          if c.is_whitespace() || c == '#' {
+            break;
+          }
+          token.push(c);
+          chars.next();
+        }
+        tokens.push(token);
+      }
+    }
+  }
+  Ok(tokens)
+}
+
+// Walks the arguments of a single statement.
+struct Args<'a> {
+  tokens: &'a [String],
+  pos: usize,
+}
+
+impl<'a> Args<'a> {
+  fn new(tokens: &'a [String]) -> Args<'a> {
+    Args {
+      tokens,
+      pos: 0
+    }
+  }
+
+  fn peek(&self) -> Option<&'a str> {
+    self.tokens.get(self.pos).map(|t| t.as_str())
+  }
+
+  fn word(&mut self, what: &str) -> Result<&'a str, String> {
+    let token = self.tokens.get(self.pos).ok_or_else(|| format!("expected {}", what))?;
+    self.pos += 1;
+    Ok(token)
+  }
+
+  fn number(&mut self, what: &str) -> Result<f64, String> {
+    let token = self.word(what)?;
+    token.parse::<f64>().map_err(|_| format!("expected {}, found '{}'", what, token))
+  }
+
+  fn vec3(&mut self, what: &str) -> Result<Vec3, String> {
+    Ok(Vec3::new(self.number(what)?, self.number(what)?, self.number(what)?))
+  }
+
+  fn rest(&mut self) -> &'a [String] {
+    let rest = &self.tokens[self.pos..];
+    self.pos = self.tokens.len();
+    rest
+  }
+
+  fn end(&self) -> Result<(), String> {
+    match self.peek() {
+      Some(extra) => Err(format!("unexpected argument '{}'", extra)),
+      None => Ok(())
+    }
+  }
+}
+
+struct SceneParser<'a> {
+  base_dir: PathBuf,
+  nx: u32,
+  ny: u32,
+  rng: &'a mut Rng,
+  camera: Option<Camera>,
+  default_sky: bool,
+  textures: HashMap<String, TexturePtr>,
+  materials: HashMap<String, MaterialPtr>,
+  objects: HashMap<String, HitablePtr>,
+  world: HitableList,
+}
+
+impl<'a> SceneParser<'a> {
+  fn new(base_dir: &Path, nx: u32, ny: u32, rng: &'a mut Rng) -> SceneParser<'a> {
+    SceneParser {
+      base_dir: base_dir.to_path_buf(),
+      nx,
+      ny,
+      rng,
+      camera: None,
+      default_sky: false,
+      textures: HashMap::new(),
+      materials: HashMap::new(),
+      objects: HashMap::new(),
+      world: HitableList::new(),
+    }
+  }
+
+  fn finish(self) -> Result<(HitablePtr, Camera, bool), SceneError> {
+    let camera = self.camera.ok_or_else(|| SceneError::Invalid("scene has no camera".to_string()))?;
+    if self.world.list.is_empty() {
+      return Err(SceneError::Invalid("scene has no objects, use 'add' to place them".to_string()));
+    }
+    Ok((accelerate(self.world.list, 0.0, 1.0), camera, self.default_sky))
+  }
+
+  fn statement(&mut self, tokens: &[String]) -> Result<(), String> {
+    let mut args = Args::new(&tokens[1..]);
+    match tokens[0].as_str() {
+      "camera" => self.camera(&mut args)?,
+      "sky" => {
+        self.default_sky = match args.word("sky type")? {
+          "gradient" => true,
+          "black" => false,
+          other => return Err(format!("unknown sky '{}'", other)),
+        };
+      },
+      "texture" => {
+        let name = args.word("texture name")?;
+        let texture = self.texture(&mut args)?;
+        insert_unique(&mut self.textures, name, texture, "texture")?;
+      },
+      "material" => {
+        let name = args.word("material name")?;
+        let material = self.material(&mut args)?;
+        insert_unique(&mut self.materials, name, material, "material")?;
+      },
+      "object" => {
+        let name = args.word("object name")?;
+        let object = self.object(&mut args)?;
+        insert_unique(&mut self.objects, name, object, "object")?;
+      },
+      "add" => {
+        if args.peek().is_none() {
+          return Err("expected object name".to_string());
+        }
+        for name in args.rest() {
+          let object = lookup(&self.objects, name, "object")?;
+          self.world.add_hitable(object);
+        }
+      },
+      other => return Err(format!("unknown statement '{}'", other)),
+    }
+    args.end()
+  }
+
+  fn camera(&mut self, args: &mut Args) -> Result<(), String> {
+    let mut look_from = None;
+    let mut look_at = None;
+    let mut up = Vec3::new(0.0, 1.0, 0.0);
+    let mut vfov = 40.0;
+    let mut aperture = 0.0;
+    let mut focus_dist = None;
+    let mut time0 = 0.0;
+    let mut time1 = 1.0;
+    while let Some(key) = args.peek() {
+      args.pos += 1;
+      match key {
+        "look_from" => look_from = Some(args.vec3("look_from position")?),
+        "look_at" => look_at = Some(args.vec3("look_at position")?),
+        "up" => up = args.vec3("up vector")?,
+        "vfov" => vfov = args.number("vertical field of view")?,
+        "aperture" => aperture = args.number("aperture")?,
+        "focus_dist" => focus_dist = Some(args.number("focus distance")?),
+        "time" => {
+          time0 = args.number("shutter open time")?;
+          time1 = args.number("shutter close time")?;
+        },
+        other => return Err(format!("unknown camera parameter '{}'", other)),
+      }
+    }
+    let look_from = look_from.ok_or("camera needs look_from")?;
+    let look_at = look_at.ok_or("camera needs look_at")?;
+    let focus_dist = focus_dist.unwrap_or_else(|| (look_from - look_at).length());
+    let aspect = self.nx as f64 / self.ny as f64;
+    self.camera = Some(Camera::new(&look_from, &look_at, &up, vfov, aspect, aperture, focus_dist, time0, time1));
+    Ok(())
+  }
+
+  fn texture(&mut self, args: &mut Args) -> Result<TexturePtr, String> {
+    match args.word("texture type")? {
+      "constant" => Ok(ConstantTexture::rc(args.vec3("color")?)),
+      "checker" => {
+        let odd = lookup(&self.textures, args.word("odd texture")?, "texture")?;
+        let even = lookup(&self.textures, args.word("even texture")?, "texture")?;
+        Ok(CheckerTexture::rc(odd, even))
+      },
+      "noise" => Ok(NoiseTexture::rc(args.number("noise scale")?, self.rng)),
+      "image" => {
+        let path = self.base_dir.join(args.word("image path")?);
+        let texture = ImageTexture::open(&path)
+          .map_err(|err| format!("unable to load image {}: {}", path.display(), err))?;
+        Ok(Arc::new(texture))
+      },
+      other => Err(format!("unknown texture type '{}'", other)),
+    }
+  }
+
+  fn material(&self, args: &mut Args) -> Result<MaterialPtr, String> {
+    match args.word("material type")? {
+      "lambertian" => Ok(Lambertian::rc(lookup(&self.textures, args.word("texture")?, "texture")?)),
+      "metal" => {
+        let texture = lookup(&self.textures, args.word("texture")?, "texture")?;
+        Ok(Metal::rc(texture, args.number("fuzz")?))
+      },
+      "microfacet" => {
+        let base_color = lookup(&self.textures, args.word("base color texture")?, "texture")?;
+        let roughness = lookup(&self.textures, args.word("roughness texture")?, "texture")?;
+        let metalness = lookup(&self.textures, args.word("metalness texture")?, "texture")?;
+        let mut transmission: TexturePtr = ConstantTexture::rc(Vec3::zero());
+        let mut ior = 1.5;
+        while let Some(key) = args.peek() {
+          args.pos += 1;
+          match key {
+            "transmission" => transmission = lookup(&self.textures, args.word(key)?, "texture")?,
+            "ior" => ior = args.number("refractive index")?,
+            other => return Err(format!("unknown microfacet parameter '{}'", other)),
+          }
+        }
+        Ok(Arc::new(Microfacet::with_transmission(base_color, roughness, metalness, transmission, ior)))
+      },
+      "principled" => {
+        let mut params = PrincipledParams::new(lookup(&self.textures, args.word("base color texture")?, "texture")?);
+        while let Some(key) = args.peek() {
+          args.pos += 1;
+          let param = match key {
+            "metallic" => &mut params.metallic,
+            "roughness" => &mut params.roughness,
+            "specular" => &mut params.specular,
+            "clearcoat" => &mut params.clearcoat,
+            "clearcoat_roughness" => &mut params.clearcoat_roughness,
+            "sheen" => &mut params.sheen,
+            "transmission" => &mut params.transmission,
+            other => return Err(format!("unknown principled parameter '{}'", other)),
+          };
+          *param = lookup(&self.textures, args.word(key)?, "texture")?;
+        }
+        Ok(Principled::rc(params))
+      },
+      "dielectric" => {
+        let ref_index = match args.peek() {
+          Some("cauchy") => {
+            args.pos += 1;
+            RefractiveIndex::Cauchy { a: args.number("Cauchy coefficient")?, b: args.number("Cauchy coefficient")? }
+          },
+          Some("sellmeier") => {
+            args.pos += 1;
+            let b = args.vec3("Sellmeier coefficients")?;
+            let c = args.vec3("Sellmeier coefficients")?;
+            RefractiveIndex::Sellmeier { b: [b.x, b.y, b.z], c: [c.x, c.y, c.z] }
+          },
+          _ => RefractiveIndex::Constant(args.number("refractive index")?),
+        };
+        let mut tint = Vec3::one();
+        let mut absorption = None;
+        while let Some(key) = args.peek() {
+          args.pos += 1;
+          match key {
+            "tint" => tint = args.vec3("tint color")?,
+            "absorption" => absorption = Some(args.number("absorption")?),
+            other => return Err(format!("unknown dielectric parameter '{}'", other)),
+          }
+        }
+        Ok(Arc::new(Dielectric::with_refractive_index(ref_index, tint, absorption.unwrap_or(1.0))))
+      },
+      "diffuse_light" => Ok(DiffuseLight::rc(lookup(&self.textures, args.word("texture")?, "texture")?)),
+      "isotropic" => Ok(Isotropic::rc(lookup(&self.textures, args.word("texture")?, "texture")?)),
+      other => Err(format!("unknown material type '{}'", other)),
+    }
+  }
+
+  fn object(&self, args: &mut Args) -> Result<HitablePtr, String> {
+    let kind = args.word("object type")?;
+    match kind {
+      "sphere" => {
+        let center = args.vec3("center")?;
+        let radius = args.number("radius")?;
+        Ok(Sphere::hitable_ptr(center, radius, self.material_arg(args)?))
+      },
+      "moving_sphere" => {
+        let center0 = args.vec3("start center")?;
+        let center1 = args.vec3("end center")?;
+        let time0 = args.number("start time")?;
+        let time1 = args.number("end time")?;
+        let radius = args.number("radius")?;
+        Ok(Sphere::hitable_ptr_moving(center0, center1, time0, time1, radius, self.material_arg(args)?))
+      },
+      "xy_rect" | "xz_rect" | "yz_rect" => {
+        let a0 = args.number("rect bounds")?;
+        let b0 = args.number("rect bounds")?;
+        let a1 = args.number("rect bounds")?;
+        let b1 = args.number("rect bounds")?;
+        let k = args.number("rect offset")?;
+        let material = self.material_arg(args)?;
+        Ok(match kind {
+          "xy_rect" => Rect::xyrect(a0, b0, a1, b1, k, material),
+          "xz_rect" => Rect::xzrect(a0, b0, a1, b1, k, material),
+          _ => Rect::yzrect(a0, b0, a1, b1, k, material),
+        })
+      },
+      "box" => {
+        let min = args.vec3("box minimum")?;
+        let max = args.vec3("box maximum")?;
+        Ok(AabbBox::hitable_ptr(Aabb::new(min, max), self.material_arg(args)?))
+      },
+      "triangle" => {
+        let p0 = args.vec3("vertex")?;
+        let p1 = args.vec3("vertex")?;
+        let p2 = args.vec3("vertex")?;
+        Ok(Triangle::hitable_ptr(p0, p1, p2, self.material_arg(args)?))
+      },
+      "plane" => {
+        let point = args.vec3("plane point")?;
+        let normal = args.vec3("plane normal")?;
+        if normal.length() == 0.0 {
+          return Err("plane normal must not be zero".to_string());
+        }
+        Ok(Plane::hitable_ptr(point, normal, self.material_arg(args)?))
+      },
+      "obj" => {
+        let path = self.base_dir.join(args.word("obj path")?);
+        let material = match args.peek() {
+          Some(_) => Some(self.material_arg(args)?),
+          None => None
+        };
+        load_obj(&path, material).map_err(|err| err.to_string())
+      },
+      "constant_medium" => {
+        let boundary = lookup(&self.objects, args.word("boundary object")?, "object")?;
+        let density = args.number("density")?;
+        let texture = lookup(&self.textures, args.word("texture")?, "texture")?;
+        Ok(ConstantMedium::hitable_ptr(&boundary, density, texture))
+      },
+      "translate" => {
+        let object = lookup(&self.objects, args.word("object")?, "object")?;
+        Ok(Translate::hitable_ptr(object, args.vec3("offset")?))
+      },
+      "rotate_y" => {
+        let object = lookup(&self.objects, args.word("object")?, "object")?;
+        Ok(RotateY::hitable_ptr(object, args.number("angle")?))
+      },
+      "transform" => {
+        let object = lookup(&self.objects, args.word("object")?, "object")?;
+        let mut matrix = Mat4::identity();
+        while args.peek().is_some() {
+          let step = match args.word("transform step")? {
+            "translate" => Mat4::translate(&args.vec3("offset")?),
+            "scale" => Mat4::scale(&args.vec3("scale")?),
+            "rotate_x" => Mat4::rotate_x(args.number("angle")?),
+            "rotate_y" => Mat4::rotate_y(args.number("angle")?),
+            "rotate_z" => Mat4::rotate_z(args.number("angle")?),
+            "rotate" => {
+              let axis = args.vec3("axis")?;
+              if axis.length() == 0.0 {
+                return Err("rotation axis must be non-zero".to_string());
+              }
+              Mat4::rotate(&axis, args.number("angle")?)
+            },
+            other => return Err(format!("unknown transform step '{}'", other)),
+          };
+          matrix = step * matrix;
+        }
+        if matrix.inverse().is_none() {
+          return Err("transform is not invertible".to_string());
+        }
+        Ok(Transform::hitable_ptr(object, matrix))
+      },
+      "flip_normals" => {
+        let object = lookup(&self.objects, args.word("object")?, "object")?;
+        Ok(FlipNormals::hitable_ptr(object))
+      },
+      "group" => {
+        let mut objects = Vec::new();
+        for name in args.rest() {
+          objects.push(lookup(&self.objects, name, "object")?);
+        }
+        if objects.is_empty() {
+          return Err("group needs at least one object".to_string());
+        }
+        Ok(accelerate(objects, 0.0, 1.0))
+      },
+      other => Err(format!("unknown object type '{}'", other)),
+    }
+  }
+
+  fn material_arg(&self, args: &mut Args) -> Result<MaterialPtr, String> {
+    lookup(&self.materials, args.word("material")?, "material")
+  }
+}
+
+fn lookup<T: ?Sized>(table: &HashMap<String, Arc<T>>, name: &str, what: &str) -> Result<Arc<T>, String> {
+  table.get(name).cloned().ok_or_else(|| format!("unknown {} '{}'", what, name))
+}
+
+fn insert_unique<T: ?Sized>(table: &mut HashMap<String, Arc<T>>, name: &str, value: Arc<T>, what: &str) -> Result<(), String> {
+  if table.contains_key(name) {
+    return Err(format!("{} '{}' is already defined", what, name));
+  }
+  table.insert(name.to_string(), value);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::path::Path;
+  use vec3::Vec3;
+  use ray::Ray;
+  use scene_file::*;
+
+  const SCENE: &str = "
+    # A light above a sphere
+    camera look_from 0 0 5 look_at 0 0 0 vfov 30
+    texture white constant 1 1 1
+    material matte lambertian white
+    material lamp diffuse_light white
+    object ball sphere 0 0 0 1 matte
+    object light xz_rect -1 -1 1 1 3 lamp
+    object light_down flip_normals light
+    object floor plane 0 -2 0 0 1 0 matte
+    add ball light_down floor
+  ";
+
+  #[test]
+  fn test_parse_scene() {
+    let (scene, _, default_sky) = parse_scene(SCENE, Path::new(""), 10, 10, &mut Rng::new(0)).unwrap();
+    assert!(!default_sky);
+    let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let hit = scene.hit(&ray, 0.001, f64::MAX).unwrap();
+    assert_eq!(hit.t, 4.0);
+    let up = Ray::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 0.0);
+    let hit = scene.hit(&up, 1.5, f64::MAX).unwrap();
+    assert_eq!(hit.normal, Vec3::new(0.0, -1.0, 0.0));
+    let down = Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    assert_eq!(scene.hit(&down, 0.001, f64::MAX).unwrap().t, 2.0);
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    let line_of = |text: &str| match parse_scene(text, Path::new(""), 10, 10, &mut Rng::new(0)) {
+      Err(SceneError::Parse { line, .. }) => line,
+      Err(err) => panic!("unexpected error {}", err),
+      Ok(_) => panic!("expected an error"),
+    };
+    assert_eq!(line_of("camera look_from 0 0 5 look_at 0 0 0\nmaterial m lambertian missing"), 2);
+    assert_eq!(line_of("\n\ntexture t constant 1 one 1"), 3);
+    assert_eq!(line_of("texture t constant 1 1 1\ntexture t constant 1 1 1"), 2);
+    assert_eq!(line_of("camera look_from 0 0 5 look_at 0 0 0 fov 30"), 1);
+    assert_eq!(line_of("texture t constant 1 1 1 1"), 1);
+    assert_eq!(line_of("texture t image \"unterminated"), 1);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m lambertian t\nobject s sphere 0 0 0 1 m\nobject f transform s scale 1 0 1"), 4);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m lambertian t\nobject s sphere 0 0 0 1 m\nobject f transform s shear 1"), 4);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m lambertian t\nobject p plane 0 0 0 0 0 0 m"), 3);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m microfacet t t missing"), 2);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m microfacet t t t ior glass"), 2);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m microfacet t t t coat t"), 2);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m principled t metallic t gloss t"), 2);
+    assert_eq!(line_of("texture t constant 1 1 1\nmaterial m principled t sheen"), 2);
+    assert_eq!(line_of("material m dielectric 1.5 tint 1 0.5"), 1);
+    assert_eq!(line_of("material m dielectric 1.5 color 1 0.5 0.5"), 1);
+    assert_eq!(line_of("material m dielectric cauchy 1.5"), 1);
+    assert_eq!(line_of("material m dielectric sellmeier 1 0.2 1 0.006 0.02"), 1);
+    match parse_scene("texture t constant 1 1 1", Path::new(""), 10, 10, &mut Rng::new(0)) {
+      Err(SceneError::Invalid(_)) => {},
+      _ => panic!("a scene without a camera should be rejected"),
+    }
+  }
+
+  #[test]
+  fn test_instances() {
+    let text = "
+      camera look_from 0 0 5 look_at 0 0 0
+      texture white constant 1 1 1
+      material matte lambertian white
+      object a sphere 0 0 0 1 matte
+      object b sphere 3 0 0 1 matte
+      object pair group a b
+      object left transform pair translate -10 0 0
+      object right transform pair scale 2 2 2 rotate_y 90 translate 10 0 0
+      add left right
+    ";
+    let (scene, _, _) = parse_scene(text, Path::new(""), 10, 10, &mut Rng::new(0)).unwrap();
+    let down = |x: f64, z: f64| scene.hit(&Ray::new(Vec3::new(x, 10.0, z), Vec3::new(0.0, -1.0, 0.0), 0.0), 0.001, f64::MAX);
+    assert_eq!(down(-7.0, 0.0).unwrap().t, 9.0);
+    // Scaled by 2 and turned so the second sphere ends up at z = -6.
+    assert!((down(10.0, -6.0).unwrap().t - 8.0).abs() < 1e-9);
+    assert!(down(16.0, 0.0).is_none());
+  }
+
+  #[test]
+  fn test_load_example() {
+    assert!(load_scene(Path::new("scenes/cornell.scene"), 10, 10, &mut Rng::new(0)).is_ok());
+  }
+}
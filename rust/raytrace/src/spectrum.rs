@@ -0,0 +1,223 @@
+// Spectral rendering. A spectral path carries three wavelengths in the
+// channels of its colors instead of red, green and blue: a hero wavelength
+// and two more a third and two thirds of the way further along the sampling
+// distribution, wrapping around (Wilkie et al. 2014). Textures, lights and
+// the sky stay RGB and are upsampled to the spectrum at the path's
+// wavelengths, the film turns the result back into RGB through the CIE 1931
+// observer.
+
+use mat4::Mat4;
+use vec3::Vec3;
+
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+// Where RGB renders take the refractive index of dispersive glass, the
+// helium d line that catalogs quote it at.
+pub const LAMBDA_D: f64 = 587.56;
+
+// Wavelengths in nanometers, the first one is the hero, and the density
+// each was picked with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Wavelengths {
+  pub lambda: [f64; 3],
+  pub pdf: [f64; 3],
+  // Set once the path has dropped all but the hero.
+  pub hero_only: bool,
+}
+
+impl Wavelengths {
+  pub fn hero_only(&self) -> Wavelengths {
+    Wavelengths {
+      hero_only: true,
+      ..*self
+    }
+  }
+
+  // The spectrum of a linear RGB color at these wavelengths.
+  pub fn from_rgb(&self, rgb: &Vec3) -> Vec3 {
+    let at = |lambda: f64| {
+      let (r, g, b) = rgb_basis(lambda);
+      r * rgb.x + g * rgb.y + b * rgb.z
+    };
+    Vec3::new(at(self.lambda[0]), at(self.lambda[1]), at(self.lambda[2]))
+  }
+}
+
+// The spectra RGB colors are blended from: blue below 490nm, green in the
+// middle and red above 590nm, with smooth crossovers. They add up to one
+// everywhere, so white is a flat spectrum and colors within [0, 1] stay
+// valid reflectances at every wavelength.
+fn rgb_basis(lambda: f64) -> (f64, f64, f64) {
+  let smoothstep = |edge0: f64, edge1: f64| {
+    let t = ((lambda - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+  };
+  let b = 1.0 - smoothstep(475.0, 505.0);
+  let r = smoothstep(575.0, 605.0);
+  (r, 1.0 - r - b, b)
+}
+
+// The CIE 1931 color matching functions, as fitted with piecewise Gaussians
+// by Wyman, Sloan and Shirley (2013).
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+  let g = |mu: f64, sigma_low: f64, sigma_high: f64| {
+    let t = (lambda - mu) / if lambda < mu { sigma_low } else { sigma_high };
+    (-0.5 * t * t).exp()
+  };
+  Vec3::new(
+    1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+    0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+    1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+  )
+}
+
+fn xyz_to_srgb() -> Mat4 {
+  Mat4::new([
+    [3.2404542, -1.5371385, -0.4985314, 0.0],
+    [-0.9692660, 1.8760108, 0.0415560, 0.0],
+    [0.0556434, -0.2040259, 1.0572252, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+  ])
+}
+
+// Bins of the tabulated wavelength distribution.
+const BINS: usize = 400;
+
+// Picks the wavelengths of paths and turns the radiance they bring back into
+// linear RGB.
+pub struct SpectralFilm {
+  // From the color matching functions straight to RGB.
+  to_rgb: Mat4,
+  // Wavelengths are picked in proportion to how much they sway the RGB
+  // result, piecewise constant over equal bins. BINS + 1 entries from 0 to 1.
+  cdf: Vec<f64>,
+}
+
+impl Default for SpectralFilm {
+  fn default() -> SpectralFilm {
+    SpectralFilm::new()
+  }
+}
+
+impl SpectralFilm {
+  pub fn new() -> SpectralFilm {
+    // Integrate the observer over the range, normalizing so a flat spectrum
+    // has a luminance of one, and see which RGB each basis spectrum comes
+    // out as. The basis spectra aren't the sRGB primaries, undoing that
+    // mixture makes any color upsampled by `Wavelengths::from_rgb` come back
+    // exactly, white included.
+    let steps = 4000;
+    let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+    let mut luminance = 0.0;
+    let mut basis_xyz = [Vec3::zero(); 3];
+    for i in 0..steps {
+      let lambda = LAMBDA_MIN + (i as f64 + 0.5) * step;
+      let xyz = cie_xyz(lambda) * step;
+      let (r, g, b) = rgb_basis(lambda);
+      luminance += xyz.y;
+      basis_xyz[0] = basis_xyz[0] + xyz * r;
+      basis_xyz[1] = basis_xyz[1] + xyz * g;
+      basis_xyz[2] = basis_xyz[2] + xyz * b;
+    }
+    let srgb = xyz_to_srgb();
+    let mut mixture = Mat4::identity();
+    for (c, xyz) in basis_xyz.iter().enumerate() {
+      let rgb = srgb.transform_vector(&(*xyz / luminance));
+      for row in 0..3 {
+        mixture.m[row][c] = rgb[row];
+      }
+    }
+    let unmix = mixture.inverse().expect("RGB basis spectra are degenerate!");
+    let mut to_rgb = unmix * srgb;
+    for row in 0..3 {
+      for col in 0..3 {
+        to_rgb.m[row][col] /= luminance;
+      }
+    }
+    let bin_width = (LAMBDA_MAX - LAMBDA_MIN) / BINS as f64;
+    let mut cdf = vec![0.0; BINS + 1];
+    for i in 0..BINS {
+      let rgb = to_rgb.transform_vector(&cie_xyz(LAMBDA_MIN + (i as f64 + 0.5) * bin_width));
+      cdf[i + 1] = cdf[i] + rgb.x.abs() + rgb.y.abs() + rgb.z.abs();
+    }
+    let total = cdf[BINS];
+    for c in cdf.iter_mut() {
+      *c /= total;
+    }
+    SpectralFilm {
+      to_rgb,
+      cdf
+    }
+  }
+
+  pub fn sample_wavelengths(&self, u: f64) -> Wavelengths {
+    let mut wavelengths = Wavelengths {
+      lambda: [0.0; 3],
+      pdf: [0.0; 3],
+      hero_only: false
+    };
+    for i in 0..3 {
+      let u = (u + i as f64 / 3.0) % 1.0;
+      let bin = match self.cdf.binary_search_by(|c| c.partial_cmp(&u).unwrap()) {
+        Ok(bin) => bin,
+        Err(bin) => bin - 1,
+      }.min(BINS - 1);
+      let p = self.cdf[bin + 1] - self.cdf[bin];
+      let bin_width = (LAMBDA_MAX - LAMBDA_MIN) / BINS as f64;
+      wavelengths.lambda[i] = LAMBDA_MIN + (bin as f64 + (u - self.cdf[bin]) / p) * bin_width;
+      wavelengths.pdf[i] = p / bin_width;
+    }
+    wavelengths
+  }
+
+  // Each wavelength gives an estimate of the RGB color, they're averaged.
+  pub fn to_rgb(&self, wavelengths: &Wavelengths, values: &Vec3) -> Vec3 {
+    let mut xyz = Vec3::zero();
+    for i in 0..3 {
+      xyz = xyz + cie_xyz(wavelengths.lambda[i]) * (values[i] / (3.0 * wavelengths.pdf[i]));
+    }
+    self.to_rgb.transform_vector(&xyz)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use vec3::Vec3;
+  use spectrum::*;
+
+  #[test]
+  fn test_wavelengths() {
+    let film = SpectralFilm::new();
+    let w = film.sample_wavelengths(0.9);
+    assert!(w.lambda.iter().all(|l| (LAMBDA_MIN..LAMBDA_MAX).contains(l)));
+    assert!(w.pdf.iter().all(|&p| p > 0.0));
+    // The other two are the hero a third and two thirds further on.
+    assert_eq!(film.sample_wavelengths(0.9 + 1.0 / 3.0 - 1.0).lambda[0], w.lambda[1]);
+    // The middle of the spectrum is sampled more than its ends.
+    let (blue, middle, red) = (film.sample_wavelengths(0.001), film.sample_wavelengths(0.5), film.sample_wavelengths(0.999));
+    assert!(blue.lambda[0] < middle.lambda[0] && middle.lambda[0] < red.lambda[0]);
+    assert!(middle.pdf[0] > 1.0 / (LAMBDA_MAX - LAMBDA_MIN));
+    assert!(blue.pdf[0] < middle.pdf[0] && red.pdf[0] < middle.pdf[0]);
+    assert_eq!(w.from_rgb(&Vec3::one()), Vec3::one());
+    let red = Wavelengths { lambda: [650.0, 530.0, 430.0], pdf: [1.0; 3], hero_only: false }.from_rgb(&Vec3::new(1.0, 0.0, 0.0));
+    assert_eq!(red, Vec3::new(1.0, 0.0, 0.0));
+  }
+
+  #[test]
+  fn test_round_trip() {
+    // Averaging over the hero wavelength gives back the color that was
+    // upsampled.
+    let film = SpectralFilm::new();
+    for &rgb in [Vec3::one(), Vec3::new(0.9, 0.2, 0.1), Vec3::new(0.0, 0.3, 2.0)].iter() {
+      let n = 3000;
+      let mut sum = Vec3::zero();
+      for i in 0..n {
+        let w = film.sample_wavelengths((i as f64 + 0.5) / n as f64);
+        sum = sum + film.to_rgb(&w, &w.from_rgb(&rgb)) / n as f64;
+      }
+      assert!((sum - rgb).length() < 1e-3, "{:?} != {:?}", sum, rgb);
+    }
+  }
+}
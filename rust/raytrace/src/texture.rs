@@ -2,10 +2,11 @@ extern crate image;
 
 use std::sync::Arc;
 use std::path::Path;
-use self::image::{DynamicImage, GenericImageView, open, Pixel};
+use self::image::{DynamicImage, GenericImageView, ImageResult, open, Pixel};
 
 use vec3::Vec3;
 use perlin::Perlin;
+use rt_rand::Rng;
 
 pub trait Texture {
   // result: attenuation, scatter
@@ -75,15 +76,15 @@ pub struct NoiseTexture {
 }
 
 impl NoiseTexture {
-  pub fn new(scale: f64) -> NoiseTexture {
+  pub fn new(scale: f64, rng: &mut Rng) -> NoiseTexture {
     NoiseTexture {
       scale,
-      perlin: Perlin::new()
+      perlin: Perlin::new(rng)
     }
   }
 
-  pub fn rc(scale: f64) -> Arc<NoiseTexture> {
-    Arc::new(NoiseTexture::new(scale))
+  pub fn rc(scale: f64, rng: &mut Rng) -> Arc<NoiseTexture> {
+    Arc::new(NoiseTexture::new(scale, rng))
   }
 }
 
@@ -106,9 +107,13 @@ pub struct ImageTexture {
 
 impl ImageTexture {
   pub fn new(filename: &Path) -> ImageTexture {
-    ImageTexture {
-      image: open(filename).unwrap()
-    }
+    ImageTexture::open(filename).unwrap()
+  }
+
+  pub fn open(filename: &Path) -> ImageResult<ImageTexture> {
+    Ok(ImageTexture {
+      image: open(filename)?
+    })
   }
 
   pub fn rc(filename: &Path) -> Arc<ImageTexture> {
@@ -42,6 +42,12 @@ impl WasmRendererWrapper {
         }
     }
 
+    pub fn new_with_scene(nx: u32, ny: u32, ns: u32, scene: &str) -> WasmRendererWrapper {
+        WasmRendererWrapper {
+            renderer: Renderer::with_scene(nx, ny, ns, scene, 0).expect("Unknown scene!")
+        }
+    }
+
     pub fn pixel_color(&self, i: u32, j: u32, ret: &mut [u8]) {
         let c = self.renderer.pixel_color(i, j);
         ret[0] = c.x as u8;